@@ -0,0 +1,799 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use memory_bus::{MEMORY_SIZE, ROM_START};
+use opcodes::OpCode;
+use opcodes::OpCode::*;
+
+// deepest chain of includes or constant references before we assume a cycle
+const MAX_DEPTH: usize = 32;
+
+/// An assembly failure, pointing at the source line that caused it
+#[derive(Debug)]
+pub struct AsmError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.message)
+    }
+}
+
+/// An assembled program
+pub struct Assembly {
+    // program bytes, to be loaded at ROM_START
+    pub rom: Vec<u8>,
+    // label names and the addresses they refer to, ordered by address
+    pub labels: Vec<(usize, String)>,
+}
+
+impl Assembly {
+    /// write the symbol map, one `ADDR name` pair per line with the address in hex
+    pub fn write_symbols<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for &(addr, ref name) in &self.labels {
+            writeln!(out, "{:03X} {}", addr, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Assemble the source file at `path`.
+///
+/// The syntax is the common Chipper style: one instruction per line using the mnemonics
+/// CLS, RET, JP, CALL, SE, SNE, LD, ADD, OR, AND, XOR, SUB, SUBN, SHR, SHL, RND, DRW, SKP,
+/// SKNP and SYS (plus EOF for the 0x0A00 end of program marker), `;` comments, `name:` labels,
+/// `name EQU expr` (or `name = expr`) constants, and the directives DB, DW, ORG and
+/// INCLUDE "file". Numbers may be written as decimal, `0x`/`#` hex or `0b`/`%` binary, and
+/// operands may be expressions using `$` for the current address.
+pub fn assemble<P: AsRef<Path>>(path: P) -> Result<Assembly, AsmError> {
+    let path = path.as_ref();
+    let mut source = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut source)).map_err(|e| AsmError {
+        file: path.to_owned(),
+        line: 0,
+        message: format!("could not read {}: {}", path.display(), e),
+    })?;
+    assemble_source(path, &source)
+}
+
+/// Assemble `source` as though it was read from `path`, which includes are relative to
+pub fn assemble_source(path: &Path, source: &str) -> Result<Assembly, AsmError> {
+    let mut asm = Assembler {
+        items: vec![],
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        addr: ROM_START,
+    };
+    asm.read_source(path, source, 0)?;
+    asm.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Loc {
+    file: Rc<PathBuf>,
+    line: usize,
+}
+
+impl Loc {
+    fn error<S: Into<String>>(&self, message: S) -> AsmError {
+        AsmError {
+            file: (*self.file).clone(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+enum Stmt {
+    Instr { mnemonic: String, operands: Vec<String> },
+    Db(Vec<String>),
+    Dw(Vec<String>),
+}
+
+// a statement placed at an address during the first pass
+struct Item {
+    addr: usize,
+    stmt: Stmt,
+    loc: Loc,
+}
+
+struct Assembler {
+    items: Vec<Item>,
+    labels: HashMap<String, usize>,
+    // each constant's expression, where it was defined and `$` as it was there
+    constants: HashMap<String, (String, Loc, usize)>,
+    // address of the next statement
+    addr: usize,
+}
+
+impl Assembler {
+    fn include(&mut self, path: &Path, from: &Loc, depth: usize) -> Result<(), AsmError> {
+        if depth > MAX_DEPTH {
+            return Err(from.error("includes nested too deeply"));
+        }
+        let mut source = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut source))
+                        .map_err(|e| from.error(format!("could not read {}: {}", path.display(), e)))?;
+        self.read_source(path, &source, depth)
+    }
+
+    fn read_source(&mut self, path: &Path, source: &str, depth: usize) -> Result<(), AsmError> {
+        let file = Rc::new(path.to_owned());
+        for (idx, text) in source.lines().enumerate() {
+            let loc = Loc { file: file.clone(), line: idx + 1 };
+            self.read_line(text, &loc, depth)?;
+        }
+        Ok(())
+    }
+
+    fn read_line(&mut self, text: &str, loc: &Loc, depth: usize) -> Result<(), AsmError> {
+        let mut text = strip_comment(text).trim();
+
+        // `name:` label, optionally followed by a statement
+        if let Some(colon) = text.find(':') {
+            let name = text[..colon].trim();
+            if is_identifier(name) {
+                self.define_label(name, loc)?;
+                text = text[colon + 1..].trim();
+            }
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let (first, rest) = split_word(text);
+        // `name EQU expr` or `name = expr`
+        let (second, value) = split_word(rest);
+        if is_identifier(first) && (second.to_lowercase() == "equ" || second == "=") {
+            return self.define_constant(first, value, loc);
+        }
+        if is_identifier(first) && rest.starts_with('=') {
+            return self.define_constant(first, rest[1..].trim(), loc);
+        }
+
+        let mnemonic = first.to_lowercase();
+        let operands = split_operands(rest);
+        match &*mnemonic {
+            "include" => {
+                let name = match operands.first().and_then(|op| string_literal(op)) {
+                    Some(name) if operands.len() == 1 => name,
+                    _ => return Err(loc.error("include expects a quoted file name")),
+                };
+                let path = match loc.file.parent() {
+                    Some(dir) => dir.join(name),
+                    None => PathBuf::from(name),
+                };
+                self.include(&path, loc, depth + 1)
+            }
+            "org" => {
+                if operands.len() != 1 {
+                    return Err(loc.error("org expects one address"));
+                }
+                let addr = self.eval_at(&operands[0], self.addr, loc)?;
+                if addr < ROM_START as i64 || addr >= MEMORY_SIZE as i64 {
+                    return Err(loc.error(format!("org address {:#X} is outside program memory", addr)));
+                }
+                self.addr = addr as usize;
+                Ok(())
+            }
+            "db" => {
+                let mut size = 0;
+                for op in &operands {
+                    size += match string_literal(op) {
+                        Some(s) => s.len(),
+                        None => 1,
+                    };
+                }
+                self.place(Stmt::Db(operands), size, loc)
+            }
+            "dw" => {
+                let size = 2 * operands.len();
+                self.place(Stmt::Dw(operands), size, loc)
+            }
+            _ => self.place(Stmt::Instr { mnemonic: mnemonic, operands: operands }, 2, loc),
+        }
+    }
+
+    fn place(&mut self, stmt: Stmt, size: usize, loc: &Loc) -> Result<(), AsmError> {
+        if self.addr + size > MEMORY_SIZE {
+            return Err(loc.error("program does not fit in memory"));
+        }
+        self.items.push(Item { addr: self.addr, stmt: stmt, loc: loc.clone() });
+        self.addr += size;
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: &str, loc: &Loc) -> Result<(), AsmError> {
+        self.check_undefined(name, loc)?;
+        self.labels.insert(name.to_owned(), self.addr);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &str, expr: &str, loc: &Loc) -> Result<(), AsmError> {
+        self.check_undefined(name, loc)?;
+        self.constants.insert(name.to_owned(), (expr.to_owned(), loc.clone(), self.addr));
+        Ok(())
+    }
+
+    fn check_undefined(&self, name: &str, loc: &Loc) -> Result<(), AsmError> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(loc.error(format!("{} is already defined", name)));
+        }
+        if is_reserved(name) {
+            return Err(loc.error(format!("{} is a reserved word", name)));
+        }
+        Ok(())
+    }
+
+    /// second pass: now every label is known, evaluate operands and emit bytes
+    fn finish(self) -> Result<Assembly, AsmError> {
+        let end = self.items.iter().map(|item| item.addr + item.size()).max().unwrap_or(ROM_START);
+        let mut rom = vec![0; end - ROM_START];
+
+        // an org can move back over bytes already placed, which would silently replace them
+        let mut placed: Vec<(usize, &Item)> = self.items.iter().enumerate().filter(|&(_, item)| item.size() > 0).collect();
+        placed.sort_by_key(|&(_, item)| item.addr);
+        for pair in placed.windows(2) {
+            let ((first_idx, first), (second_idx, second)) = (pair[0], pair[1]);
+            if first.addr + first.size() > second.addr {
+                let (earlier, later) = if first_idx < second_idx { (first, second) } else { (second, first) };
+                return Err(later.loc.error(format!("{:#X} overlaps the bytes placed by {}:{}",
+                                                   later.addr, earlier.loc.file.display(), earlier.loc.line)));
+            }
+        }
+
+        for item in &self.items {
+            let bytes = self.emit(item)?;
+            let start = item.addr - ROM_START;
+            rom[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        let mut labels: Vec<(usize, String)> = self.labels.iter()
+                                                          .map(|(name, addr)| (*addr, name.clone()))
+                                                          .collect();
+        labels.sort();
+        Ok(Assembly {
+            rom: rom,
+            labels: labels,
+        })
+    }
+
+    fn emit(&self, item: &Item) -> Result<Vec<u8>, AsmError> {
+        let loc = &item.loc;
+        let mut bytes = vec![];
+        match item.stmt {
+            Stmt::Db(ref operands) => {
+                for op in operands {
+                    match string_literal(op) {
+                        Some(s) => bytes.extend_from_slice(s.as_bytes()),
+                        None => bytes.push(self.byte(op, item.addr, loc)?),
+                    }
+                }
+            }
+            Stmt::Dw(ref operands) => {
+                for op in operands {
+                    let value = self.eval_at(op, item.addr, loc)?;
+                    if value < -0x8000 || value > 0xFFFF {
+                        return Err(loc.error(format!("{} does not fit in a word", value)));
+                    }
+                    bytes.push((value >> 8) as u8);
+                    bytes.push(value as u8);
+                }
+            }
+            Stmt::Instr { ref mnemonic, ref operands } => {
+                let instr = self.instruction(mnemonic, operands, item.addr, loc)?.encode();
+                bytes.push((instr >> 8) as u8);
+                bytes.push(instr as u8);
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String], pc: usize, loc: &Loc) -> Result<OpCode, AsmError> {
+        use self::Operand::*;
+
+        if operands.len() > 3 {
+            return Err(loc.error("too many operands"));
+        }
+        let ops: Vec<Operand> = operands.iter().map(|op| Operand::parse(op)).collect();
+        let addr = |e: &str| self.addr(e, pc, loc);
+        let byte = |e: &str| self.byte(e, pc, loc);
+
+        let opcode = match (mnemonic, ops.get(0), ops.get(1), ops.get(2)) {
+            ("cls", None, None, None) => DrawClr,
+            ("ret", None, None, None) => Return,
+            ("eof", None, None, None) => Eof,
+            ("sys", Some(&Expr(a)), None, None) => Sys { nnn: addr(a)? },
+            ("jp", Some(&Expr(a)), None, None) => JpConst { nnn: addr(a)? },
+            ("jp", Some(&Reg(0)), Some(&Expr(a)), None) => JpOffset { nnn: addr(a)? },
+            ("call", Some(&Expr(a)), None, None) => Call { nnn: addr(a)? },
+            ("se", Some(&Reg(x)), Some(&Reg(y)), None) => SkpEqReg { x: x, y: y },
+            ("se", Some(&Reg(x)), Some(&Expr(nn)), None) => SkpEqConst { x: x, nn: byte(nn)? },
+            ("sne", Some(&Reg(x)), Some(&Reg(y)), None) => JpRegNe { x: x, y: y },
+            ("sne", Some(&Reg(x)), Some(&Expr(nn)), None) => SkpNeConst { x: x, nn: byte(nn)? },
+            ("ld", Some(&Reg(x)), Some(&Reg(y)), None) => SetReg { x: x, y: y },
+            ("ld", Some(&Reg(x)), Some(&Expr(nn)), None) => SetConst { x: x, nn: byte(nn)? },
+            ("ld", Some(&I), Some(&Expr(a)), None) => SetI { nnn: addr(a)? as u16 },
+            ("ld", Some(&Reg(x)), Some(&Dt), None) => SetRegDelay { x: x },
+            ("ld", Some(&Reg(x)), Some(&K), None) => SetKey { x: x },
+            ("ld", Some(&Dt), Some(&Reg(x)), None) => SetDelay { x: x },
+            ("ld", Some(&St), Some(&Reg(x)), None) => SetSound { x: x },
+            ("ld", Some(&F), Some(&Reg(x)), None) => SetISprite { x: x },
+            ("ld", Some(&B), Some(&Reg(x)), None) => SetBCD { x: x },
+            ("ld", Some(&IndirectI), Some(&Reg(x)), None) => DumpReg { x: x },
+            ("ld", Some(&Reg(x)), Some(&IndirectI), None) => LoadReg { x: x },
+            ("add", Some(&Reg(x)), Some(&Reg(y)), None) => SetRegAdd { x: x, y: y },
+            ("add", Some(&Reg(x)), Some(&Expr(nn)), None) => AddConst { x: x, nn: byte(nn)? },
+            ("add", Some(&I), Some(&Reg(x)), None) => SetIRegAdd { x: x },
+            ("or", Some(&Reg(x)), Some(&Reg(y)), None) => SetRegBor { x: x, y: y },
+            ("and", Some(&Reg(x)), Some(&Reg(y)), None) => SetRegBand { x: x, y: y },
+            ("xor", Some(&Reg(x)), Some(&Reg(y)), None) => SetRegBxor { x: x, y: y },
+            ("sub", Some(&Reg(x)), Some(&Reg(y)), None) => SetRegSub { x: x, y: y },
+            ("subn", Some(&Reg(x)), Some(&Reg(y)), None) => SetRegRevSub { x: x, y: y },
+            ("shr", Some(&Reg(x)), None, None) => SetShr1 { x: x, y: 0 },
+            ("shr", Some(&Reg(x)), Some(&Reg(y)), None) => SetShr1 { x: x, y: y },
+            ("shl", Some(&Reg(x)), None, None) => SetShl1 { x: x, y: 0 },
+            ("shl", Some(&Reg(x)), Some(&Reg(y)), None) => SetShl1 { x: x, y: y },
+            ("rnd", Some(&Reg(x)), Some(&Expr(nn)), None) => SetRand { x: x, nn: byte(nn)? },
+            ("drw", Some(&Reg(x)), Some(&Reg(y)), Some(&Expr(n))) => {
+                let n = self.eval_at(n, pc, loc)?;
+                if n < 0 || n > 0xF {
+                    return Err(loc.error(format!("sprite height {} is not between 0 and 15", n)));
+                }
+                Draw { x: x, y: y, n: n as u8 }
+            }
+            ("skp", Some(&Reg(x)), None, None) => SkpKeyEq { x: x },
+            ("sknp", Some(&Reg(x)), None, None) => SkpKeyNe { x: x },
+            _ => {
+                if is_mnemonic(mnemonic) {
+                    return Err(loc.error(format!("invalid operands for {}", mnemonic.to_uppercase())));
+                }
+                return Err(loc.error(format!("unknown instruction {}", mnemonic)));
+            }
+        };
+        Ok(opcode)
+    }
+
+    fn addr(&self, expr: &str, pc: usize, loc: &Loc) -> Result<usize, AsmError> {
+        let value = self.eval_at(expr, pc, loc)?;
+        if value < 0 || value > 0xFFF {
+            return Err(loc.error(format!("address {:#X} is out of range", value)));
+        }
+        Ok(value as usize)
+    }
+
+    fn byte(&self, expr: &str, pc: usize, loc: &Loc) -> Result<u8, AsmError> {
+        let value = self.eval_at(expr, pc, loc)?;
+        // allow negative bytes so `ADD V0, -1` works
+        if value < -0x80 || value > 0xFF {
+            return Err(loc.error(format!("{} does not fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    /// evaluate an expression, with `$` standing for `pc`
+    fn eval_at(&self, expr: &str, pc: usize, loc: &Loc) -> Result<i64, AsmError> {
+        self.eval_depth(expr, pc, loc, 0)
+    }
+
+    fn eval_depth(&self, expr: &str, pc: usize, loc: &Loc, depth: usize) -> Result<i64, AsmError> {
+        let resolve = |name: &str| -> Result<i64, AsmError> {
+            if let Some(addr) = self.labels.get(name) {
+                return Ok(*addr as i64);
+            }
+            match self.constants.get(name) {
+                Some(&(ref expr, ref def, here)) => {
+                    if depth >= MAX_DEPTH {
+                        return Err(def.error(format!("definition of {} is circular", name)));
+                    }
+                    self.eval_depth(expr, here, def, depth + 1)
+                }
+                None => Err(loc.error(format!("undefined symbol {}", name))),
+            }
+        };
+        let tokens = tokenize(expr).map_err(|e| loc.error(e))?;
+        let mut parser = ExprParser { tokens: &tokens, pos: 0, pc: pc as i64, resolve: &resolve };
+        let value = parser.expr(0).map_err(|e| e.into_error(loc))?;
+        if parser.pos != tokens.len() {
+            return Err(loc.error(format!("unexpected trailing input in expression {}", expr)));
+        }
+        Ok(value)
+    }
+}
+
+impl Item {
+    fn size(&self) -> usize {
+        match self.stmt {
+            Stmt::Instr { .. } => 2,
+            Stmt::Db(ref operands) => {
+                operands.iter()
+                        .map(|op| string_literal(op).map(|s| s.len()).unwrap_or(1))
+                        .sum()
+            }
+            Stmt::Dw(ref operands) => 2 * operands.len(),
+        }
+    }
+}
+
+enum Operand<'a> {
+    Reg(usize),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    B,
+    Expr(&'a str),
+}
+
+impl<'a> Operand<'a> {
+    fn parse(op: &'a str) -> Operand<'a> {
+        let lower = op.to_lowercase();
+        match &*lower {
+            "i" => return Operand::I,
+            "[i]" => return Operand::IndirectI,
+            "dt" => return Operand::Dt,
+            "st" => return Operand::St,
+            "k" => return Operand::K,
+            "f" => return Operand::F,
+            "b" => return Operand::B,
+            _ => (),
+        }
+        match register(&lower) {
+            Some(x) => Operand::Reg(x),
+            None => Operand::Expr(op),
+        }
+    }
+}
+
+fn register(s: &str) -> Option<usize> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(c), None) | (Some('V'), Some(c), None) => c.to_digit(16).map(|x| x as usize),
+        _ => None,
+    }
+}
+
+const MNEMONICS: [&'static str; 23] = [
+    "cls", "ret", "eof", "sys", "jp", "call", "se", "sne", "ld", "add", "or", "and", "xor",
+    "sub", "subn", "shr", "shl", "rnd", "drw", "skp", "sknp", "db", "dw",
+];
+
+fn is_mnemonic(s: &str) -> bool {
+    MNEMONICS.contains(&&*s.to_lowercase())
+}
+
+fn is_reserved(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    is_mnemonic(&lower) || register(&lower).is_some() ||
+        ["i", "dt", "st", "k", "f", "b", "org", "equ", "include"].contains(&&*lower)
+}
+
+/// true if `name` can be used as a label in assembler source
+pub fn is_label_name(name: &str) -> bool {
+    is_identifier(name) && !is_reserved(name)
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' || c == '.' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+/// strip a `;` comment, leaving semicolons inside string literals alone
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..idx],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    match s.find(char::is_whitespace) {
+        Some(idx) => (&s[..idx], s[idx..].trim()),
+        None => (s, ""),
+    }
+}
+
+/// split operands on commas that aren't inside brackets or string literals
+fn split_operands(s: &str) -> Vec<String> {
+    let mut operands = vec![];
+    let mut current = String::new();
+    let mut nesting = 0;
+    let mut in_string = false;
+    for c in s.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            '(' | '[' if !in_string => nesting += 1,
+            ')' | ']' if !in_string => nesting -= 1,
+            ',' if !in_string && nesting == 0 => {
+                operands.push(current.trim().to_owned());
+                current.clear();
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !operands.is_empty() {
+        operands.push(current.trim().to_owned());
+    }
+    operands
+}
+
+fn string_literal(op: &str) -> Option<&str> {
+    if op.len() >= 2 && op.starts_with('"') && op.ends_with('"') {
+        Some(&op[1..op.len() - 1])
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+    Here,
+    Open,
+    Close,
+}
+
+const OPERATORS: [&'static str; 12] = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!"];
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let chars: Vec<char> = expr.chars().collect();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        let rest: String = chars[idx..].iter().collect();
+        if c.is_whitespace() {
+            idx += 1;
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            idx += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            idx += 1;
+        } else if c.is_digit(10) || ((c == '#' || c == '%') && idx + 1 < chars.len() && chars[idx + 1].is_alphanumeric()) {
+            let len = chars[idx + 1..].iter().take_while(|c| c.is_alphanumeric() || **c == '_').count() + 1;
+            let literal: String = chars[idx..idx + len].iter().collect();
+            tokens.push(Token::Num(parse_number(&literal)?));
+            idx += len;
+        } else if c == '$' {
+            tokens.push(Token::Here);
+            idx += 1;
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let len = chars[idx..].iter().take_while(|c| c.is_alphanumeric() || **c == '_' || **c == '.').count();
+            tokens.push(Token::Ident(chars[idx..idx + len].iter().collect()));
+            idx += len;
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    idx += op.len();
+                }
+                None => return Err(format!("unexpected character '{}' in expression", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// parse a number literal in decimal, hex (`0x1F`, `#1F`) or binary (`0b101`, `%101`)
+pub fn parse_number(literal: &str) -> Result<i64, String> {
+    let lower = literal.replace('_', "").to_lowercase();
+    let parsed = if lower.starts_with("0x") {
+        i64::from_str_radix(&lower[2..], 16)
+    } else if lower.starts_with('#') {
+        i64::from_str_radix(&lower[1..], 16)
+    } else if lower.starts_with("0b") {
+        i64::from_str_radix(&lower[2..], 2)
+    } else if lower.starts_with('%') {
+        i64::from_str_radix(&lower[1..], 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| format!("invalid number {}", literal))
+}
+
+// errors while parsing an expression either come from resolving a symbol (and already
+// carry a location) or from the expression itself
+enum ExprError {
+    Asm(AsmError),
+    Syntax(String),
+}
+
+impl ExprError {
+    fn into_error(self, loc: &Loc) -> AsmError {
+        match self {
+            ExprError::Asm(e) => e,
+            ExprError::Syntax(message) => loc.error(message),
+        }
+    }
+}
+
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    pc: i64,
+    resolve: &'a Fn(&str) -> Result<i64, AsmError>,
+}
+
+fn precedence(op: &str) -> Option<usize> {
+    match op {
+        "|" => Some(1),
+        "^" => Some(2),
+        "&" => Some(3),
+        "<<" | ">>" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" | "%" => Some(6),
+        _ => None,
+    }
+}
+
+fn overflow() -> ExprError {
+    ExprError::Syntax("expression overflows".into())
+}
+
+impl<'a> ExprParser<'a> {
+    /// precedence climbing over binary operators that bind tighter than `min_prec`
+    fn expr(&mut self, min_prec: usize) -> Result<i64, ExprError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(&Token::Op(op)) => op,
+                _ => return Ok(lhs),
+            };
+            let prec = match precedence(op) {
+                Some(prec) if prec > min_prec => prec,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.expr(prec)?;
+            lhs = match op {
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "<<" => lhs << (rhs & 63),
+                ">>" => lhs >> (rhs & 63),
+                "+" => lhs.checked_add(rhs).ok_or_else(overflow)?,
+                "-" => lhs.checked_sub(rhs).ok_or_else(overflow)?,
+                "*" => lhs.checked_mul(rhs).ok_or_else(overflow)?,
+                "/" | "%" if rhs == 0 => return Err(ExprError::Syntax("division by zero".into())),
+                "/" => lhs.checked_div(rhs).ok_or_else(overflow)?,
+                "%" => lhs.checked_rem(rhs).ok_or_else(overflow)?,
+                _ => unreachable!(),
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, ExprError> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => Ok(n),
+            Some(Token::Here) => Ok(self.pc),
+            Some(Token::Ident(name)) => (self.resolve)(&name).map_err(ExprError::Asm),
+            Some(Token::Op("-")) => self.unary().and_then(|n| n.checked_neg().ok_or_else(overflow)),
+            Some(Token::Op("+")) => self.unary(),
+            Some(Token::Op("~")) | Some(Token::Op("!")) => self.unary().map(|n| !n),
+            Some(Token::Open) => {
+                let value = self.expr(0)?;
+                match self.tokens.get(self.pos) {
+                    Some(&Token::Close) => {
+                        self.pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(ExprError::Syntax("missing )".into())),
+                }
+            }
+            Some(token) => Err(ExprError::Syntax(format!("unexpected {:?} in expression", token))),
+            None => Err(ExprError::Syntax("expression ended unexpectedly".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use super::{assemble, assemble_source, AsmError, Assembly};
+
+    fn asm(source: &str) -> Result<Assembly, AsmError> {
+        assemble_source(Path::new("test.asm"), source)
+    }
+
+    fn rom(source: &str) -> Vec<u8> {
+        asm(source).expect("source should assemble").rom
+    }
+
+    // the line and message of the error `source` fails with
+    fn error(source: &str) -> (usize, String) {
+        match asm(source) {
+            Ok(_) => panic!("{:?} should not assemble", source),
+            Err(e) => (e.line, e.message),
+        }
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(rom("start: JP end\nCLS\nend: JP start"), vec![0x12, 0x04, 0x00, 0xE0, 0x12, 0x00]);
+        let labels = asm("JP here\nhere:\nloop: JP loop").expect("source should assemble").labels;
+        assert_eq!(labels, vec![(0x202, "here".to_owned()), (0x202, "loop".to_owned())]);
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(rom("LD V0, speed\nspeed EQU 3 * 4\nLD V1, delay\ndelay = speed + 1"),
+                   vec![0x60, 0x0C, 0x61, 0x0D]);
+        // `$` in a constant is the address where it was defined
+        assert_eq!(rom("CLS\nhere EQU $\nCLS\nJP here"), vec![0x00, 0xE0, 0x00, 0xE0, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn data() {
+        assert_eq!(rom("DB \"Hi, you\", 0, -1, #FF"), b"Hi, you\x00\xFF\xFF".to_vec());
+        assert_eq!(rom("DW 0x1234, $, -2"), vec![0x12, 0x34, 0x02, 0x00, 0xFF, 0xFE]);
+        // gaps left by org are zero filled
+        assert_eq!(rom("CLS\nORG 0x206\nDB 7"), vec![0x00, 0xE0, 0, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn include() {
+        let dir = env::temp_dir().join("chip8-asm-include-test");
+        fs::create_dir_all(&dir).expect("can create the test directory");
+        File::create(dir.join("sprites.asm")).and_then(|mut f| f.write_all(b"dot: DB #80\n"))
+                                             .expect("can write the include");
+        File::create(dir.join("main.asm")).and_then(|mut f| f.write_all(b"LD I, dot\nINCLUDE \"sprites.asm\"\n"))
+                                          .expect("can write the source");
+        let assembly = assemble(dir.join("main.asm"));
+        fs::remove_dir_all(&dir).expect("can remove the test directory");
+        assert_eq!(assembly.expect("source should assemble").rom, vec![0xA2, 0x02, 0x80]);
+
+        let (line, message) = error("CLS\nINCLUDE \"no-such-file.asm\"");
+        assert_eq!(line, 2);
+        assert!(message.starts_with("could not read"), "{}", message);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("CLS\nJP nowhere"), (2, "undefined symbol nowhere".to_owned()));
+        assert_eq!(error("a: CLS\na: CLS"), (2, "a is already defined".to_owned()));
+        assert_eq!(error("LD V0, 256"), (1, "256 does not fit in a byte".to_owned()));
+        assert_eq!(error("FOO V0"), (1, "unknown instruction foo".to_owned()));
+        assert_eq!(error("x EQU y\ny EQU x\nLD V0, x").1, "definition of x is circular");
+        assert_eq!(error("LD V0, 0x7FFFFFFFFFFFFFFF + 1").1, "expression overflows");
+        assert_eq!(error("LD V0, 5 / 0").1, "division by zero");
+    }
+
+    #[test]
+    fn overlapping_org() {
+        let (line, message) = error("CLS\nCLS\nORG 0x202\nJP 0x200");
+        assert_eq!(line, 4);
+        assert_eq!(message, "0x202 overlaps the bytes placed by test.asm:2");
+        // filling a gap left by an earlier org is fine
+        assert_eq!(rom("CLS\nORG 0x204\nRET\nORG 0x202\nCLS"), vec![0x00, 0xE0, 0x00, 0xE0, 0x00, 0xEE]);
+    }
+}
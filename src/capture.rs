@@ -0,0 +1,210 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use gif;
+use png;
+use png::HasParameters;
+
+use display::{WIDTH, HEIGHT};
+
+// gif frame delays are measured in hundredths of a second
+const GIF_TICKS_PER_SEC: u64 = 100;
+const FRAMES_PER_SEC: u64 = 60;
+
+/// Colors used when turning the display grid into an image
+#[derive(Clone, Copy, Debug)]
+pub struct Palette {
+    pub on: [u8; 3],
+    pub off: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        // same colors the window renders with
+        Palette {
+            on: [0xFF, 0x29, 0x29],
+            off: [0x12, 0x12, 0x12],
+        }
+    }
+}
+
+impl Palette {
+    /// parse a palette of the form `RRGGBB,RRGGBB` (on color first, then off color)
+    pub fn parse(s: &str) -> Option<Palette> {
+        let mut colors = s.split(',').map(|c| parse_color(c.trim()));
+        match (colors.next(), colors.next(), colors.next()) {
+            (Some(Some(on)), Some(Some(off)), None) => Some(Palette { on: on, off: off }),
+            _ => None,
+        }
+    }
+
+    pub fn color(&self, pixel: u8) -> [u8; 3] {
+        if pixel == 1 { self.on } else { self.off }
+    }
+}
+
+fn parse_color(s: &str) -> Option<[u8; 3]> {
+    let s = s.trim_left_matches('#');
+    if s.len() != 6 {
+        return None;
+    }
+    let value = match u32::from_str_radix(s, 16) {
+        Ok(value) => value,
+        Err(_) => return None,
+    };
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// scale a display grid up so that every CHIP-8 pixel becomes a `scale` x `scale` block
+/// of palette indices (0 for off, 1 for on)
+fn scale_grid(grid: &[u8], scale: usize) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(WIDTH * HEIGHT * scale * scale);
+    for row in grid.chunks(WIDTH) {
+        for _ in 0..scale {
+            for pixel in row {
+                for _ in 0..scale {
+                    pixels.push(*pixel);
+                }
+            }
+        }
+    }
+    pixels
+}
+
+/// Write the display grid to `path` as an RGB png
+pub fn save_png<P: AsRef<Path>>(path: P, grid: &[u8], scale: usize, palette: &Palette) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let width = (WIDTH * scale) as u32;
+    let height = (HEIGHT * scale) as u32;
+
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+
+    let mut data = Vec::with_capacity((width * height * 3) as usize);
+    for pixel in scale_grid(grid, scale) {
+        data.extend_from_slice(&palette.color(pixel));
+    }
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/// Records one display frame per 60 Hz tick, either as an animated gif or as a raw
+/// frame sequence where each frame is the 64x32 grid, one byte (0 or 1) per pixel.
+pub enum Recorder {
+    Gif(GifRecorder),
+    Raw(BufWriter<File>),
+}
+
+impl Recorder {
+    /// start a recording; the format is picked from the file extension (`.gif` or anything
+    /// else for a raw frame sequence)
+    pub fn create<P: AsRef<Path>>(path: P, scale: usize, palette: &Palette) -> io::Result<Recorder> {
+        let is_gif = path.as_ref()
+                         .extension()
+                         .and_then(|ext| ext.to_str())
+                         .map(|ext| ext.to_lowercase() == "gif")
+                         .unwrap_or(false);
+        let file = BufWriter::new(File::create(path)?);
+        if is_gif {
+            Ok(Recorder::Gif(GifRecorder::new(file, scale, palette)?))
+        } else {
+            Ok(Recorder::Raw(file))
+        }
+    }
+
+    pub fn record(&mut self, grid: &[u8]) -> io::Result<()> {
+        match *self {
+            Recorder::Gif(ref mut gif) => gif.record(grid),
+            Recorder::Raw(ref mut raw) => raw.write_all(grid),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Recorder::Gif(gif) => gif.finish(),
+            Recorder::Raw(mut raw) => raw.flush(),
+        }
+    }
+}
+
+/// Gif frames can only be delayed in hundredths of a second, so identical 60 Hz frames
+/// are merged and each distinct frame is written once its on-screen time is known.
+pub struct GifRecorder {
+    encoder: gif::Encoder<BufWriter<File>>,
+    scale: usize,
+    width: u16,
+    height: u16,
+    pending: Option<Vec<u8>>,
+    // frame number the pending frame started on
+    pending_start: u64,
+    frames: u64,
+}
+
+impl GifRecorder {
+    fn new(file: BufWriter<File>, scale: usize, palette: &Palette) -> io::Result<GifRecorder> {
+        // imported here because png::HasParameters also provides a `set` method
+        use gif::SetParameter;
+
+        let mut global_palette = Vec::with_capacity(6);
+        global_palette.extend_from_slice(&palette.off);
+        global_palette.extend_from_slice(&palette.on);
+
+        // gif dimensions are 16 bits
+        let side = |pixels: usize| match pixels.checked_mul(scale) {
+            Some(size) if size <= u16::max_value() as usize => Ok(size as u16),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("a scale of {} is too large for a gif", scale))),
+        };
+        let (width, height) = (side(WIDTH)?, side(HEIGHT)?);
+        let mut encoder = gif::Encoder::new(file, width, height, &global_palette)?;
+        encoder.set(gif::Repeat::Infinite)?;
+
+        Ok(GifRecorder {
+            encoder: encoder,
+            scale: scale,
+            width: width,
+            height: height,
+            pending: None,
+            pending_start: 0,
+            frames: 0,
+        })
+    }
+
+    fn record(&mut self, grid: &[u8]) -> io::Result<()> {
+        let changed = match self.pending {
+            Some(ref pending) => &pending[..] != grid,
+            None => true,
+        };
+        if changed {
+            self.flush_pending()?;
+            self.pending = Some(grid.to_vec());
+            self.pending_start = self.frames;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn flush_pending(&mut self) -> io::Result<()> {
+        if let Some(grid) = self.pending.take() {
+            // round frame boundaries to gif ticks so delays don't drift over long recordings
+            let start = self.pending_start * GIF_TICKS_PER_SEC / FRAMES_PER_SEC;
+            let end = self.frames * GIF_TICKS_PER_SEC / FRAMES_PER_SEC;
+            let frame = gif::Frame {
+                width: self.width,
+                height: self.height,
+                delay: cmp::min(end - start, 0xFFFF) as u16,
+                buffer: Cow::Owned(scale_grid(&grid, self.scale)),
+                ..gif::Frame::default()
+            };
+            self.encoder.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush_pending()
+    }
+}
@@ -0,0 +1,331 @@
+/// What an RCA CDP1802 is wired to
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+    /// the byte put on the data bus by the device selected by `INP port`
+    fn input(&mut self, port: u8) -> u8;
+    /// `OUT port` with `value` on the data bus
+    fn output(&mut self, port: u8, value: u8);
+    /// the level of external flag EF1 to EF4, numbered from 0
+    fn flag(&mut self, flag: usize) -> bool;
+    /// the Q output changed
+    fn set_q(&mut self, on: bool);
+}
+
+/// An RCA CDP1802 CPU
+pub struct Cdp1802 {
+    // sixteen 16-bit scratchpad registers; P picks the program counter and X the data pointer
+    r: [u16; 16],
+    p: usize,
+    x: usize,
+    d: u8,
+    df: bool,
+    // X and P saved by an interrupt
+    t: u8,
+    ie: bool,
+    q: bool,
+    // waiting in IDL for an interrupt or DMA
+    idle: bool,
+}
+
+impl Cdp1802 {
+    /// as it comes out of reset: P, X and R0 are zero and interrupts are enabled
+    pub fn new() -> Cdp1802 {
+        Cdp1802 {
+            r: [0; 16],
+            p: 0,
+            x: 0,
+            d: 0,
+            df: false,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+        }
+    }
+
+    pub fn register(&self, n: usize) -> u16 {
+        self.r[n]
+    }
+
+    pub fn set_register(&mut self, n: usize, value: u16) {
+        self.r[n] = value;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.r[self.p]
+    }
+
+    /// which register is the program counter
+    pub fn p(&self) -> usize {
+        self.p
+    }
+
+    /// make `p` the program counter and `x` the data pointer, as SEP and SEX would
+    pub fn select(&mut self, p: usize, x: usize) {
+        self.p = p;
+        self.x = x;
+    }
+
+    /// take an interrupt if they're enabled, returning the machine cycles it took
+    pub fn interrupt(&mut self) -> u32 {
+        if !self.ie {
+            return 0;
+        }
+        self.t = ((self.x as u8) << 4) | self.p as u8;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    /// one cycle of output DMA: the byte at R0, which moves on to the next
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[self.p]);
+        self.r[self.p] = self.r[self.p].wrapping_add(1);
+        value
+    }
+
+    fn short_branch<B: Bus>(&mut self, bus: &mut B, taken: bool) {
+        let low = self.fetch(bus);
+        if taken {
+            let pc = self.r[self.p].wrapping_sub(1);
+            self.r[self.p] = (pc & 0xFF00) | low as u16;
+        }
+    }
+
+    fn long_branch<B: Bus>(&mut self, bus: &mut B, taken: bool) {
+        let high = self.fetch(bus);
+        let low = self.fetch(bus);
+        if taken {
+            self.r[self.p] = ((high as u16) << 8) | low as u16;
+        }
+    }
+
+    fn long_skip(&mut self, taken: bool) {
+        if taken {
+            self.r[self.p] = self.r[self.p].wrapping_add(2);
+        }
+    }
+
+    // D + value + carry, setting DF on carry out
+    fn add(&mut self, value: u8, carry: bool) {
+        let sum = self.d as u16 + value as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // a - b - borrow, with DF clear on borrow
+    fn subtract(&mut self, a: u8, b: u8, borrow: bool) {
+        let difference = a as i16 - b as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+    fn return_from_interrupt<B: Bus>(&mut self, bus: &mut B, enable: bool) {
+        let value = bus.read(self.r[self.x]);
+        self.r[self.x] = self.r[self.x].wrapping_add(1);
+        self.x = (value >> 4) as usize;
+        self.p = (value & 0xF) as usize;
+        self.ie = enable;
+    }
+
+    /// run one instruction, returning the machine cycles it took
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+        let opcode = self.fetch(bus);
+        let (i, n) = (opcode >> 4, (opcode & 0xF) as usize);
+        let rx = self.r[self.x];
+        match i {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[n]),
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => {
+                let taken = match n {
+                    0x0 => true,
+                    0x1 => self.q,
+                    0x2 => self.d == 0,
+                    0x3 => self.df,
+                    0x4...0x7 => bus.flag(n - 0x4),
+                    // SKP: skip the byte a branch would have jumped with
+                    0x8 => false,
+                    0x9 => !self.q,
+                    0xA => self.d != 0,
+                    0xB => !self.df,
+                    _ => !bus.flag(n - 0xC),
+                };
+                self.short_branch(bus, taken);
+            }
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[n], self.d),
+            0x6 => match n {
+                0x0 => self.r[self.x] = rx.wrapping_add(1),
+                0x1...0x7 => {
+                    let value = bus.read(rx);
+                    self.r[self.x] = rx.wrapping_add(1);
+                    bus.output(n as u8, value);
+                }
+                // not an instruction on the 1802
+                0x8 => (),
+                _ => {
+                    let value = bus.input(n as u8 - 8);
+                    bus.write(rx, value);
+                    self.d = value;
+                }
+            },
+            0x7 => match n {
+                0x0 => self.return_from_interrupt(bus, true),
+                0x1 => self.return_from_interrupt(bus, false),
+                0x2 => {
+                    self.d = bus.read(rx);
+                    self.r[self.x] = rx.wrapping_add(1);
+                }
+                0x3 => {
+                    bus.write(rx, self.d);
+                    self.r[self.x] = rx.wrapping_sub(1);
+                }
+                0x4 => {
+                    let value = bus.read(rx);
+                    let carry = self.df;
+                    self.add(value, carry);
+                }
+                0x5 => {
+                    let (value, borrow) = (bus.read(rx), !self.df);
+                    let d = self.d;
+                    self.subtract(value, d, borrow);
+                }
+                0x6 => {
+                    let carry = self.df;
+                    self.df = self.d & 1 == 1;
+                    self.d = (self.d >> 1) | ((carry as u8) << 7);
+                }
+                0x7 => {
+                    let (value, borrow) = (bus.read(rx), !self.df);
+                    let d = self.d;
+                    self.subtract(d, value, borrow);
+                }
+                0x8 => bus.write(rx, self.t),
+                0x9 => {
+                    self.t = ((self.x as u8) << 4) | self.p as u8;
+                    bus.write(self.r[2], self.t);
+                    self.x = self.p;
+                    self.r[2] = self.r[2].wrapping_sub(1);
+                }
+                0xA => {
+                    self.q = false;
+                    bus.set_q(false);
+                }
+                0xB => {
+                    self.q = true;
+                    bus.set_q(true);
+                }
+                0xC => {
+                    let value = self.fetch(bus);
+                    let carry = self.df;
+                    self.add(value, carry);
+                }
+                0xD => {
+                    let (value, borrow) = (self.fetch(bus), !self.df);
+                    let d = self.d;
+                    self.subtract(value, d, borrow);
+                }
+                0xE => {
+                    let carry = self.df;
+                    self.df = self.d & 0x80 != 0;
+                    self.d = (self.d << 1) | carry as u8;
+                }
+                _ => {
+                    let (value, borrow) = (self.fetch(bus), !self.df);
+                    let d = self.d;
+                    self.subtract(d, value, borrow);
+                }
+            },
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16,
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | ((self.d as u16) << 8),
+            0xC => {
+                match n {
+                    0x0 => self.long_branch(bus, true),
+                    0x1 => { let q = self.q; self.long_branch(bus, q) }
+                    0x2 => { let zero = self.d == 0; self.long_branch(bus, zero) }
+                    0x3 => { let df = self.df; self.long_branch(bus, df) }
+                    // NOP
+                    0x4 => (),
+                    0x5 => { let q = self.q; self.long_skip(!q) }
+                    0x6 => { let zero = self.d == 0; self.long_skip(!zero) }
+                    0x7 => { let df = self.df; self.long_skip(!df) }
+                    0x8 => self.long_skip(true),
+                    0x9 => { let q = self.q; self.long_branch(bus, !q) }
+                    0xA => { let zero = self.d == 0; self.long_branch(bus, !zero) }
+                    0xB => { let df = self.df; self.long_branch(bus, !df) }
+                    0xC => { let ie = self.ie; self.long_skip(ie) }
+                    0xD => { let q = self.q; self.long_skip(q) }
+                    0xE => { let zero = self.d == 0; self.long_skip(zero) }
+                    _ => { let df = self.df; self.long_skip(df) }
+                }
+                // long branches and skips take an extra cycle
+                return 3;
+            }
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => match n {
+                0x0 => self.d = bus.read(rx),
+                0x1 => self.d |= bus.read(rx),
+                0x2 => self.d &= bus.read(rx),
+                0x3 => self.d ^= bus.read(rx),
+                0x4 => {
+                    let value = bus.read(rx);
+                    self.add(value, false);
+                }
+                0x5 => {
+                    let (value, d) = (bus.read(rx), self.d);
+                    self.subtract(value, d, false);
+                }
+                0x6 => {
+                    self.df = self.d & 1 == 1;
+                    self.d >>= 1;
+                }
+                0x7 => {
+                    let (value, d) = (bus.read(rx), self.d);
+                    self.subtract(d, value, false);
+                }
+                0x8 => self.d = self.fetch(bus),
+                0x9 => self.d |= self.fetch(bus),
+                0xA => self.d &= self.fetch(bus),
+                0xB => self.d ^= self.fetch(bus),
+                0xC => {
+                    let value = self.fetch(bus);
+                    self.add(value, false);
+                }
+                0xD => {
+                    let (value, d) = (self.fetch(bus), self.d);
+                    self.subtract(value, d, false);
+                }
+                0xE => {
+                    self.df = self.d & 0x80 != 0;
+                    self.d <<= 1;
+                }
+                _ => {
+                    let (value, d) = (self.fetch(bus), self.d);
+                    self.subtract(d, value, false);
+                }
+            },
+        }
+        2
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use serde_json::{self, Value};
+
+use disassembler::Analysis;
+use memory_bus::ROM_START;
+use opcodes::OpCode;
+use opcodes::OpCode::*;
+use opcodes::OP_SIZE;
+use symbols::Symbols;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    // execution continues with the next instruction
+    Fallthrough,
+    Jump,
+    // a skip instruction jumping over the next instruction
+    Skip,
+    Call,
+    // a `JP V0, nnn` target guessed statically (the first entry of the jump table)
+    Indirect,
+    // a target observed while running the rom
+    Dynamic,
+}
+
+impl EdgeKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Skip => "skip",
+            EdgeKind::Call => "call",
+            EdgeKind::Indirect => "indirect",
+            EdgeKind::Dynamic => "dynamic",
+        }
+    }
+}
+
+pub struct Edge {
+    // start address of the block the edge leaves
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A run of instructions that is only entered at the top and only left at the bottom
+pub struct Block {
+    pub start: usize,
+    // address one past the last instruction
+    pub end: usize,
+    // true if the block ends in a `JP V0, nnn` whose targets can't all be known statically
+    pub indirect: bool,
+}
+
+/// A subroutine: the blocks reachable from its entry without following calls
+pub struct Function {
+    pub entry: usize,
+    pub blocks: BTreeSet<usize>,
+    pub calls: BTreeSet<usize>,
+}
+
+/// Control-flow and call graphs of a rom
+pub struct Cfg<'a> {
+    analysis: Analysis<'a>,
+    pub blocks: BTreeMap<usize, Block>,
+    pub edges: Vec<Edge>,
+    pub functions: BTreeMap<usize, Function>,
+}
+
+/// Read jumps observed at runtime, one `FROM TO` pair of hex addresses per line, as written
+/// by `--jump-log`
+pub fn read_dynamic_edges<P: AsRef<Path>>(path: P) -> io::Result<Vec<(usize, usize)>> {
+    let file = File::open(path)?;
+    let mut edges = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let addrs: Vec<usize> = line.split_whitespace()
+                                    .filter_map(|a| usize::from_str_radix(a.trim_left_matches("0x"), 16).ok())
+                                    .collect();
+        if addrs.len() != 2 || line.split_whitespace().count() != 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid jump '{}'", line)));
+        }
+        edges.push((addrs[0], addrs[1]));
+    }
+    Ok(edges)
+}
+
+impl<'a> Cfg<'a> {
+    /// build the graphs for `rom`, adding jumps seen at runtime as `(from, to)` instruction
+    /// addresses
+    pub fn new(rom: &'a [u8], dynamic: &[(usize, usize)], symbols: &'a Symbols) -> Cfg<'a> {
+        let targets: Vec<usize> = dynamic.iter().map(|&(_, to)| to).collect();
+        let analysis = Analysis::with_entries(rom, &targets).with_symbols(symbols);
+        let mut cfg = Cfg {
+            analysis: analysis,
+            blocks: BTreeMap::new(),
+            edges: vec![],
+            functions: BTreeMap::new(),
+        };
+        cfg.build_blocks(&targets);
+        cfg.add_dynamic_edges(dynamic);
+        cfg.build_functions();
+        cfg
+    }
+
+    fn build_blocks(&mut self, dynamic_targets: &[usize]) {
+        let code = self.analysis.code().clone();
+
+        // every address control can arrive at other than by falling through starts a block
+        let mut leaders: BTreeSet<usize> = dynamic_targets.iter().cloned().collect();
+        leaders.insert(ROM_START);
+        for (&addr, opcode) in &code {
+            let next = addr + OP_SIZE;
+            match *opcode {
+                JpConst { nnn } | Call { nnn } | JpOffset { nnn } => {
+                    leaders.insert(nnn);
+                    leaders.insert(next);
+                }
+                Return | Eof => {
+                    leaders.insert(next);
+                }
+                _ if is_skip(opcode) => {
+                    leaders.insert(next);
+                    leaders.insert(next + OP_SIZE);
+                }
+                _ => (),
+            }
+        }
+
+        for &start in leaders.iter().filter(|addr| code.contains_key(addr)) {
+            let mut addr = start;
+            loop {
+                let opcode = code[&addr];
+                let next = addr + OP_SIZE;
+                let ends_block = match opcode {
+                    JpConst { .. } | Call { .. } | JpOffset { .. } | Return | Eof => true,
+                    _ => is_skip(&opcode),
+                };
+                if ends_block || leaders.contains(&next) || !code.contains_key(&next) {
+                    self.finish_block(start, addr, opcode, &code);
+                    break;
+                }
+                addr = next;
+            }
+        }
+    }
+
+    // record the block from `start` to the instruction `last`, and the edges leaving it
+    fn finish_block(&mut self, start: usize, last: usize, opcode: OpCode, code: &BTreeMap<usize, OpCode>) {
+        let next = last + OP_SIZE;
+        let mut edges = vec![];
+        match opcode {
+            JpConst { nnn } => edges.push((nnn, EdgeKind::Jump)),
+            Call { nnn } => {
+                edges.push((nnn, EdgeKind::Call));
+                edges.push((next, EdgeKind::Fallthrough));
+            }
+            JpOffset { nnn } => edges.push((nnn, EdgeKind::Indirect)),
+            Return | Eof => (),
+            _ if is_skip(&opcode) => {
+                edges.push((next, EdgeKind::Fallthrough));
+                edges.push((next + OP_SIZE, EdgeKind::Skip));
+            }
+            _ => edges.push((next, EdgeKind::Fallthrough)),
+        }
+
+        for (to, kind) in edges {
+            // a skip over the last instruction of the rom (or a call that never returns into
+            // code) has nowhere to go
+            if code.contains_key(&to) {
+                self.edges.push(Edge { from: start, to: to, kind: kind });
+            }
+        }
+        let indirect = match opcode {
+            JpOffset { .. } => true,
+            _ => false,
+        };
+        self.blocks.insert(start, Block { start: start, end: next, indirect: indirect });
+    }
+
+    fn add_dynamic_edges(&mut self, dynamic: &[(usize, usize)]) {
+        for &(from, to) in dynamic {
+            let block = match self.block_containing(from) {
+                Some(block) => block,
+                None => {
+                    warn!("ignoring jump from {:03X}, which isn't in a known block", from);
+                    continue;
+                }
+            };
+            let known = self.edges.iter().any(|e| e.from == block && e.to == to && e.kind != EdgeKind::Indirect);
+            if !known && self.blocks.contains_key(&to) {
+                self.edges.push(Edge { from: block, to: to, kind: EdgeKind::Dynamic });
+            }
+        }
+    }
+
+    /// start address of the block containing the instruction at `addr`
+    pub fn block_containing(&self, addr: usize) -> Option<usize> {
+        match self.blocks.range(..addr + 1).next_back() {
+            Some((&start, block)) if addr < block.end => Some(start),
+            _ => None,
+        }
+    }
+
+    fn build_functions(&mut self) {
+        let mut entries: BTreeSet<usize> = self.edges.iter()
+                                                     .filter(|e| e.kind == EdgeKind::Call)
+                                                     .map(|e| e.to)
+                                                     .collect();
+        entries.insert(ROM_START);
+
+        for entry in entries {
+            let mut function = Function { entry: entry, blocks: BTreeSet::new(), calls: BTreeSet::new() };
+            let mut pending = vec![entry];
+            while let Some(block) = pending.pop() {
+                if !self.blocks.contains_key(&block) || !function.blocks.insert(block) {
+                    continue;
+                }
+                for edge in self.edges.iter().filter(|e| e.from == block) {
+                    match edge.kind {
+                        EdgeKind::Call => {
+                            function.calls.insert(edge.to);
+                        }
+                        _ => pending.push(edge.to),
+                    }
+                }
+            }
+            self.functions.insert(entry, function);
+        }
+    }
+
+    fn instruction_text(&self, addr: usize, opcode: &OpCode) -> String {
+        let instr = self.analysis.word(addr).expect("decoded instructions are in the rom");
+        match self.analysis.mnemonic(opcode) {
+            Some(text) => text,
+            None => format!("DW 0x{:04X}", instr),
+        }
+    }
+
+    fn instructions(&self, block: &Block) -> Vec<(usize, &OpCode)> {
+        self.analysis.code().range(block.start..block.end).map(|(&addr, opcode)| (addr, opcode)).collect()
+    }
+
+    /// the control-flow graph in Graphviz DOT format, with one node per basic block
+    pub fn cfg_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if self.functions.contains_key(&block.start) {
+                label.push_str(&format!("{}:\\l", self.analysis.label(block.start)));
+            }
+            for (addr, opcode) in self.instructions(block) {
+                label.push_str(&format!("{:03X}  {}\\l", addr, self.instruction_text(addr, opcode)));
+            }
+            let style = if block.indirect { ", color=red" } else { "" };
+            writeln!(out, "    b{:03X} [label=\"{}\"{}];", block.start, label, style).unwrap();
+        }
+        for edge in self.edges.iter().filter(|e| e.kind != EdgeKind::Call) {
+            let style = match edge.kind {
+                EdgeKind::Skip => " [label=\"skip\"]",
+                EdgeKind::Indirect => " [style=dashed, color=red, label=\"indirect\"]",
+                EdgeKind::Dynamic => " [style=dashed, color=blue, label=\"dynamic\"]",
+                _ => "",
+            };
+            writeln!(out, "    b{:03X} -> b{:03X}{};", edge.from, edge.to, style).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// the call graph in Graphviz DOT format, with one node per subroutine
+    pub fn call_graph_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph calls {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for function in self.functions.values() {
+            let indirect = function.blocks.iter().any(|b| self.blocks[b].indirect);
+            let style = if indirect { ", color=red" } else { "" };
+            writeln!(out, "    f{:03X} [label=\"{}\"{}];", function.entry, self.analysis.label(function.entry), style).unwrap();
+        }
+        for function in self.functions.values() {
+            for callee in &function.calls {
+                writeln!(out, "    f{:03X} -> f{:03X};", function.entry, callee).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    fn functions_json(&self) -> Value {
+        let functions: Vec<Value> = self.functions.values().map(|function| {
+            json!({
+                "entry": function.entry,
+                "name": self.analysis.label(function.entry),
+                "blocks": function.blocks.iter().collect::<Vec<_>>(),
+                "calls": function.calls.iter().collect::<Vec<_>>(),
+            })
+        }).collect();
+        Value::Array(functions)
+    }
+
+    /// blocks, edges and functions as JSON
+    pub fn cfg_json(&self) -> String {
+        let blocks: Vec<Value> = self.blocks.values().map(|block| {
+            let instructions: Vec<Value> = self.instructions(block).into_iter().map(|(addr, opcode)| {
+                json!({
+                    "addr": addr,
+                    "word": self.analysis.word(addr),
+                    "text": self.instruction_text(addr, opcode),
+                })
+            }).collect();
+            json!({
+                "start": block.start,
+                "end": block.end,
+                "indirect": block.indirect,
+                "instructions": instructions,
+            })
+        }).collect();
+        let edges: Vec<Value> = self.edges.iter().map(|edge| {
+            json!({ "from": edge.from, "to": edge.to, "kind": edge.kind.name() })
+        }).collect();
+        let graph = json!({
+            "blocks": blocks,
+            "edges": edges,
+            "functions": self.functions_json(),
+        });
+        serde_json::to_string_pretty(&graph).expect("json values always serialize")
+    }
+
+    /// subroutines and the subroutines they call as JSON
+    pub fn call_graph_json(&self) -> String {
+        let graph = json!({ "functions": self.functions_json() });
+        serde_json::to_string_pretty(&graph).expect("json values always serialize")
+    }
+}
+
+fn is_skip(opcode: &OpCode) -> bool {
+    match *opcode {
+        SkpEqConst { .. } | SkpNeConst { .. } | SkpEqReg { .. } |
+        JpRegNe { .. } | SkpKeyEq { .. } | SkpKeyNe { .. } => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use memory_bus::{MemoryBus, MEMORY_SIZE};
+
+/// Cheat codes for one rom: each address is forced to its value at the end of every frame
+pub type Cheats = BTreeMap<usize, u8>;
+
+/// parse a code like `2A4=05`, with both sides in hex
+pub fn parse_code(code: &str) -> Result<(usize, u8), String> {
+    let mut sides = code.splitn(2, '=');
+    let addr = sides.next().and_then(|addr| usize::from_str_radix(addr.trim().trim_left_matches("0x"), 16).ok());
+    let value = sides.next().and_then(|value| u8::from_str_radix(value.trim().trim_left_matches("0x"), 16).ok());
+    match (addr, value) {
+        (Some(addr), Some(value)) if addr < MEMORY_SIZE => Ok((addr, value)),
+        _ => Err(format!("expected a cheat code like 2A4=05 but found '{}'", code)),
+    }
+}
+
+/// read the codes for the rom with `hash` from a file of `HASH ADDR=VALUE` lines. Blank lines
+/// and lines starting with `#` are skipped, and a missing file has no codes.
+pub fn load<P: AsRef<Path>>(path: P, hash: &str) -> io::Result<Cheats> {
+    let mut cheats = Cheats::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(cheats),
+        Err(e) => return Err(e),
+    };
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next().map(parse_code), fields.next()) {
+            (Some(rom), Some(Ok(_)), None) if rom != hash => (),
+            (Some(_), Some(Ok((addr, value))), None) => {
+                cheats.insert(addr, value);
+            }
+            _ => {
+                let message = format!("line {}: expected 'HASH ADDR=VALUE' but found '{}'", idx + 1, line);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+    }
+    Ok(cheats)
+}
+
+/// replace the codes for the rom with `hash`, keeping other roms' codes and any comments
+pub fn save<P: AsRef<Path>>(path: P, hash: &str, cheats: &Cheats) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut lines = vec![];
+    if path.exists() {
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.split_whitespace().next() != Some(hash) {
+                lines.push(line);
+            }
+        }
+    } else {
+        lines.push("# chip8 cheat codes: ROM_SHA1 ADDR=VALUE, frozen every frame".to_owned());
+    }
+    for (addr, value) in cheats {
+        lines.push(format!("{} {:03X}={:02X}", hash, addr, value));
+    }
+    let mut file = File::create(path)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// How a search narrows its candidates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compare {
+    Equal,
+    Greater,
+    Less,
+    Changed,
+}
+
+impl Compare {
+    pub fn parse(name: &str) -> Option<Compare> {
+        match name {
+            "eq" => Some(Compare::Equal),
+            "gt" => Some(Compare::Greater),
+            "lt" => Some(Compare::Less),
+            "changed" => Some(Compare::Changed),
+            _ => None,
+        }
+    }
+}
+
+/// A RAM search: every address starts as a candidate, and each `narrow` keeps those whose
+/// value compares as asked, either with a given value or with the value at the last snapshot
+pub struct Search {
+    candidates: Vec<usize>,
+    // memory when the search started or was last narrowed
+    last: Vec<u8>,
+}
+
+impl Search {
+    pub fn new(mem_bus: &MemoryBus) -> Search {
+        Search {
+            candidates: (0..MEMORY_SIZE).collect(),
+            last: snapshot(mem_bus),
+        }
+    }
+
+    /// drop candidates that don't compare; `Changed` ignores `value`
+    pub fn narrow(&mut self, mem_bus: &MemoryBus, compare: Compare, value: Option<u8>) {
+        let last = &self.last;
+        self.candidates.retain(|&addr| {
+            let now = mem_bus.peek_word(addr);
+            let then = match (compare, value) {
+                (Compare::Changed, _) | (_, None) => last[addr],
+                (_, Some(value)) => value,
+            };
+            match compare {
+                Compare::Equal => now == then,
+                Compare::Greater => now > then,
+                Compare::Less => now < then,
+                Compare::Changed => now != then,
+            }
+        });
+        self.last = snapshot(mem_bus);
+    }
+
+    pub fn candidates(&self) -> &[usize] {
+        &self.candidates
+    }
+}
+
+// peek so the search doesn't show up as reads in the coverage
+fn snapshot(mem_bus: &MemoryBus) -> Vec<u8> {
+    (0..MEMORY_SIZE).map(|addr| mem_bus.peek_word(addr)).collect()
+}
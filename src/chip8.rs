@@ -1,21 +1,173 @@
 use std::{fmt, io, thread};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use time;
+use time::{self, Timespec};
 
-use cpu::Cpu;
+use capture::{self, Palette, Recorder};
+use cheats::{self, Cheats};
+use coverage::Coverage;
+use cpu::{Cpu, CpuState};
+use dap::{DapServer, Stop};
+use debugger::{self, Action, Debugger, View};
+use disassembler;
+use gdb::{GdbStub, Target};
 use display::Display;
-use keyboard::Keyboard;
-use memory_bus::MemoryBus;
-use opcodes::OpCode;
-use sound::Sound;
+use keyboard::{Hotkey, Keyboard};
+use memory_bus::{MemoryBus, ROM_START};
+use native::{self, Routine, SysMode};
+use opcodes::{OpCode, OP_SIZE};
+use overlay::Overlay;
+use profile::Profiler;
+use quirks::Quirks;
+use rom_db;
+use sound::{AudioBackend, Sound, SoundConfig};
+use source_map::SourceMap;
+use symbols::Symbols;
 use timer::Timer;
+use timing::{self, Timing};
+use trace::{TraceFilter, TraceRecord, Tracer};
+use watch::{self, Reload, Watcher};
 use window::Window;
 
-const CPU_SPEED_NS: i64 = 1_000_000_000 / 500;
+// emulated time taken by each instruction, unless a tickrate is given
+const CPU_SPEED_NS: u64 = 1_000_000_000 / 500;
+const FRAME_NS: u64 = 1_000_000_000 / 60;
+// how far emulation can fall behind the wall clock (e.g. while blocked waiting for a key)
+// before we stop trying to catch up
+const MAX_LAG_NS: i64 = 100_000_000;
+// frames between refreshes of the debugger overlay
+const OVERLAY_FRAMES: u64 = 6;
+// frames between checks of a watched rom for changes
+const WATCH_FRAMES: u64 = 15;
+
+/// Settings for a single run of the emulator
+pub struct Config {
+    // where the rom was loaded from, if it came from a file
+    pub rom_path: Option<PathBuf>,
+    // the game's name, for the window title
+    pub title: Option<String>,
+    // the chip-8-database id of the platform the rom is for, if known
+    pub platform: Option<String>,
+    pub quirks: Quirks,
+    // instructions per 60 Hz frame
+    pub tickrate: Option<u32>,
+    pub timing: Timing,
+    // how 0NNN machine code calls are run, and routines emulated in place of some
+    pub sys: SysMode,
+    pub sys_routines: Vec<(usize, Routine)>,
+    // keypad keys for game actions like `up` and `a`, which also get their own keys
+    pub keys: Vec<(String, u8)>,
+    // start in the debugger, stopped before the first instruction
+    pub step: bool,
+    // debugger breakpoint locations
+    pub breakpoints: Vec<String>,
+    // names for addresses in the debugger, traces and profiles
+    pub symbols: Symbols,
+    // number of cycles to execute before exiting
+    pub cycles: Option<u64>,
+    // number of 60 Hz frames a whole COSMAC VIP runs before exiting
+    pub frames: Option<u64>,
+    // run without opening a window
+    pub headless: bool,
+    // show the debugger overlay under the display, and stop there rather than in the terminal
+    pub overlay: bool,
+    // save a png of the display here on exit
+    pub screenshot: Option<PathBuf>,
+    // record every frame to this file from startup
+    pub record: Option<PathBuf>,
+    // size of a CHIP-8 pixel in captured images
+    pub scale: usize,
+    pub palette: Palette,
+    pub sound: SoundConfig,
+    pub audio: AudioBackend,
+    // pace the emulator by how fast the audio device consumes samples instead of by the
+    // wall clock
+    pub audio_sync: bool,
+    // where the rom was compiled from, so the debugger can show source lines
+    pub source_map: Option<SourceMap>,
+    // write the targets of indirect jumps here as they're taken
+    pub jump_log: Option<PathBuf>,
+    // write a record of every instruction that passes `trace_filter` here
+    pub trace: Option<PathBuf>,
+    pub trace_filter: TraceFilter,
+    // print a profile of where instructions were spent on exit
+    pub profile: bool,
+    // write the profile's call stacks here on exit, for flame graphs
+    pub profile_folded: Option<PathBuf>,
+    // add this run's coverage to this lcov-like file
+    pub coverage: Option<PathBuf>,
+    // write a disassembly annotated with the coverage here
+    pub coverage_listing: Option<PathBuf>,
+    // wait for a gdb client on this local port and let it control execution
+    pub gdb: Option<u16>,
+    // cheat codes to add to the ones saved for this rom
+    pub cheats: Vec<(usize, u8)>,
+    // where cheat codes are saved, keyed by rom hash
+    pub cheats_file: Option<PathBuf>,
+    // reload the rom when `rom_path` changes, starting from this
+    pub watch: Option<Reload>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            rom_path: None,
+            title: None,
+            platform: None,
+            quirks: Quirks::default(),
+            tickrate: None,
+            timing: Timing::Fixed,
+            sys: SysMode::Log,
+            sys_routines: vec![],
+            keys: vec![],
+            step: false,
+            breakpoints: vec![],
+            symbols: Symbols::new(),
+            cycles: None,
+            frames: None,
+            headless: false,
+            overlay: false,
+            screenshot: None,
+            record: None,
+            scale: 10,
+            palette: Palette::default(),
+            sound: SoundConfig::default(),
+            audio: AudioBackend::Auto,
+            audio_sync: false,
+            source_map: None,
+            jump_log: None,
+            trace: None,
+            trace_filter: TraceFilter::default(),
+            profile: false,
+            profile_folded: None,
+            coverage: None,
+            coverage_listing: None,
+            gdb: None,
+            cheats: vec![],
+            cheats_file: None,
+            watch: None,
+        }
+    }
+}
+
+/// Everything needed to put the machine back as it was
+#[derive(Clone)]
+struct SaveState {
+    cpu: CpuState,
+    mem: Vec<u8>,
+    delay_timer: u8,
+    sound_timer: u8,
+    grid: Vec<u8>,
+}
 
 pub struct Chip8 {
+    // the program that's running, as loaded at startup or last reloaded
+    rom: Vec<u8>,
     cpu: Cpu,
     mem_bus: MemoryBus,
     delay_timer: Timer,
@@ -23,64 +175,191 @@ pub struct Chip8 {
     display: Display,
     keyboard: Keyboard,
     sound: Sound,
+
+    // emulated time since startup; timers, sound and frames all follow this rather than
+    // the wall clock so they behave the same regardless of machine load
+    emulated_ns: u64,
+    next_frame_ns: u64,
+    // emulated time taken by each instruction, unless timing follows the VIP
+    cycle_ns: u64,
+    timing: Timing,
+    // 60 Hz frames elapsed since startup
+    frames: u64,
+
+    // wall-clock pacing: frames run since `pace_at`
+    pace_at: Timespec,
+    paced_frames: u64,
+    audio_sync: bool,
+
+    // screenshot and recording settings
+    scale: usize,
+    palette: Palette,
+    recorder: Option<Recorder>,
+
+    source_map: Option<SourceMap>,
+    symbols: Symbols,
+    debugger: Option<Debugger>,
+    overlay: Option<Overlay>,
+    // stopped in the window until F5 or F10 is pressed
+    paused: bool,
+    gdb: Option<GdbStub>,
+    dap: Option<DapServer>,
+
+    // indirect jumps seen so far and where to log new ones
+    jumps: HashSet<(usize, usize)>,
+    jump_log: Option<BufWriter<File>>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+
+    watcher: Option<Watcher>,
+    reload: Reload,
+    // for a fresh machine code handler to go with a reloaded rom
+    sys: SysMode,
+    sys_routines: Vec<(usize, Routine)>,
+    // saved with F6 and restored with F7
+    saved_state: Option<SaveState>,
 }
 
 impl fmt::Debug for Chip8 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // TODO FIXME: if timers are ever run out of lockstep from cpu, the values will be wrong
-        // here
         write!(f, "{:?} dt={:#02X} st={:#02X}", self.cpu, self.delay_timer.get_value(), self.sound_timer.get_value())
     }
 }
 
 impl Chip8 {
-    pub fn disassemble(rom: &[u8]) {
-        let mut count = 0x200;
-        for chunk in rom.chunks(2) {
-            let word_1 = chunk[0];
-            let instr = 
-                if word_1 == 0x0A {
-                    0x0A00
-                } else {
-                    let word_2 = chunk[1];
-                    // opcodes are big-endian
-                    ((word_1 as u16) << 8) | (word_2 as u16)
-                };
-            let opcode: OpCode = instr.into();
-            println!("{:#03X} {:04X}    {}", count, instr, opcode);
-            count += 2;
-        }
+    pub fn disassemble(rom: &[u8], symbols: &Symbols) {
+        print!("{}", disassembler::disassemble(rom, symbols));
+    }
+
+    pub fn run(rom: &[u8], config: &Config) -> Result<(), String> {
+        Chip8::launch(rom, config, None)
+    }
+
+    /// run under the control of a debug adapter client
+    pub fn debug_adapter(rom: &[u8], config: &Config, dap: DapServer) -> Result<(), String> {
+        Chip8::launch(rom, config, Some(dap))
     }
 
-    pub fn run(rom: &[u8], step: bool, cycles: Option<u64>) {
+    fn launch(rom: &[u8], config: &Config, dap: Option<DapServer>) -> Result<(), String> {
         let mut mem_bus = MemoryBus::new();
-        mem_bus.load_rom(rom);
-        let window = Arc::new(Mutex::new(Window::new(64, 32)));
+        mem_bus.load_rom(rom).map_err(|e| format!("could not load the rom: {}", e))?;
+        let sound = Sound::new(&config.audio, &config.sound)?;
+        let window = if config.headless {
+            None
+        } else {
+            let title = match config.title {
+                Some(ref title) => format!("CHIP-8 - {}", title),
+                None => "CHIP-8".to_owned(),
+            };
+            Some(Arc::new(Mutex::new(Window::new(64, 32, config.overlay, &title, config.palette))))
+        };
 
         let mut c8 = Chip8 {
+            rom: rom.to_vec(),
             cpu: Cpu::new(),
             mem_bus: mem_bus,
             delay_timer: Timer::new(),
             sound_timer: Timer::new(),
             display: Display::new(window.clone()),
             keyboard: Keyboard::new(window.clone()),
-            sound: Sound::new(),
+            sound: sound,
+            emulated_ns: 0,
+            next_frame_ns: FRAME_NS,
+            cycle_ns: config.tickrate.map(|tickrate| FRAME_NS / tickrate as u64).unwrap_or(CPU_SPEED_NS),
+            timing: config.timing,
+            frames: 0,
+            pace_at: time::get_time(),
+            paced_frames: 0,
+            audio_sync: config.audio_sync,
+            scale: config.scale,
+            palette: config.palette,
+            recorder: None,
+            source_map: config.source_map.clone(),
+            symbols: config.symbols.clone(),
+            debugger: None,
+            overlay: None,
+            paused: false,
+            gdb: None,
+            dap: None,
+            jumps: HashSet::new(),
+            jump_log: config.jump_log.as_ref().map(|path| {
+                BufWriter::new(File::create(path).expect("Could not create jump log"))
+            }),
+            tracer: config.trace.as_ref().map(|path| {
+                Tracer::create(path, config.trace_filter.clone()).expect("Could not create trace file")
+            }),
+            profiler: None,
+            watcher: None,
+            reload: config.watch.unwrap_or(Reload::Reset),
+            sys: config.sys,
+            sys_routines: config.sys_routines.clone(),
+            saved_state: None,
         };
+        c8.mem_bus.log_writes(c8.tracer.is_some());
+        c8.cpu.set_quirks(config.quirks);
+        c8.cpu.set_native(native::handler(config.sys, &config.sys_routines, rom.len()));
+        for &(ref action, hex_code) in &config.keys {
+            match Keyboard::action_key(action) {
+                Some(key) => c8.keyboard.bind(key, hex_code),
+                None => debug!("no key for the {} action", action),
+            }
+        }
+        if config.profile || config.profile_folded.is_some() {
+            c8.profiler = Some(Profiler::new());
+        }
+        if config.step || !config.breakpoints.is_empty() {
+            let mut debugger = Debugger::new(config.step);
+            for location in &config.breakpoints {
+                match debugger::resolve(location, &c8.symbols, c8.source_map.as_ref()) {
+                    Some(addr) => debugger.add_breakpoint(addr),
+                    None => warn!("ignoring breakpoint at unknown location {}", location),
+                }
+            }
+            c8.debugger = Some(debugger);
+        }
+        if config.overlay {
+            c8.overlay = window.clone().map(Overlay::new);
+        }
+        if let Some(port) = config.gdb {
+            c8.gdb = Some(GdbStub::listen(port).expect("Could not start gdb server"));
+        }
+        if let Some(mut dap) = dap {
+            if let Action::Quit = dap.start(&c8.symbols, c8.source_map.as_ref()) {
+                return Ok(());
+            }
+            c8.dap = Some(dap);
+        }
+        if config.coverage.is_some() || config.coverage_listing.is_some() {
+            c8.mem_bus.track_coverage(Chip8::load_coverage(rom, config));
+        }
+        let rom_hash = rom_db::rom_hash(rom);
+        let saved_cheats = Chip8::load_cheats(config, &rom_hash);
+        for (&addr, &value) in &saved_cheats {
+            c8.mem_bus.set_cheat(addr, value);
+        }
+        for &(addr, value) in &config.cheats {
+            c8.mem_bus.set_cheat(addr, value);
+        }
 
-        let start_time = time::get_time();
+        if let Some(ref path) = config.record {
+            c8.start_recording(path);
+        }
+        if let (Some(_), Some(ref path)) = (config.watch, config.rom_path.as_ref()) {
+            info!("watching {} for changes", path.display());
+            c8.watcher = Some(Watcher::new(path));
+        }
 
-        // boot sound
-        c8.sound.emit();
+        let start_time = time::get_time();
 
         // either loop at most some specified number of cycles or loop infinitely until rom exit
-        match cycles {
+        match config.cycles {
             Some(cycles) => while c8.cpu.instruction_count() < cycles {
-                if c8._run(step) {
+                if c8._run() {
                     break
                 }
             },
             None => loop { 
-                if c8._run(step) { 
+                if c8._run() { 
                     break 
                 } 
             },
@@ -88,50 +367,520 @@ impl Chip8 {
 
         let end_time = time::get_time();
 
+        if let Some(ref mut gdb) = c8.gdb {
+            gdb.exited();
+        }
+        if let Some(ref mut dap) = c8.dap {
+            dap.exited();
+        }
+
+        if let Some(ref path) = config.screenshot {
+            c8.screenshot(path);
+        }
+        c8.stop_recording();
+        c8.sound.flush();
+        c8.stop_tracing();
+
         info!("Shutdown -- elapsed time {:?}", end_time - start_time);
+
+        c8.save_coverage(config);
+        if let Some(ref path) = config.cheats_file {
+            if *c8.mem_bus.cheats() != saved_cheats {
+                match cheats::save(path, &rom_hash, c8.mem_bus.cheats()) {
+                    Ok(()) => info!("saved cheats to {:?}", path),
+                    Err(e) => error!("failed to save cheats to {:?}: {}", path, e),
+                }
+            }
+        }
+
+        if let Some(ref profiler) = c8.profiler {
+            if config.profile {
+                print!("{}", profiler.report(&c8.symbols));
+            }
+            if let Some(ref path) = config.profile_folded {
+                match profiler.write_folded(path, &c8.symbols) {
+                    Ok(()) => info!("wrote folded stacks to {:?}", path),
+                    Err(e) => error!("failed to write folded stacks to {:?}: {}", path, e),
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn _run(&mut self, step: bool) -> bool {
+    fn _run(&mut self) -> bool {
+        if let Action::Quit = self.debug() {
+            return true;
+        }
+
         self.execute_cycle();
 
         if self.should_exit() {
             return true;
         }
 
-        if step {
-            println!("{:?}", self);
-            let mut input = String::new();
-            let _ = io::stdin().read_line(&mut input);
-        } else {
-            debug!("{:?}", self);
+        debug!("{:?}", self);
+        false
+    }
+
+    // hand control to the debugger if it wants to stop before the next instruction
+    fn debug(&mut self) -> Action {
+        let pc = self.cpu.pc();
+        if let Some(ref mut gdb) = self.gdb {
+            if let Some(signal) = gdb.should_stop(pc) {
+                let mut target = Target {
+                    cpu: &mut self.cpu,
+                    mem_bus: &mut self.mem_bus,
+                    delay_timer: &mut self.delay_timer,
+                    sound_timer: &mut self.sound_timer,
+                };
+                return gdb.serve(signal, &mut target);
+            }
+        }
+        let depth = self.cpu.stack_depth();
+        let reason = match self.dap.as_mut().and_then(|dap| dap.poll(pc, depth)) {
+            Some(Stop::Paused(reason)) => Some(reason),
+            Some(Stop::Disconnected) => return Action::Quit,
+            None => None,
+        };
+        let stop = match self.debugger {
+            Some(ref mut debugger) => debugger.should_stop(pc),
+            None => false,
+        };
+        // with the overlay, the debugger stops in the window instead of the terminal
+        if reason.is_none() && (self.paused || stop && self.overlay.is_some()) {
+            return self.pause();
+        }
+        if !stop && reason.is_none() {
+            return Action::Run;
         }
+        match reason {
+            Some(reason) => {
+                let mut dap = self.dap.take().expect("checked above");
+                let action = dap.serve(reason, &self.view());
+                self.dap = Some(dap);
+                action
+            }
+            None => {
+                let mut debugger = self.debugger.take().expect("checked above");
+                let action = debugger.prompt(&mut self.view());
+                self.debugger = Some(debugger);
+                action
+            }
+        }
+    }
 
-        false
+    fn view(&mut self) -> View {
+        View {
+            status: format!("{:?}", self),
+            cpu: self.cpu.state(),
+            delay_timer: self.delay_timer.get_value(),
+            sound_timer: self.sound_timer.get_value(),
+            mem_bus: &mut self.mem_bus,
+            symbols: &self.symbols,
+            source_map: self.source_map.as_ref(),
+        }
     }
 
-    pub fn execute_cycle(&mut self) {
-        let cycle_start = time::get_time();
-        if self.sound_timer.get_value() > 0 {
-            self.sound.emit();
+    // stay stopped, keeping the window and overlay live, until F5 resumes or F10 runs a
+    // single instruction
+    fn pause(&mut self) -> Action {
+        self.paused = true;
+        loop {
+            self.update_overlay();
+            let mut step = false;
+            for hotkey in self.keyboard.take_hotkeys() {
+                match hotkey {
+                    Hotkey::TogglePause => self.paused = false,
+                    Hotkey::Step => step = true,
+                    hotkey => self.handle_hotkey(hotkey),
+                }
+            }
+            self.check_watched();
+            if step || !self.paused {
+                // don't race to catch up on the time spent stopped
+                self.pace_at = time::get_time();
+                self.paced_frames = 0;
+                return Action::Run;
+            }
+            thread::sleep(Duration::new(0, FRAME_NS as u32));
         }
+    }
+
+    fn update_overlay(&mut self) {
+        if let Some(overlay) = self.overlay.take() {
+            let keys = self.keyboard.pressed_keys();
+            let paused = self.paused;
+            overlay.update(&self.view(), &keys, paused);
+            self.overlay = Some(overlay);
+        }
+    }
+
+    pub fn execute_cycle(&mut self) {
+        let pc = self.cpu.pc();
+        // the instruction is read up front in case it overwrites itself
+        let instr = self.mem_bus.read_instruction(pc);
+        let before = match self.tracer {
+            Some(_) => Some(self.cpu.state()),
+            None => None,
+        };
+        let started_ns = if self.profiler.is_some() { time::precise_time_ns() } else { 0 };
+        // some instructions take longer depending on the registers they start with
+        let registers = self.cpu.registers();
         self.cpu.execute_instruction(&mut self.mem_bus,
                                      &mut self.display,
                                      &mut self.keyboard,
                                      &mut self.delay_timer,
                                      &mut self.sound_timer);
-        // TODO: should this happen here or at the beginning of the cycle?
-        self.delay_timer.cycle();
-        self.sound_timer.cycle();
-        let cycle_end = time::get_time();
-        let cycle_dur = (cycle_end - cycle_start).num_nanoseconds().unwrap();
-        debug!("cycle duration: {} micros", cycle_dur / 1000);
-        if cycle_dur < CPU_SPEED_NS {
-            let nanos = CPU_SPEED_NS - cycle_dur;
-            thread::sleep(Duration::new(0, nanos as u32));
+        if let Some(coverage) = self.mem_bus.coverage() {
+            coverage.mark_executed(pc);
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(pc, instr.into(), time::precise_time_ns() - started_ns);
+        }
+        if self.jump_log.is_some() {
+            self.log_jump(pc, instr);
+        }
+        if let Some(state) = before {
+            self.trace(state, instr);
+        }
+        let opcode = OpCode::from(instr);
+        let cycle_ns = match self.timing {
+            Timing::Fixed => self.cycle_ns,
+            Timing::Vip => {
+                let skipped = self.cpu.pc() == pc + 2 * OP_SIZE;
+                timing::vip_cycles(opcode, &registers, skipped) * timing::VIP_CYCLE_NS
+            }
+        };
+        let draw = match opcode {
+            OpCode::Draw { .. } => true,
+            _ => false,
+        };
+        if draw && (self.timing == Timing::Vip || self.cpu.quirks().vblank) {
+            // the VIP's interpreter only draws once the vertical blank interrupt has come
+            let wait = self.next_frame_ns - self.emulated_ns;
+            self.advance_time(wait);
+        }
+        self.advance_time(cycle_ns);
+        self.sound.set_tone(self.sound_timer.get_value() > 0);
+    }
+
+    // let `ns` of emulated time pass, ending any frames it runs past
+    fn advance_time(&mut self, ns: u64) {
+        self.emulated_ns += ns;
+        // samples up to now use the tone as it was before this instruction
+        self.sound.advance_to(self.emulated_ns);
+        while self.emulated_ns >= self.next_frame_ns {
+            self.next_frame_ns += FRAME_NS;
+            self.end_frame();
+            if self.timing == Timing::Vip {
+                // the 1861's interrupt at the start of each frame holds up the interpreter
+                self.emulated_ns += timing::VIP_INTERRUPT_NS;
+                self.sound.advance_to(self.emulated_ns);
+            }
         }
     }
 
     pub fn should_exit(&self) -> bool {
         self.cpu.should_exit()
     }
+
+    fn trace(&mut self, before: CpuState, instr: u16) {
+        let writes = self.mem_bus.take_writes();
+        let after = self.cpu.state();
+        let finished = {
+            let tracer = self.tracer.as_mut().expect("only called when tracing");
+            if tracer.filter.includes(after.counter, before.pc) {
+                let record = TraceRecord {
+                    counter: after.counter,
+                    pc: before.pc,
+                    symbol: self.symbols.describe(before.pc),
+                    instr: instr,
+                    mnemonic: OpCode::from(instr).to_string(),
+                    before: before,
+                    after: after.clone(),
+                    writes: writes,
+                    delay_timer: self.delay_timer.get_value(),
+                    sound_timer: self.sound_timer.get_value(),
+                    display: self.display.hash(),
+                };
+                if let Err(e) = tracer.record(&record) {
+                    error!("failed to write trace record: {}", e);
+                }
+            }
+            tracer.filter.finished(after.counter)
+        };
+        if finished {
+            self.stop_tracing();
+        }
+    }
+
+    fn coverage_name(config: &Config) -> String {
+        config.rom_path.as_ref().map(|p| p.display().to_string()).unwrap_or("rom".into())
+    }
+
+    // start from the coverage of earlier runs of the same rom, if there are any. Roms are
+    // matched by hash, so coverage follows a rom run from anywhere but not a rebuilt one.
+    fn load_coverage(rom: &[u8], config: &Config) -> Coverage {
+        let path = match config.coverage {
+            Some(ref path) if path.exists() => path,
+            _ => return Coverage::new(),
+        };
+        match Coverage::read_lcov(path) {
+            Ok((coverage, ref hash)) if *hash == rom_db::rom_hash(rom) => coverage,
+            Ok(_) => {
+                warn!("{:?} has coverage for a different rom; starting over", path);
+                Coverage::new()
+            }
+            Err(e) => {
+                warn!("could not read coverage from {:?}: {}; starting over", path, e);
+                Coverage::new()
+            }
+        }
+    }
+
+    fn load_cheats(config: &Config, rom_hash: &str) -> Cheats {
+        let path = match config.cheats_file {
+            Some(ref path) => path,
+            None => return Cheats::new(),
+        };
+        match cheats::load(path, rom_hash) {
+            Ok(cheats) => cheats,
+            Err(e) => {
+                warn!("could not read cheats from {:?}: {}", path, e);
+                Cheats::new()
+            }
+        }
+    }
+
+    fn save_coverage(&self, config: &Config) {
+        let rom = &self.rom;
+        let coverage = match self.mem_bus.coverage() {
+            Some(coverage) => coverage,
+            None => return,
+        };
+        if let Some(ref path) = config.coverage {
+            match coverage.write_lcov(path, rom, &Chip8::coverage_name(config)) {
+                Ok(()) => info!("wrote coverage to {:?}", path),
+                Err(e) => error!("failed to write coverage to {:?}: {}", path, e),
+            }
+        }
+        if let Some(ref path) = config.coverage_listing {
+            match File::create(path).and_then(|mut f| f.write_all(coverage.listing(rom, &self.symbols).as_bytes())) {
+                Ok(()) => info!("wrote coverage listing to {:?}", path),
+                Err(e) => error!("failed to write coverage listing to {:?}: {}", path, e),
+            }
+        }
+    }
+
+    pub fn stop_tracing(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            if let Err(e) = tracer.flush() {
+                error!("failed to finish trace: {}", e);
+            }
+            self.mem_bus.log_writes(false);
+        }
+    }
+
+    // record where the instruction at `pc` went if it was an indirect jump
+    fn log_jump(&mut self, pc: usize, instr: u16) {
+        if let OpCode::JpOffset { .. } = instr.into() {
+            let target = self.cpu.pc();
+            if self.jumps.insert((pc, target)) {
+                let log = self.jump_log.as_mut().expect("only called when logging jumps");
+                if let Err(e) = writeln!(log, "{:03X} {:03X}", pc, target) {
+                    error!("failed to log jump: {}", e);
+                }
+            }
+        }
+    }
+
+    /// called once per 60 Hz frame of emulated time
+    fn end_frame(&mut self) {
+        self.frames += 1;
+        self.delay_timer.tick();
+        self.sound_timer.tick();
+        self.sound.flush();
+        self.mem_bus.end_frame();
+
+        for hotkey in self.keyboard.take_hotkeys() {
+            self.handle_hotkey(hotkey);
+        }
+        if self.frames % OVERLAY_FRAMES == 0 {
+            self.update_overlay();
+        }
+        if self.frames % WATCH_FRAMES == 0 {
+            self.check_watched();
+        }
+
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.record(self.display.grid()).is_err(),
+            None => false,
+        };
+        if failed {
+            error!("failed to record frame {}; stopping recording", self.frames);
+            self.recorder = None;
+        }
+
+        if !(self.audio_sync && self.sound.wait()) {
+            self.pace_frame();
+        }
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::Screenshot => {
+                let path = format!("chip8-{:06}.png", self.frames);
+                self.screenshot(path);
+            }
+            Hotkey::ToggleRecording => {
+                if self.recorder.is_some() {
+                    self.stop_recording();
+                } else {
+                    let path = format!("chip8-{:06}.gif", self.frames);
+                    self.start_recording(path);
+                }
+            }
+            Hotkey::ToggleMute => {
+                let muted = self.sound.toggle_mute();
+                info!("sound {}", if muted { "muted" } else { "unmuted" });
+            }
+            // stop before the next instruction; `pause` handles these while stopped
+            Hotkey::TogglePause | Hotkey::Step => self.paused = true,
+            Hotkey::SaveState => {
+                self.saved_state = Some(self.save_state());
+                info!("saved state at instruction {}", self.cpu.instruction_count());
+            }
+            Hotkey::LoadState => match self.saved_state.clone() {
+                Some(state) => self.restore_state(&state, false),
+                None => warn!("no state to load; F6 saves one"),
+            },
+        }
+    }
+
+    fn save_state(&self) -> SaveState {
+        SaveState {
+            cpu: self.cpu.state(),
+            mem: self.mem_bus.contents().to_vec(),
+            delay_timer: self.delay_timer.get_value(),
+            sound_timer: self.sound_timer.get_value(),
+            grid: self.display.grid().to_vec(),
+        }
+    }
+
+    // put the machine back as it was in `state`, except for the rom itself if `keep_rom` is
+    // set, so that a reloaded program carries on with its new code
+    fn restore_state(&mut self, state: &SaveState, keep_rom: bool) {
+        let rom_end = if keep_rom { ROM_START + self.rom.len() } else { ROM_START };
+        for (addr, &value) in state.mem.iter().enumerate() {
+            if addr < ROM_START || addr >= rom_end {
+                self.mem_bus.poke_word(addr, value);
+            }
+        }
+        self.cpu.set_state(&state.cpu);
+        self.delay_timer.set_value(state.delay_timer);
+        self.sound_timer.set_value(state.sound_timer);
+        self.display.set_grid(&state.grid);
+    }
+
+    fn check_watched(&mut self) {
+        let changed = match self.watcher {
+            Some(ref mut watcher) => watcher.changed(),
+            None => false,
+        };
+        if changed {
+            self.reload_rom();
+        }
+    }
+
+    /// load the watched rom again into a fresh machine, in the same window. A rom that
+    /// doesn't load leaves the old one running.
+    fn reload_rom(&mut self) {
+        let path = self.watcher.as_ref().expect("only called when watching").path().to_owned();
+        let (rom, source_map, symbols) = match watch::load(&path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("not reloading: {}", e);
+                return;
+            }
+        };
+        let mut mem_bus = MemoryBus::new();
+        if let Err(e) = mem_bus.load_rom(&rom) {
+            error!("not reloading {}: {}", path.display(), e);
+            return;
+        }
+        let state = match self.reload {
+            Reload::Reset => None,
+            Reload::Keep => Some(self.save_state()),
+            Reload::Saved => self.saved_state.clone(),
+        };
+
+        mem_bus.log_writes(self.tracer.is_some());
+        // coverage of the old code says nothing about the new
+        if self.mem_bus.coverage().is_some() {
+            mem_bus.track_coverage(Coverage::new());
+        }
+        for (&addr, &value) in self.mem_bus.cheats() {
+            mem_bus.set_cheat(addr, value);
+        }
+        self.mem_bus = mem_bus;
+        self.cpu.reset();
+        self.cpu.set_native(native::handler(self.sys, &self.sys_routines, rom.len()));
+        self.delay_timer = Timer::new();
+        self.sound_timer = Timer::new();
+        self.display.clear();
+        self.rom = rom;
+        // labels and source lines from octo source move with the code
+        if source_map.is_some() {
+            self.source_map = source_map;
+            self.symbols = symbols;
+        }
+        if let Some(state) = state {
+            self.restore_state(&state, true);
+        }
+        info!("reloaded {}", path.display());
+    }
+
+    /// sleep until the wall-clock time the current frame should end at
+    fn pace_frame(&mut self) {
+        self.paced_frames += 1;
+        let elapsed = (time::get_time() - self.pace_at).num_nanoseconds().unwrap_or(i64::max_value());
+        let due = (self.paced_frames * FRAME_NS) as i64;
+        if elapsed < due {
+            let nanos = (due - elapsed) as u64;
+            thread::sleep(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32));
+        } else if elapsed - due > MAX_LAG_NS {
+            // start pacing again from here rather than racing to catch up
+            self.pace_at = time::get_time();
+            self.paced_frames = 0;
+        }
+    }
+
+    /// save the current display as a png
+    pub fn screenshot<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref();
+        match capture::save_png(path, self.display.grid(), self.scale, &self.palette) {
+            Ok(()) => info!("saved screenshot to {:?}", path),
+            Err(e) => error!("failed to save screenshot to {:?}: {}", path, e),
+        }
+    }
+
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref();
+        match Recorder::create(path, self.scale, &self.palette) {
+            Ok(recorder) => {
+                info!("recording to {:?}", path);
+                self.recorder = Some(recorder);
+            }
+            Err(e) => error!("failed to start recording to {:?}: {}", path, e),
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            match recorder.finish() {
+                Ok(()) => info!("recording stopped"),
+                Err(e) => error!("failed to finish recording: {}", e),
+            }
+        }
+    }
 }
@@ -0,0 +1,170 @@
+use std::cell::Cell;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use disassembler::Analysis;
+use memory_bus::{MEMORY_SIZE, ROM_START};
+use rom_db::rom_hash;
+use symbols::Symbols;
+
+/// How often each memory address was executed, read as data and written during one or
+/// more runs. Counters are cells so that reads through a shared `MemoryBus` can be counted.
+pub struct Coverage {
+    // executions of the instruction starting at each address
+    executed: Vec<Cell<u64>>,
+    read: Vec<Cell<u64>>,
+    written: Vec<Cell<u64>>,
+}
+
+fn counters() -> Vec<Cell<u64>> {
+    (0..MEMORY_SIZE).map(|_| Cell::new(0)).collect()
+}
+
+fn bump(counts: &[Cell<u64>], addr: usize, by: u64) {
+    if let Some(count) = counts.get(addr) {
+        count.set(count.get() + by);
+    }
+}
+
+fn invalid<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            executed: counters(),
+            read: counters(),
+            written: counters(),
+        }
+    }
+
+    pub fn mark_executed(&self, addr: usize) {
+        bump(&self.executed, addr, 1);
+    }
+
+    pub fn mark_read(&self, addr: usize) {
+        bump(&self.read, addr, 1);
+    }
+
+    pub fn mark_written(&self, addr: usize) {
+        bump(&self.written, addr, 1);
+    }
+
+    /// read coverage written by `write_lcov`, so a new run can add to it. Also returns the
+    /// sha1 of the rom it's for.
+    pub fn read_lcov<P: AsRef<Path>>(path: P) -> io::Result<(Coverage, String)> {
+        let coverage = Coverage::new();
+        let mut hash = String::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let (key, value) = match line.find(':') {
+                Some(idx) => (&line[..idx], &line[idx + 1..]),
+                None => continue,
+            };
+            let counts = match key {
+                "TN" => {
+                    hash = value.to_owned();
+                    continue;
+                }
+                "DA" => &coverage.executed,
+                "DR" => &coverage.read,
+                "DW" => &coverage.written,
+                _ => continue,
+            };
+            let mut fields = value.split(',').map(|f| f.trim().parse::<u64>());
+            match (fields.next(), fields.next()) {
+                (Some(Ok(addr)), Some(Ok(count))) => bump(counts, addr as usize, count),
+                _ => return Err(invalid(format!("invalid coverage line '{}'", line))),
+            }
+        }
+        Ok((coverage, hash))
+    }
+
+    /// write coverage in an lcov-like format, using addresses in place of line numbers.
+    /// `DA` lines count executions of each instruction, and the extra `DR` and `DW` lines
+    /// count data reads and writes of each byte. The test name is the rom's sha1, which
+    /// says whether later runs can add to the counts, and `name` is the rom's file.
+    pub fn write_lcov<P: AsRef<Path>>(&self, path: P, rom: &[u8], name: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let code = self.code(rom);
+        writeln!(out, "TN:{}", rom_hash(rom))?;
+        writeln!(out, "SF:{}", name)?;
+        for &addr in &code {
+            writeln!(out, "DA:{},{}", addr, self.executed[addr].get())?;
+        }
+        for (key, counts) in vec![("DR", &self.read), ("DW", &self.written)] {
+            for (addr, count) in counts.iter().enumerate().filter(|&(_, c)| c.get() > 0) {
+                writeln!(out, "{}:{},{}", key, addr, count.get())?;
+            }
+        }
+        let hit = code.iter().filter(|&&addr| self.executed[addr].get() > 0).count();
+        writeln!(out, "LH:{}", hit)?;
+        writeln!(out, "LF:{}", code.len())?;
+        writeln!(out, "end_of_record")?;
+        out.flush()
+    }
+
+    // addresses of every instruction in the rom: those found by tracing it plus any that
+    // were executed
+    fn code(&self, rom: &[u8]) -> Vec<usize> {
+        let executed: Vec<usize> = self.executed_addrs();
+        let analysis = Analysis::with_entries(rom, &executed);
+        let mut code: Vec<usize> = analysis.code().keys().cloned().chain(executed).collect();
+        code.sort();
+        code.dedup();
+        code
+    }
+
+    fn executed_addrs(&self) -> Vec<usize> {
+        self.executed.iter()
+                     .enumerate()
+                     .filter(|&(_, count)| count.get() > 0)
+                     .map(|(addr, _)| addr)
+                     .collect()
+    }
+
+    /// a disassembly of the rom with execution counts, `#####` for code that never ran,
+    /// and R/W flags for data that was read or written
+    pub fn listing(&self, rom: &[u8], symbols: &Symbols) -> String {
+        let executed = self.executed_addrs();
+        let analysis = Analysis::with_entries(rom, &executed).with_symbols(symbols);
+        let end = ROM_START + rom.len();
+        let flags = |addr: usize| {
+            let read = if self.read[addr].get() > 0 { 'R' } else { '-' };
+            let written = if self.written[addr].get() > 0 { 'W' } else { '-' };
+            format!("{}{}", read, written)
+        };
+
+        let mut out = String::new();
+        writeln!(out, "{:>10} {:<2} {:<4} {:<4}", "runs", "rw", "addr", "data").unwrap();
+        let mut addr = ROM_START;
+        while addr < end {
+            let instr = analysis.code().get(&addr).and_then(|opcode| {
+                analysis.word(addr).map(|word| (word, analysis.mnemonic(opcode)))
+            });
+            if let Some(name) = symbols.name(addr) {
+                writeln!(out, "{}:", name).unwrap();
+            }
+            match instr {
+                Some((word, mnemonic)) => {
+                    let runs = match self.executed[addr].get() {
+                        0 => "#####".into(),
+                        runs => runs.to_string(),
+                    };
+                    let text = mnemonic.unwrap_or_else(|| format!("DW 0x{:04X}", word));
+                    writeln!(out, "{:>10} {:<2} {:03X}: {:04X} {}", runs, flags(addr), addr, word, text).unwrap();
+                    addr += 2;
+                }
+                None => {
+                    let byte = rom[addr - ROM_START];
+                    writeln!(out, "{:>10} {:<2} {:03X}: {:02X}   DB 0x{:02X}", "-", flags(addr), addr, byte, byte).unwrap();
+                    addr += 1;
+                }
+            }
+        }
+        out
+    }
+}
@@ -4,12 +4,14 @@ use rand::random;
 use display::Display;
 use keyboard::Keyboard;
 use memory_bus::{MemoryBus, ROM_START};
+use native::{self, Machine, NativeHandler, Progress};
 use timer::Timer;
 use opcodes::OP_SIZE;
+use quirks::Quirks;
 use opcodes::OpCode::*;
 
 // number of general purpose (VX) registers
-const GP_REG_COUNT: usize = 16;
+pub const GP_REG_COUNT: usize = 16;
 
 // general purpose register names
 const V0: usize = 0x0;
@@ -32,6 +34,18 @@ const VF: usize = 0xF;
 // 16 is a common stack size in modern chip-8 implementations
 const STACK_SIZE: usize = 16;
 
+/// A snapshot of the cpu registers
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuState {
+    // instructions executed so far
+    pub counter: u64,
+    pub pc: usize,
+    pub i: u16,
+    pub v: [u8; GP_REG_COUNT],
+    // return addresses, innermost last
+    pub stack: Vec<usize>,
+}
+
 pub struct Cpu {
     // Registers
     // program counter
@@ -52,6 +66,10 @@ pub struct Cpu {
     // flag to let the system know whether to shut down
     // this is just for convenience and doesn't model the real CHIP-8 system
     exit: bool,
+
+    quirks: Quirks,
+    // runs 0NNN machine code calls
+    native: Box<NativeHandler>,
 }
 
 impl fmt::Debug for Cpu {
@@ -98,14 +116,74 @@ impl Cpu {
             stack: Vec::with_capacity(STACK_SIZE),
             counter: 0,
             exit: false,
+            quirks: Quirks::default(),
+            native: Box::new(native::Log::new()),
         }
     }
 
+    /// back to how `new` left things, keeping the quirks and machine code handler
+    pub fn reset(&mut self) {
+        self.reg_pc = ROM_START;
+        self.reg_vx = [0; GP_REG_COUNT];
+        self.reg_i = 0;
+        self.stack.clear();
+        self.counter = 0;
+        self.exit = false;
+    }
+
+    pub fn set_native(&mut self, native: Box<NativeHandler>) {
+        self.native = native;
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
     #[inline(always)]
     pub fn instruction_count(&self) -> u64 {
         self.counter
     }
 
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            counter: self.counter,
+            pc: self.reg_pc,
+            i: self.reg_i,
+            v: self.reg_vx,
+            stack: self.stack.clone(),
+        }
+    }
+
+    /// number of return addresses on the stack
+    #[inline(always)]
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// overwrite the registers, e.g. from a debugger
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.counter = state.counter;
+        self.reg_pc = state.pc;
+        self.reg_i = state.i;
+        self.reg_vx = state.v;
+        self.stack = state.stack.clone();
+    }
+
+    /// V0 to VF
+    #[inline(always)]
+    pub fn registers(&self) -> [u8; GP_REG_COUNT] {
+        self.reg_vx
+    }
+
+    #[inline(always)]
+    pub fn pc(&self) -> usize {
+        self.reg_pc
+    }
+
     #[inline(always)]
     pub fn should_exit(&self) -> bool {
         self.exit
@@ -139,6 +217,23 @@ impl Cpu {
         // execute instruction logic
         match opcode {
             Eof => self.exit = true,
+            Sys{nnn} => {
+                let mut machine = Machine {
+                    v: &mut self.reg_vx,
+                    i: &mut self.reg_i,
+                    mem_bus: memory_bus,
+                    display: display,
+                };
+                match self.native.call(nnn, &mut machine) {
+                    Ok(Progress::Returned) => (),
+                    // run the call again, for the routine to carry on where it left off
+                    Ok(Progress::Running) => self.reg_pc = pc,
+                    Err(e) => {
+                        error!("stopping at {:03X}, where calling machine code failed: {}", pc, e);
+                        self.exit = true;
+                    }
+                }
+            }
             DrawClr => display.clear(),
             Return => {
                 self.reg_pc = self.stack
@@ -182,33 +277,46 @@ impl Cpu {
                 self.reg_vx[x] = self.reg_vx[x].wrapping_add(nn);
             }
             SetReg{x,y} => self.reg_vx[x] = self.reg_vx[y],
-            SetRegBor{x,y} => self.reg_vx[x] |= self.reg_vx[y],
-            SetRegBand{x,y} => self.reg_vx[x] &= self.reg_vx[y],
-            SetRegBxor{x,y} => self.reg_vx[x] ^= self.reg_vx[y],
+            SetRegBor{x,y} => {
+                self.reg_vx[x] |= self.reg_vx[y];
+                self.reset_vf_after_logic();
+            }
+            SetRegBand{x,y} => {
+                self.reg_vx[x] &= self.reg_vx[y];
+                self.reset_vf_after_logic();
+            }
+            SetRegBxor{x,y} => {
+                self.reg_vx[x] ^= self.reg_vx[y];
+                self.reset_vf_after_logic();
+            }
             SetRegAdd{x,y} => {
+                // VF is written after the result, as on the VIP, so with X = F it ends up
+                // holding the flag
                 let result = self.reg_vx[x] as u16 + self.reg_vx[y] as u16;
-                self.reg_vx[VF] = if result > 0xFF { 1 } else { 0 };
                 self.reg_vx[x] = result as u8;
+                self.reg_vx[VF] = if result > 0xFF { 1 } else { 0 };
             }
             SetRegSub{x,y} => {
                 let vx = self.reg_vx[x];
                 let vy = self.reg_vx[y];
-                self.reg_vx[VF] = if vx < vy { 0 } else { 1 };
                 self.reg_vx[x] = vx.wrapping_sub(vy);
+                self.reg_vx[VF] = if vx < vy { 0 } else { 1 };
             }
-            SetShr1{x} => {
-                self.reg_vx[VF] = self.reg_vx[x] & 1;
-                self.reg_vx[x] >>= 1;
+            SetShr1{x, y} => {
+                let value = if self.quirks.shift { self.reg_vx[x] } else { self.reg_vx[y] };
+                self.reg_vx[x] = value >> 1;
+                self.reg_vx[VF] = value & 1;
             }
             SetRegRevSub{x,y} => {
                 let vx = self.reg_vx[x];
                 let vy = self.reg_vx[y];
-                self.reg_vx[VF] = if vy < vx { 0 } else { 1 };
                 self.reg_vx[x] = vy.wrapping_sub(vx);
+                self.reg_vx[VF] = if vy < vx { 0 } else { 1 };
             }
-            SetShl1{x} => {
-                self.reg_vx[VF] = (self.reg_vx[x] >> 7) & 0b1;
-                self.reg_vx[x] <<= 1;
+            SetShl1{x, y} => {
+                let value = if self.quirks.shift { self.reg_vx[x] } else { self.reg_vx[y] };
+                self.reg_vx[x] = value << 1;
+                self.reg_vx[VF] = (value >> 7) & 0b1;
             }
             JpRegNe{x,y} => {
                 if self.reg_vx[x] != self.reg_vx[y] {
@@ -216,7 +324,11 @@ impl Cpu {
                 }
             }
             SetI{nnn} => self.reg_i = nnn,
-            JpOffset{nnn} => self.reg_pc = self.reg_vx[V0] as usize + nnn,
+            JpOffset{nnn} => {
+                // with the jump quirk, BXNN adds VX rather than V0
+                let offset = if self.quirks.jump { self.reg_vx[(nnn >> 8) & 0xF] } else { self.reg_vx[V0] };
+                self.reg_pc = offset as usize + nnn;
+            }
             SetRand{x,nn} => self.reg_vx[x] = random::<u8>() & nn,
             Draw{x,y,n} => {
                 let vx = self.reg_vx[x] as u16;
@@ -224,7 +336,7 @@ impl Cpu {
                 let i = self.reg_i as usize;
                 let n = n as usize; // convert to usize here so we don't blow up size of enum
 
-                let flipped_unset = display.draw(vx, vy, n, memory_bus, i);
+                let flipped_unset = display.draw(vx, vy, n, memory_bus, i, self.quirks.wrap);
                 self.reg_vx[VF] = if flipped_unset { 1 } else { 0 };
             }
             SkpKeyEq{x} => {
@@ -273,6 +385,7 @@ impl Cpu {
                 let dst_addr = self.reg_i as usize;
                 let src = &self.reg_vx[0..len];
                 memory_bus.write_words(dst_addr, src);
+                self.advance_i_after_memory(len);
             }
             LoadReg{x} => {
                 let len = x + 1;
@@ -280,9 +393,25 @@ impl Cpu {
                 let src = memory_bus.read_words(src_addr, len);
                 let dst = &mut self.reg_vx[0..len];
                 dst.copy_from_slice(src);
+                self.advance_i_after_memory(len);
             }
             Unknown(instr) => panic!("Invalid instruction {:X}", instr),
         };
 
     }
+
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic {
+            self.reg_vx[VF] = 0;
+        }
+    }
+
+    // FX55 and FX65 move I past the `len` registers they copied, unless quirks say otherwise
+    fn advance_i_after_memory(&mut self, len: usize) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        let step = if self.quirks.memory_increment_by_x { len - 1 } else { len };
+        self.reg_i = (self.reg_i + step as u16) & 0xFFF;
+    }
 }
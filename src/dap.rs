@@ -0,0 +1,580 @@
+//! A Debug Adapter Protocol server, so editors like VS Code can launch a rom and debug it.
+//! It talks to a single client over stdin and stdout, or over a localhost TCP connection.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use base64;
+use serde_json::{self, Value};
+
+use debugger::{Action, View};
+use memory_bus::MEMORY_SIZE;
+use opcodes::OP_SIZE;
+use source_map::SourceMap;
+use symbols::Symbols;
+
+// the emulator only has the one thread
+const THREAD_ID: u64 = 1;
+
+// variable references of the scopes shown for every frame
+const REGISTERS: u64 = 1;
+const TIMERS: u64 = 2;
+const STACK: u64 = 3;
+
+// how long to wait for the client to disconnect once the rom has exited
+const DISCONNECT_TIMEOUT_MS: u64 = 1000;
+
+/// Why the adapter wants the emulator to stop
+pub enum Stop {
+    // stopped for a reason the client is told about, like "breakpoint"
+    Paused(&'static str),
+    // the client went away or ended the session
+    Disconnected,
+}
+
+/// What the client asked to launch
+pub struct LaunchArgs {
+    pub program: PathBuf,
+    pub headless: bool,
+    pub symbols: Option<PathBuf>,
+}
+
+enum StepKind {
+    In,
+    Over,
+    Out,
+}
+
+// a step the client asked for that hasn't finished yet
+struct Step {
+    kind: StepKind,
+    // call stack depth when the step started
+    depth: usize,
+    // the source line the step started on, when stepping by line rather than instruction
+    line: Option<usize>,
+}
+
+fn invalid<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// read one message framed by a `Content-Length` header, or None at the end of the input
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        match header.find(':') {
+            Some(idx) if header[..idx].eq_ignore_ascii_case("content-length") => {
+                length = Some(header[idx + 1..].trim().parse().map_err(|_| invalid("invalid Content-Length"))?);
+            }
+            // headers end at a blank line
+            None if header.is_empty() && length.is_some() => break,
+            _ => (),
+        }
+    }
+    let mut body = vec![0; length.expect("checked above")];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| invalid(format!("invalid message: {}", e)))
+}
+
+// read messages on another thread, so they can be checked for without blocking while
+// the rom runs
+fn spawn_reader<R: Read + Send + 'static>(input: R) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        loop {
+            match read_message(&mut input) {
+                Ok(Some(message)) => {
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("could not read from debug client: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+// an address given as a hex string, like "0x2A4"
+fn parse_reference(text: &str) -> Option<usize> {
+    let hex = if text.starts_with("0x") || text.starts_with("0X") { &text[2..] } else { text };
+    usize::from_str_radix(hex, 16).ok()
+}
+
+fn reference(addr: usize) -> String {
+    format!("0x{:03X}", addr)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn variable(name: String, value: String) -> Value {
+    json!({"name": name, "value": value, "variablesReference": 0})
+}
+
+/// A connected client and the breakpoints and steps it has asked for
+pub struct DapServer {
+    requests: Receiver<Value>,
+    out: Box<Write>,
+    // sequence number of the last message sent
+    seq: u64,
+    // answered once the rom is loaded
+    launch: Option<Value>,
+    symbols: Symbols,
+    source_map: Option<SourceMap>,
+    // breakpoint addresses, keyed by the source path, or "function" or "instruction", they
+    // were set through
+    breakpoints: HashMap<String, BTreeSet<usize>>,
+    step: Option<Step>,
+    // stop before the next instruction, for this reason
+    stop: Option<&'static str>,
+    disconnected: bool,
+}
+
+impl DapServer {
+    /// serve a client on `port` of the loopback interface, or on stdin and stdout
+    pub fn connect(port: Option<u16>) -> io::Result<DapServer> {
+        let (requests, out): (Receiver<Value>, Box<Write>) = match port {
+            Some(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", port))?;
+                info!("waiting for a debug client to connect to 127.0.0.1:{}", port);
+                let (stream, peer) = listener.accept()?;
+                info!("debug client connected from {}", peer);
+                (spawn_reader(stream.try_clone()?), Box::new(stream))
+            }
+            None => (spawn_reader(io::stdin()), Box::new(io::stdout())),
+        };
+        Ok(DapServer {
+            requests: requests,
+            out: out,
+            seq: 0,
+            launch: None,
+            symbols: Symbols::new(),
+            source_map: None,
+            breakpoints: HashMap::new(),
+            step: None,
+            stop: None,
+            disconnected: false,
+        })
+    }
+
+    /// answer requests until the client asks to launch a rom, and return what it asked for.
+    /// Returns None if the client leaves first.
+    pub fn wait_for_launch(&mut self) -> Option<LaunchArgs> {
+        loop {
+            let request = match self.requests.recv() {
+                Ok(request) => request,
+                Err(_) => {
+                    self.disconnected = true;
+                    return None;
+                }
+            };
+            match request["command"].as_str().unwrap_or("") {
+                "initialize" => {
+                    let capabilities = json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsInstructionBreakpoints": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsSteppingGranularity": true,
+                    });
+                    self.respond(&request, capabilities);
+                }
+                "launch" => {
+                    let args = request["arguments"].clone();
+                    match args["program"].as_str() {
+                        Some(program) => {
+                            if args["stopOnEntry"].as_bool().unwrap_or(false) {
+                                self.stop = Some("entry");
+                            }
+                            self.launch = Some(request.clone());
+                            return Some(LaunchArgs {
+                                program: PathBuf::from(program),
+                                headless: args["headless"].as_bool().unwrap_or(false),
+                                symbols: args["symbols"].as_str().map(PathBuf::from),
+                            });
+                        }
+                        None => self.fail(&request, "launch needs a program to run"),
+                    }
+                }
+                "disconnect" => {
+                    self.respond(&request, json!({}));
+                    self.disconnected = true;
+                    return None;
+                }
+                _ => self.fail(&request, "launch a rom first"),
+            }
+        }
+    }
+
+    /// tell the client the rom it asked for couldn't be launched
+    pub fn launch_failed(&mut self, message: &str) {
+        if let Some(request) = self.launch.take() {
+            self.fail(&request, message);
+        }
+    }
+
+    /// finish launching the rom and take breakpoints from the client until it's done
+    /// configuring
+    pub fn start(&mut self, symbols: &Symbols, source_map: Option<&SourceMap>) -> Action {
+        self.symbols = symbols.clone();
+        self.source_map = source_map.cloned();
+        if let Some(request) = self.launch.take() {
+            self.respond(&request, json!({}));
+        }
+        self.event("initialized", json!({}));
+        self.serve_requests(None)
+    }
+
+    /// handle requests that arrived while the rom was running, and decide whether to stop
+    /// before the instruction at `pc` with the call stack `depth` deep
+    pub fn poll(&mut self, pc: usize, depth: usize) -> Option<Stop> {
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => {
+                    if let Some(Action::Quit) = self.handle(&request, None) {
+                        return Some(Stop::Disconnected);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    return Some(Stop::Disconnected);
+                }
+            }
+        }
+        if let Some(reason) = self.stop.take() {
+            return Some(Stop::Paused(reason));
+        }
+        if self.breakpoints.values().any(|addrs| addrs.contains(&pc)) {
+            return Some(Stop::Paused("breakpoint"));
+        }
+        if self.step_done(pc, depth) {
+            return Some(Stop::Paused("step"));
+        }
+        None
+    }
+
+    fn step_done(&self, pc: usize, depth: usize) -> bool {
+        let step = match self.step {
+            Some(ref step) => step,
+            None => return false,
+        };
+        match (&step.kind, step.line) {
+            (&StepKind::Out, _) => depth < step.depth,
+            (&StepKind::Over, _) if depth > step.depth => false,
+            (_, None) => true,
+            // code with no source, like the jump to main, is stepped through
+            (_, Some(line)) => match self.source_map.as_ref().and_then(|map| map.line_for(pc)) {
+                Some((at, _)) => at != line,
+                None => false,
+            },
+        }
+    }
+
+    /// tell the client the emulator stopped and answer its requests until it resumes
+    pub fn serve(&mut self, reason: &str, view: &View) -> Action {
+        self.step = None;
+        self.event("stopped", json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}));
+        self.serve_requests(Some(view))
+    }
+
+    fn serve_requests(&mut self, view: Option<&View>) -> Action {
+        loop {
+            match self.requests.recv() {
+                Ok(request) => {
+                    if let Some(action) = self.handle(&request, view) {
+                        return action;
+                    }
+                }
+                Err(_) => {
+                    self.disconnected = true;
+                    return Action::Quit;
+                }
+            }
+        }
+    }
+
+    /// tell the client the rom has finished, and give it a moment to disconnect
+    pub fn exited(&mut self) {
+        if self.disconnected {
+            return;
+        }
+        self.event("exited", json!({"exitCode": 0}));
+        self.event("terminated", json!({}));
+        let timeout = Duration::from_millis(DISCONNECT_TIMEOUT_MS);
+        while let Ok(request) = self.requests.recv_timeout(timeout) {
+            if request["command"] == "disconnect" {
+                self.respond(&request, json!({}));
+                break;
+            }
+            self.fail(&request, "the rom has exited");
+        }
+        self.disconnected = true;
+    }
+
+    // answer a request, returning what the emulator should do if it changes anything.
+    // `view` is None while the rom is running.
+    fn handle(&mut self, request: &Value, view: Option<&View>) -> Option<Action> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        match (command, view) {
+            ("configurationDone", _) => {
+                self.respond(request, json!({}));
+                return Some(Action::Run);
+            }
+            ("setBreakpoints", _) => {
+                let body = self.set_source_breakpoints(args);
+                self.respond(request, body);
+            }
+            ("setFunctionBreakpoints", _) => {
+                let addrs = args["breakpoints"].as_array().map(|bps| {
+                    bps.iter().map(|bp| bp["name"].as_str().and_then(|name| self.symbols.parse_addr(name))).collect()
+                }).unwrap_or(vec![]);
+                let body = self.set_breakpoints("function", addrs);
+                self.respond(request, body);
+            }
+            ("setInstructionBreakpoints", _) => {
+                let addrs = args["breakpoints"].as_array().map(|bps| {
+                    bps.iter().map(|bp| {
+                        let offset = bp["offset"].as_i64().unwrap_or(0);
+                        bp["instructionReference"].as_str()
+                                                  .and_then(parse_reference)
+                                                  .map(|addr| (addr as i64 + offset) as usize)
+                    }).collect()
+                }).unwrap_or(vec![]);
+                let body = self.set_breakpoints("instruction", addrs);
+                self.respond(request, body);
+            }
+            ("threads", _) => self.respond(request, json!({"threads": [{"id": THREAD_ID, "name": "CHIP-8"}]})),
+            ("pause", None) => {
+                self.stop = Some("pause");
+                self.respond(request, json!({}));
+            }
+            ("pause", Some(_)) => self.respond(request, json!({})),
+            ("continue", _) => {
+                self.respond(request, json!({"allThreadsContinued": true}));
+                if view.is_some() {
+                    return Some(Action::Run);
+                }
+            }
+            ("next", Some(view)) | ("stepIn", Some(view)) | ("stepOut", Some(view)) => {
+                let kind = match command {
+                    "next" => StepKind::Over,
+                    "stepIn" => StepKind::In,
+                    _ => StepKind::Out,
+                };
+                let line = match args["granularity"].as_str() {
+                    Some("instruction") => None,
+                    _ => self.source_map.as_ref().and_then(|map| map.line_for(view.cpu.pc)).map(|(line, _)| line),
+                };
+                self.step = Some(Step {
+                    kind: kind,
+                    depth: view.cpu.stack.len(),
+                    line: line,
+                });
+                self.respond(request, json!({}));
+                return Some(Action::Run);
+            }
+            ("stackTrace", Some(view)) => {
+                let frames: Vec<Value> = iter::once(view.cpu.pc)
+                    .chain(view.cpu.stack.iter().rev().map(|&ret| ret - OP_SIZE))
+                    .enumerate()
+                    .map(|(id, addr)| self.frame(id, addr))
+                    .collect();
+                let total = frames.len();
+                self.respond(request, json!({"stackFrames": frames, "totalFrames": total}));
+            }
+            ("scopes", Some(_)) => {
+                let scopes = json!({"scopes": [
+                    {"name": "Registers", "variablesReference": REGISTERS, "expensive": false},
+                    {"name": "Timers", "variablesReference": TIMERS, "expensive": false},
+                    {"name": "Stack", "variablesReference": STACK, "expensive": false},
+                ]});
+                self.respond(request, scopes);
+            }
+            ("variables", Some(view)) => {
+                let variables = self.variables(args["variablesReference"].as_u64().unwrap_or(0), view);
+                self.respond(request, json!({"variables": variables}));
+            }
+            ("readMemory", Some(view)) => match read_memory(args, view) {
+                Some(body) => self.respond(request, body),
+                None => self.fail(request, "invalid memory reference"),
+            },
+            ("disconnect", _) | ("terminate", _) => {
+                self.respond(request, json!({}));
+                self.disconnected = true;
+                return Some(Action::Quit);
+            }
+            (_, None) if ["next", "stepIn", "stepOut", "stackTrace", "scopes", "variables", "readMemory"].contains(&command) => {
+                self.fail(request, "the rom is running");
+            }
+            _ => self.fail(request, &format!("unsupported request '{}'", command)),
+        }
+        None
+    }
+
+    fn set_source_breakpoints(&mut self, args: &Value) -> Value {
+        let path = PathBuf::from(args["source"]["path"].as_str().unwrap_or(""));
+        let lines: Vec<u64> = args["breakpoints"].as_array()
+                                                 .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64()).collect())
+                                                 .unwrap_or(vec![]);
+        let map = match self.source_map {
+            Some(ref map) if same_file(map.file(), &path) => Some(map),
+            _ => None,
+        };
+        let mut addrs = BTreeSet::new();
+        let mut results = vec![];
+        for line in lines {
+            let addr = map.and_then(|map| map.addr_for_line(line as usize));
+            results.push(match addr {
+                Some(addr) => {
+                    addrs.insert(addr);
+                    // the breakpoint moves to the line that actually has code
+                    let line = map.and_then(|map| map.line_for(addr)).map(|(line, _)| line as u64).unwrap_or(line);
+                    json!({"verified": true, "line": line, "instructionReference": reference(addr)})
+                }
+                None => json!({"verified": false, "line": line, "message": "no code at or after this line"}),
+            });
+        }
+        self.breakpoints.insert(path.display().to_string(), addrs);
+        json!({"breakpoints": results})
+    }
+
+    // replace the breakpoints of one kind; unresolved ones are reported unverified
+    fn set_breakpoints(&mut self, kind: &str, addrs: Vec<Option<usize>>) -> Value {
+        let results: Vec<Value> = addrs.iter().map(|addr| match *addr {
+            Some(addr) => json!({"verified": true, "instructionReference": reference(addr)}),
+            None => json!({"verified": false, "message": "unknown location"}),
+        }).collect();
+        self.breakpoints.insert(kind.to_owned(), addrs.into_iter().filter_map(|addr| addr).collect());
+        json!({"breakpoints": results})
+    }
+
+    fn frame(&self, id: usize, addr: usize) -> Value {
+        let name = self.symbols.describe(addr).unwrap_or_else(|| format!("{:03X}", addr));
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": reference(addr),
+        });
+        if let Some(ref map) = self.source_map {
+            if let Some((line, _)) = map.line_for(addr) {
+                let path = fs::canonicalize(map.file()).unwrap_or(map.file().to_owned());
+                let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+                frame["source"] = json!({"name": name, "path": path.display().to_string()});
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+            }
+        }
+        frame
+    }
+
+    fn variables(&self, scope: u64, view: &View) -> Vec<Value> {
+        let address = |name: &str, addr: usize| {
+            json!({
+                "name": name,
+                "value": view.symbols.format_addr(addr),
+                "variablesReference": 0,
+                "memoryReference": reference(addr),
+            })
+        };
+        match scope {
+            REGISTERS => {
+                let mut variables: Vec<Value> = view.cpu.v.iter()
+                                                        .enumerate()
+                                                        .map(|(x, v)| variable(format!("V{:X}", x), format!("0x{:02X}", v)))
+                                                        .collect();
+                variables.push(address("I", view.cpu.i as usize));
+                variables.push(address("PC", view.cpu.pc));
+                variables
+            }
+            TIMERS => vec![
+                variable("DT".into(), view.delay_timer.to_string()),
+                variable("ST".into(), view.sound_timer.to_string()),
+            ],
+            // innermost return address first, like the stack trace
+            STACK => view.cpu.stack.iter()
+                                   .rev()
+                                   .enumerate()
+                                   .map(|(idx, &ret)| address(&format!("#{}", idx), ret))
+                                   .collect(),
+            _ => vec![],
+        }
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let sent = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body).and_then(|()| self.out.flush());
+        if let Err(e) = sent {
+            error!("could not write to debug client: {}", e);
+        }
+    }
+}
+
+// the bytes asked for by a readMemory request, base64 encoded
+fn read_memory(args: &Value, view: &View) -> Option<Value> {
+    let base = args["memoryReference"].as_str().and_then(parse_reference)?;
+    let start = base as i64 + args["offset"].as_i64().unwrap_or(0);
+    let count = args["count"].as_u64().unwrap_or(0) as usize;
+    if start < 0 || start as usize >= MEMORY_SIZE {
+        return Some(json!({"address": reference(start.max(0) as usize), "unreadableBytes": count}));
+    }
+    let start = start as usize;
+    let readable = count.min(MEMORY_SIZE - start);
+    let data = view.mem_bus.read_words(start, readable);
+    Some(json!({
+        "address": reference(start),
+        "data": base64::encode(data),
+        "unreadableBytes": count - readable,
+    }))
+}
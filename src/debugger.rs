@@ -0,0 +1,341 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use termion::{self, color};
+
+use cheats::{self, Compare, Search};
+use cpu::CpuState;
+use memory_bus::{MemoryBus, MEMORY_SIZE};
+use opcodes::{OpCode, OP_SIZE};
+use source_map::SourceMap;
+use symbols::Symbols;
+
+const HELP: &'static str = "\
+commands:
+    s, step [N]          run N instructions (default 1); an empty line repeats the last command
+    c, continue          run until a breakpoint
+    b, break LOCATION    stop before the instruction at LOCATION: a symbol, a hex address or FILE:LINE;
+                         hex addresses starting with a letter need 0x or $, as in 0xA20
+    d, delete [LOCATION] remove a breakpoint, or all of them
+    breaks               list breakpoints
+    bt, backtrace        show the call stack
+    r, regs              show registers
+    x, mem [ADDR] [LEN]  show LEN bytes (default 64) from ADDR (default I) as hex and ascii;
+                         bytes changed since the last frame are highlighted
+    sprite [ADDR] [ROWS] draw ROWS bytes (default 15) from ADDR (default I) as sprite rows
+    poke ADDR BYTE...    write hex bytes to memory starting at ADDR
+    dump ADDR LEN FILE   write LEN bytes from ADDR to FILE
+    search [start]       start a RAM search with every address as a candidate
+    search eq|gt|lt|changed [VALUE]
+                         keep candidates equal to, greater or less than VALUE (hex), or than
+                         their value at the last search if there's no VALUE
+    cheat [ADDR=VALUE]   freeze the byte at ADDR at VALUE every frame, or list cheats
+    uncheat ADDR         stop freezing the byte at ADDR
+    q, quit              stop the emulator";
+
+const DEFAULT_MEM_LEN: usize = 64;
+const DEFAULT_SPRITE_ROWS: usize = 15;
+const MEM_ROW_BYTES: usize = 16;
+// search candidates listed after each search
+const MAX_CANDIDATES_SHOWN: usize = 20;
+
+/// What the emulator should do when the debugger hands control back
+pub enum Action {
+    Run,
+    Quit,
+}
+
+/// Interactive breakpoints and stepping, driven from the terminal
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    // instructions left to run before stopping, if stepping
+    steps: Option<u64>,
+    last_command: String,
+    search: Option<Search>,
+}
+
+/// everything the debugger can show about the stopped emulator
+pub struct View<'a> {
+    pub cpu: CpuState,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub status: String,
+    pub mem_bus: &'a mut MemoryBus,
+    pub symbols: &'a Symbols,
+    pub source_map: Option<&'a SourceMap>,
+}
+
+impl<'a> View<'a> {
+    fn describe(&self, addr: usize) -> String {
+        let mut text = self.symbols.format_addr(addr);
+        if let Some((line, source)) = self.source_map.and_then(|map| map.line_for(addr)) {
+            let file = self.source_map.expect("checked above").file().display();
+            text.push_str(&format!("  {}:{}: {}", file, line, source.trim()));
+        }
+        text
+    }
+
+    fn location(&self, text: &str) -> Option<usize> {
+        resolve(text, self.symbols, self.source_map)
+    }
+}
+
+// memory in rows of hex and ascii. Bytes changed since the last frame are colored when
+// `highlight` is set, and marked with `*` otherwise.
+fn hex_dump(mem_bus: &MemoryBus, start: usize, len: usize, highlight: bool) -> String {
+    let end = start.saturating_add(len).min(MEMORY_SIZE);
+    let mut out = String::new();
+    let mut row = start - start % MEM_ROW_BYTES;
+    while row < end {
+        let (mut hex, mut ascii) = (String::new(), String::new());
+        for addr in row..row + MEM_ROW_BYTES {
+            if addr < start || addr >= end {
+                hex.push_str("   ");
+                ascii.push(' ');
+                continue;
+            }
+            let byte = mem_bus.peek_word(addr);
+            let c = if byte >= 0x20 && byte < 0x7F { byte as char } else { '.' };
+            match (mem_bus.changed(addr), highlight) {
+                (true, true) => {
+                    let (on, off) = (color::Fg(color::Yellow), color::Fg(color::Reset));
+                    hex.push_str(&format!(" {}{:02X}{}", on, byte, off));
+                    ascii.push_str(&format!("{}{}{}", on, c, off));
+                }
+                (true, false) => {
+                    hex.push_str(&format!("*{:02X}", byte));
+                    ascii.push(c);
+                }
+                _ => {
+                    hex.push_str(&format!(" {:02X}", byte));
+                    ascii.push(c);
+                }
+            }
+        }
+        out.push_str(&format!("{:03X}:{}  |{}|\n", row, hex, ascii));
+        row += MEM_ROW_BYTES;
+    }
+    out
+}
+
+// each byte as a row of 8 pixels, the way `draw` would show it
+fn sprite(mem_bus: &MemoryBus, start: usize, rows: usize) -> String {
+    let mut out = String::new();
+    for addr in start..start.saturating_add(rows).min(MEMORY_SIZE) {
+        let byte = mem_bus.peek_word(addr);
+        let pixels: String = (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect();
+        out.push_str(&format!("{:03X}: {:02X} {}\n", addr, byte, pixels));
+    }
+    out
+}
+
+fn print_candidates(search: &Search, mem_bus: &MemoryBus) {
+    let candidates = search.candidates();
+    println!("{} candidates", candidates.len());
+    for &addr in candidates.iter().take(MAX_CANDIDATES_SHOWN) {
+        println!("    {:03X}: {:02X}", addr, mem_bus.peek_word(addr));
+    }
+    if candidates.len() > MAX_CANDIDATES_SHOWN {
+        println!("    ...");
+    }
+}
+
+/// the address of a breakpoint location: a symbol, a hex address, or `FILE:LINE` when the
+/// rom was compiled from source
+pub fn resolve(location: &str, symbols: &Symbols, source_map: Option<&SourceMap>) -> Option<usize> {
+    if let (Some(map), Some(idx)) = (source_map, location.rfind(':')) {
+        if let Ok(line) = location[idx + 1..].parse() {
+            // the source map only covers the file the rom was compiled from
+            let file = Path::new(&location[..idx]);
+            if !map.file().ends_with(file) && !file.ends_with(map.file()) {
+                return None;
+            }
+            return map.addr_for_line(line);
+        }
+    }
+    symbols.parse_addr(location)
+}
+
+impl Debugger {
+    /// a debugger that stops before the first instruction if `paused`
+    pub fn new(paused: bool) -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            steps: if paused { Some(1) } else { None },
+            last_command: "step".into(),
+            search: None,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// true if execution should stop before the instruction at `pc`
+    pub fn should_stop(&mut self, pc: usize) -> bool {
+        if let Some(steps) = self.steps {
+            if steps <= 1 {
+                self.steps = None;
+                return true;
+            }
+            self.steps = Some(steps - 1);
+        }
+        self.breakpoints.contains(&pc)
+    }
+
+    /// show where we stopped and read commands until told to run; the instruction at the pc
+    /// runs as soon as this returns
+    pub fn prompt(&mut self, view: &mut View) -> Action {
+        let pc = view.cpu.pc;
+        let tty = termion::is_tty(&io::stdout());
+        if self.breakpoints.contains(&pc) {
+            println!("breakpoint at {}", view.symbols.format_addr(pc));
+        }
+        println!("{}", view.status);
+        let instr = view.mem_bus.read_instruction(pc);
+        println!("=> {}: {:04X} {}", view.describe(pc), instr, OpCode::from(instr));
+
+        loop {
+            print!("(chip8) ");
+            let _ = io::stdout().flush();
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                // end of input
+                Ok(0) | Err(_) => return Action::Quit,
+                Ok(_) => (),
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                command => command.to_owned(),
+            };
+            self.last_command = line.clone();
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            match (words[0], words.get(1)) {
+                ("s", count) | ("step", count) => {
+                    match count.map(|c| c.parse::<u64>()).unwrap_or(Ok(1)) {
+                        Ok(count) if count > 0 => {
+                            self.steps = Some(count);
+                            return Action::Run;
+                        }
+                        _ => println!("step takes a positive number of instructions"),
+                    }
+                }
+                ("c", _) | ("continue", _) => return Action::Run,
+                ("b", Some(location)) | ("break", Some(location)) => match view.location(location) {
+                    Some(addr) => {
+                        self.breakpoints.insert(addr);
+                        println!("breakpoint at {}", view.describe(addr));
+                    }
+                    None => println!("unknown location {}", location),
+                },
+                ("d", None) | ("delete", None) => {
+                    self.breakpoints.clear();
+                    println!("deleted all breakpoints");
+                }
+                ("d", Some(location)) | ("delete", Some(location)) => {
+                    match view.location(location).map(|addr| self.breakpoints.remove(&addr)) {
+                        Some(true) => println!("deleted breakpoint at {}", location),
+                        _ => println!("no breakpoint at {}", location),
+                    }
+                }
+                ("breaks", _) => {
+                    for &addr in &self.breakpoints {
+                        println!("    {}", view.describe(addr));
+                    }
+                }
+                ("bt", _) | ("backtrace", _) => {
+                    println!("#0  {}", view.describe(pc));
+                    // each return address is just past the call that pushed it
+                    for (frame, &ret) in view.cpu.stack.iter().rev().enumerate() {
+                        println!("#{}  {}", frame + 1, view.describe(ret - OP_SIZE));
+                    }
+                }
+                ("r", _) | ("regs", _) => println!("{}", view.status),
+                ("x", _) | ("mem", _) => {
+                    let addr = words.get(1).map(|addr| view.location(addr)).unwrap_or(Some(view.cpu.i as usize));
+                    let len = words.get(2).map(|len| len.parse().ok()).unwrap_or(Some(DEFAULT_MEM_LEN));
+                    match (addr, len) {
+                        (Some(addr), Some(len)) if addr < MEMORY_SIZE => print!("{}", hex_dump(view.mem_bus, addr, len, tty)),
+                        _ => println!("usage: x [ADDR] [LEN]"),
+                    }
+                }
+                ("sprite", _) => {
+                    let addr = words.get(1).map(|addr| view.location(addr)).unwrap_or(Some(view.cpu.i as usize));
+                    let rows = words.get(2).map(|rows| rows.parse().ok()).unwrap_or(Some(DEFAULT_SPRITE_ROWS));
+                    match (addr, rows) {
+                        (Some(addr), Some(rows)) => print!("{}", sprite(view.mem_bus, addr, rows)),
+                        _ => println!("usage: sprite [ADDR] [ROWS]"),
+                    }
+                }
+                ("poke", Some(location)) => {
+                    let bytes: Option<Vec<u8>> = words[2..].iter()
+                                                           .map(|b| u8::from_str_radix(b.trim_left_matches("0x"), 16).ok())
+                                                           .collect();
+                    match (view.location(location), bytes) {
+                        (Some(addr), Some(ref bytes)) if !bytes.is_empty() && addr.saturating_add(bytes.len()) <= MEMORY_SIZE => {
+                            for (offset, &byte) in bytes.iter().enumerate() {
+                                view.mem_bus.poke_word(addr + offset, byte);
+                            }
+                            print!("{}", hex_dump(view.mem_bus, addr, bytes.len(), tty));
+                        }
+                        _ => println!("usage: poke ADDR BYTE..."),
+                    }
+                }
+                ("dump", Some(location)) => {
+                    let len = words.get(2).and_then(|len| len.parse::<usize>().ok());
+                    match (view.location(location), len, words.get(3)) {
+                        (Some(addr), Some(len), Some(path)) if addr.saturating_add(len) <= MEMORY_SIZE => {
+                            let bytes: Vec<u8> = (addr..addr + len).map(|a| view.mem_bus.peek_word(a)).collect();
+                            match File::create(path).and_then(|mut f| f.write_all(&bytes)) {
+                                Ok(()) => println!("wrote {} bytes to {}", len, path),
+                                Err(e) => println!("could not write {}: {}", path, e),
+                            }
+                        }
+                        _ => println!("usage: dump ADDR LEN FILE"),
+                    }
+                }
+                ("search", None) | ("search", Some(&"start")) => {
+                    self.search = Some(Search::new(view.mem_bus));
+                    println!("searching all {} addresses", MEMORY_SIZE);
+                }
+                ("search", Some(compare)) => {
+                    let value = words.get(2)
+                                     .map(|v| u8::from_str_radix(v.trim_left_matches("0x"), 16).map(Some))
+                                     .unwrap_or(Ok(None));
+                    match (self.search.as_mut(), Compare::parse(compare), value) {
+                        (None, _, _) => println!("no search; start one with 'search'"),
+                        (Some(search), Some(compare), Ok(value)) => {
+                            search.narrow(view.mem_bus, compare, value);
+                            print_candidates(search, view.mem_bus);
+                        }
+                        _ => println!("usage: search eq|gt|lt|changed [VALUE]"),
+                    }
+                }
+                ("cheat", None) => {
+                    for (&addr, value) in view.mem_bus.cheats() {
+                        let name = view.symbols.describe(addr).unwrap_or_default();
+                        println!("    {:03X}={:02X}  {}", addr, value, name);
+                    }
+                }
+                ("cheat", Some(code)) => match cheats::parse_code(code) {
+                    Ok((addr, value)) => {
+                        view.mem_bus.set_cheat(addr, value);
+                        println!("froze {} at {:02X}", view.symbols.format_addr(addr), value);
+                    }
+                    Err(e) => println!("{}", e),
+                },
+                ("uncheat", Some(location)) => {
+                    match view.location(location).map(|addr| view.mem_bus.remove_cheat(addr)) {
+                        Some(true) => println!("removed cheat at {}", location),
+                        _ => println!("no cheat at {}", location),
+                    }
+                }
+                ("q", _) | ("quit", _) => return Action::Quit,
+                _ => println!("{}", HELP),
+            }
+        }
+    }
+}
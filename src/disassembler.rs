@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use assembler::is_label_name;
+use memory_bus::ROM_START;
+use opcodes::OpCode;
+use opcodes::OpCode::*;
+use symbols::Symbols;
+
+// data bytes per `DB` line when the bytes aren't a sprite
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// Disassemble a rom by following control flow from the entry point, so that only bytes
+/// reachable as instructions are shown as code. Everything else is emitted as `DB` data,
+/// with ranges drawn as sprites shown as bitmaps. Jump, call and `I` targets are named from
+/// `symbols` where possible and get `label_XXX` names otherwise, and the output can be fed
+/// back to the assembler to rebuild the rom byte for byte.
+pub fn disassemble(rom: &[u8], symbols: &Symbols) -> String {
+    let analysis = Analysis::new(rom).with_symbols(symbols);
+    analysis.listing()
+}
+
+/// The result of tracing a rom's control flow
+pub struct Analysis<'a> {
+    rom: &'a [u8],
+    // decoded instructions by address
+    code: BTreeMap<usize, OpCode>,
+    // addresses referenced by jumps, calls and `I`
+    targets: BTreeSet<usize>,
+    // sprite data ranges as (address, height)
+    sprites: BTreeMap<usize, usize>,
+    // reachable addresses whose instruction couldn't be decoded
+    unknown: BTreeSet<usize>,
+    symbols: Option<&'a Symbols>,
+}
+
+impl<'a> Analysis<'a> {
+    pub fn new(rom: &'a [u8]) -> Analysis<'a> {
+        Analysis::with_entries(rom, &[])
+    }
+
+    /// trace from the rom's entry point and also from `entries`, e.g. jump targets that were
+    /// only discovered by running the rom
+    pub fn with_entries(rom: &'a [u8], entries: &[usize]) -> Analysis<'a> {
+        let mut analysis = Analysis {
+            rom: rom,
+            code: BTreeMap::new(),
+            targets: BTreeSet::new(),
+            sprites: BTreeMap::new(),
+            unknown: BTreeSet::new(),
+            symbols: None,
+        };
+        analysis.trace(ROM_START);
+        for &entry in entries {
+            analysis.targets.insert(entry);
+            analysis.trace(entry);
+        }
+        analysis
+    }
+
+    /// name addresses from `symbols` in the listing
+    pub fn with_symbols(mut self, symbols: &'a Symbols) -> Analysis<'a> {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    fn end(&self) -> usize {
+        ROM_START + self.rom.len()
+    }
+
+    fn in_rom(&self, addr: usize) -> bool {
+        addr >= ROM_START && addr < self.end()
+    }
+
+    /// the raw instruction at `addr`, if both of its bytes are in the rom
+    pub fn word(&self, addr: usize) -> Option<u16> {
+        if addr >= ROM_START && addr + 1 < self.end() {
+            let idx = addr - ROM_START;
+            // opcodes are big-endian
+            Some(((self.rom[idx] as u16) << 8) | (self.rom[idx + 1] as u16))
+        } else {
+            None
+        }
+    }
+
+    pub fn code(&self) -> &BTreeMap<usize, OpCode> {
+        &self.code
+    }
+
+    /// addresses control flow reaches where the instruction isn't one we know; tracing
+    /// stops there
+    pub fn unknown(&self) -> &BTreeSet<usize> {
+        &self.unknown
+    }
+
+    // true if the two bytes at `addr` overlap an instruction decoded at another address
+    fn overlaps_code(&self, addr: usize) -> bool {
+        self.code.contains_key(&(addr + 1)) ||
+            (addr > 0 && self.code.contains_key(&(addr - 1)))
+    }
+
+    fn trace(&mut self, entry: usize) {
+        // each pending path carries the value of I if it is known at that point
+        let mut pending = vec![(entry, None)];
+        while let Some((addr, mut reg_i)) = pending.pop() {
+            let mut addr = addr;
+            loop {
+                if self.code.contains_key(&addr) || self.overlaps_code(addr) {
+                    break;
+                }
+                let instr = match self.word(addr) {
+                    Some(instr) => instr,
+                    None => break,
+                };
+                // the cpu treats any instruction starting with 0x0A as the end of the program
+                let opcode = if instr >> 8 == 0x0A { Eof } else { OpCode::from(instr) };
+                if let Unknown(_) = opcode {
+                    self.unknown.insert(addr);
+                    break;
+                }
+                self.code.insert(addr, opcode);
+                let next = addr + 2;
+
+                match opcode {
+                    Eof | Return => break,
+                    JpConst { nnn } => {
+                        self.targets.insert(nnn);
+                        pending.push((nnn, reg_i));
+                        break;
+                    }
+                    JpOffset { nnn } => {
+                        // an indirect jump, usually into a table of jumps starting at nnn;
+                        // all we can follow is the first entry
+                        self.targets.insert(nnn);
+                        pending.push((nnn, reg_i));
+                        break;
+                    }
+                    Call { nnn } => {
+                        self.targets.insert(nnn);
+                        pending.push((nnn, reg_i));
+                        // the subroutine may change I
+                        reg_i = None;
+                    }
+                    SkpEqConst { .. } | SkpNeConst { .. } | SkpEqReg { .. } |
+                    JpRegNe { .. } | SkpKeyEq { .. } | SkpKeyNe { .. } => {
+                        pending.push((next + 2, reg_i));
+                    }
+                    SetI { nnn } => {
+                        self.targets.insert(nnn as usize);
+                        reg_i = Some(nnn as usize);
+                    }
+                    // machine code can do anything to I, but comes back to the next instruction
+                    Sys { .. } |
+                    SetIRegAdd { .. } | SetISprite { .. } | DumpReg { .. } | LoadReg { .. } => {
+                        reg_i = None;
+                    }
+                    Draw { n, .. } => {
+                        if let Some(i) = reg_i {
+                            let height = self.sprites.entry(i).or_insert(0);
+                            *height = (*height).max(n as usize);
+                        }
+                    }
+                    _ => (),
+                }
+                addr = next;
+            }
+        }
+    }
+
+    /// name to use for an address in the listing
+    pub fn label(&self, addr: usize) -> String {
+        // symbols that the assembler wouldn't accept (e.g. octo names with dashes) are skipped
+        match self.symbols.and_then(|symbols| symbols.name(addr)) {
+            Some(name) if is_label_name(name) => name.to_owned(),
+            _ => format!("label_{:03X}", addr),
+        }
+    }
+
+    // targets that land inside an instruction or outside the rom can't be marked with a
+    // label line, so they become constants instead
+    fn is_constant_label(&self, addr: usize) -> bool {
+        !self.in_rom(addr) || (self.overlaps_code(addr) && !self.code.contains_key(&addr))
+    }
+
+    fn sprite_at(&self, addr: usize) -> Option<(usize, usize)> {
+        match self.sprites.range(..addr + 1).next_back() {
+            Some((&start, &height)) if addr < start + height => Some((start, height)),
+            _ => None,
+        }
+    }
+
+    pub fn listing(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "; disassembled from a {} byte rom", self.rom.len()).unwrap();
+
+        let constants: Vec<usize> = self.targets.iter()
+                                                .cloned()
+                                                .filter(|addr| self.is_constant_label(*addr))
+                                                .collect();
+        if !constants.is_empty() {
+            writeln!(out, "").unwrap();
+            for addr in constants {
+                writeln!(out, "{} equ 0x{:03X}", self.label(addr), addr).unwrap();
+            }
+        }
+        writeln!(out, "").unwrap();
+
+        let mut addr = ROM_START;
+        while addr < self.end() {
+            if self.targets.contains(&addr) && !self.is_constant_label(addr) {
+                writeln!(out, "{}:", self.label(addr)).unwrap();
+            }
+            if let Some(opcode) = self.code.get(&addr) {
+                let instr = self.word(addr).expect("decoded instructions are in the rom");
+                // anything the assembler would encode differently is kept as a raw word
+                let text = match self.mnemonic(opcode) {
+                    Some(ref text) if opcode.encode() == instr => text.clone(),
+                    _ => format!("DW 0x{:04X}", instr),
+                };
+                writeln!(out, "    {:<24} ; {:03X}: {:04X}", text, addr, instr).unwrap();
+                addr += 2;
+            } else {
+                addr = self.write_data(&mut out, addr);
+            }
+        }
+        out
+    }
+
+    // write a run of data starting at `addr` and return the address after it
+    fn write_data(&self, out: &mut String, addr: usize) -> usize {
+        let byte = |addr: usize| self.rom[addr - ROM_START];
+        if self.sprite_at(addr).is_some() {
+            writeln!(out, "    {:<24} ; {:03X}: {}", format!("DB 0b{:08b}", byte(addr)), addr, bitmap(byte(addr))).unwrap();
+            return addr + 1;
+        }
+
+        let mut end = addr + 1;
+        while end < self.end() && end - addr < DATA_BYTES_PER_LINE &&
+              !self.code.contains_key(&end) &&
+              !(self.targets.contains(&end) && !self.is_constant_label(end)) &&
+              self.sprite_at(end).is_none() {
+            end += 1;
+        }
+        let bytes: Vec<String> = (addr..end).map(|a| format!("0x{:02X}", byte(a))).collect();
+        writeln!(out, "    {:<24} ; {:03X}", format!("DB {}", bytes.join(", ")), addr).unwrap();
+        end
+    }
+
+    fn addr_operand(&self, addr: usize) -> String {
+        if self.targets.contains(&addr) {
+            self.label(addr)
+        } else {
+            format!("0x{:03X}", addr)
+        }
+    }
+
+    /// assembler syntax for an opcode, or None if it can't be written as an instruction
+    pub fn mnemonic(&self, opcode: &OpCode) -> Option<String> {
+        let text = match *opcode {
+            Eof => "EOF".into(),
+            DrawClr => "CLS".into(),
+            Return => "RET".into(),
+            JpConst{nnn} => format!("JP {}", self.addr_operand(nnn)),
+            Call{nnn} => format!("CALL {}", self.addr_operand(nnn)),
+            SkpEqConst{x,nn} => format!("SE V{:X}, 0x{:02X}", x, nn),
+            SkpNeConst{x,nn} => format!("SNE V{:X}, 0x{:02X}", x, nn),
+            SkpEqReg{x,y} => format!("SE V{:X}, V{:X}", x, y),
+            SetConst{x,nn} => format!("LD V{:X}, 0x{:02X}", x, nn),
+            AddConst{x,nn} => format!("ADD V{:X}, 0x{:02X}", x, nn),
+            SetReg{x,y} => format!("LD V{:X}, V{:X}", x, y),
+            SetRegBor{x,y} => format!("OR V{:X}, V{:X}", x, y),
+            SetRegBand{x,y} => format!("AND V{:X}, V{:X}", x, y),
+            SetRegBxor{x,y} => format!("XOR V{:X}, V{:X}", x, y),
+            SetRegAdd{x,y} => format!("ADD V{:X}, V{:X}", x, y),
+            SetRegSub{x,y} => format!("SUB V{:X}, V{:X}", x, y),
+            SetShr1{x,y} => format!("SHR V{:X}, V{:X}", x, y),
+            SetRegRevSub{x,y} => format!("SUBN V{:X}, V{:X}", x, y),
+            SetShl1{x,y} => format!("SHL V{:X}, V{:X}", x, y),
+            JpRegNe{x,y} => format!("SNE V{:X}, V{:X}", x, y),
+            SetI{nnn} => format!("LD I, {}", self.addr_operand(nnn as usize)),
+            JpOffset{nnn} => format!("JP V0, {}", self.addr_operand(nnn)),
+            SetRand{x,nn} => format!("RND V{:X}, 0x{:02X}", x, nn),
+            Draw{x,y,n} => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            SkpKeyEq{x} => format!("SKP V{:X}", x),
+            SkpKeyNe{x} => format!("SKNP V{:X}", x),
+            SetRegDelay{x} => format!("LD V{:X}, DT", x),
+            SetKey{x} => format!("LD V{:X}, K", x),
+            SetDelay{x} => format!("LD DT, V{:X}", x),
+            SetSound{x} => format!("LD ST, V{:X}", x),
+            SetIRegAdd{x} => format!("ADD I, V{:X}", x),
+            SetISprite{x} => format!("LD F, V{:X}", x),
+            SetBCD{x} => format!("LD B, V{:X}", x),
+            DumpReg{x} => format!("LD [I], V{:X}", x),
+            LoadReg{x} => format!("LD V{:X}, [I]", x),
+            Sys{nnn} => format!("SYS {}", self.addr_operand(nnn)),
+            Unknown(_) => return None,
+        };
+        Some(text)
+    }
+}
+
+/// a sprite row drawn with `#` for set pixels and `.` for clear ones
+fn bitmap(byte: u8) -> String {
+    (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::disassemble;
+    use assembler::assemble_source;
+    use symbols::Symbols;
+
+    // the listing of `rom` should assemble back into the same bytes
+    fn round_trip(rom: &[u8]) {
+        let listing = disassemble(rom, &Symbols::new());
+        match assemble_source(Path::new("test.asm"), &listing) {
+            Ok(assembly) => assert_eq!(assembly.rom, rom, "listing:\n{}", listing),
+            Err(e) => panic!("{}\nlisting:\n{}", e, listing),
+        }
+    }
+
+    #[test]
+    fn sprite_data() {
+        // LD I, sprite; LD V0, 0; LD V1, 0; DRW V0, V1, 5; loop: JP loop; then a "0" sprite
+        let rom = [0xA2, 0x0A, 0x60, 0x00, 0x61, 0x00, 0xD0, 0x15, 0x12, 0x08,
+                   0xF0, 0x90, 0x90, 0x90, 0xF0];
+        round_trip(&rom);
+        assert!(disassemble(&rom, &Symbols::new()).contains("DB 0b11110000"));
+    }
+
+    #[test]
+    fn odd_length() {
+        round_trip(&[0x00, 0xE0, 0x12, 0x02, 0x7F]);
+    }
+
+    #[test]
+    fn odd_aligned_code() {
+        // JP 0x203 over a padding byte, then CLS and a loop at odd addresses
+        round_trip(&[0x12, 0x03, 0xFF, 0x00, 0xE0, 0x12, 0x05]);
+        // a jump into the middle of an instruction
+        round_trip(&[0x12, 0x04, 0x00, 0xE0, 0x12, 0x03]);
+    }
+
+    #[test]
+    fn words_kept_raw() {
+        // 0A12 runs as the end marker but the assembler only writes EOF as 0A00, and FFFF
+        // isn't an instruction at all
+        let rom = [0x00, 0xE0, 0x0A, 0x12, 0xFF, 0xFF];
+        round_trip(&rom);
+        assert!(disassemble(&rom, &Symbols::new()).contains("DW 0x0A12"));
+    }
+}
@@ -6,16 +6,17 @@ use log::LogLevel;
 use memory_bus::MemoryBus;
 use window::Window;
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
 
 pub struct Display {
     grid: [u8; WIDTH * HEIGHT],
-    window: Arc<Mutex<Window>>,
+    // no window is attached when running headless
+    window: Option<Arc<Mutex<Window>>>,
 }
 
 impl Display {
-    pub fn new(window: Arc<Mutex<Window>>) -> Display {
+    pub fn new(window: Option<Arc<Mutex<Window>>>) -> Display {
         let mut display = Display {
             grid: [0; WIDTH * HEIGHT],
             window: window,
@@ -29,9 +30,34 @@ impl Display {
         for x in &mut self.grid[..] {
             *x = 0
         }
-        self.window.lock()
-                   .expect("failed to aquire lock")
-                   .draw(&self.grid);
+        self.refresh();
+    }
+
+    /// the current contents of the display, one byte (0 or 1) per pixel in row-major order
+    pub fn grid(&self) -> &[u8] {
+        &self.grid
+    }
+
+    /// replace the contents of the display, e.g. from a save state
+    pub fn set_grid(&mut self, grid: &[u8]) {
+        self.grid.copy_from_slice(grid);
+        self.refresh();
+    }
+
+    /// a hash of the display contents, for cheaply telling whether two frames differ
+    pub fn hash(&self) -> u64 {
+        // 64-bit FNV-1a
+        self.grid.iter().fold(0xcbf29ce484222325, |hash, &pixel| {
+            (hash ^ pixel as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    fn refresh(&self) {
+        if let Some(ref window) = self.window {
+            window.lock()
+                  .expect("failed to aquire lock")
+                  .draw(&self.grid);
+        }
     }
     /// Draws a sprite at coordinate (x, y) that has a width of 8 pixels 
     /// and a height of n pixels. Each row of 8 pixels is read as 
@@ -40,13 +66,15 @@ impl Display {
     ///
     /// returns true if any screen pixels are flipped from set to unset when 
     /// the sprite is drawn, and false if that doesn’t happen.
-    pub fn draw(&mut self, x: u16, y: u16, n: usize, mem_bus: &mut MemoryBus, i: usize) -> bool {
+    ///
+    /// pixels past the edges are dropped, or drawn on the opposite side if `wrap` is set.
+    pub fn draw(&mut self, x: u16, y: u16, n: usize, mem_bus: &mut MemoryBus, i: usize, wrap: bool) -> bool {
         debug!("drawing 8x{} block at ({},{}) with data 0b{:08b}{:08b}{:08b}{:08b}",
                n, x, y, 
-               mem_bus.read_word(i),
-               mem_bus.read_word(i+1),
-               mem_bus.read_word(i+2),
-               mem_bus.read_word(i+3)
+               mem_bus.peek_word(i),
+               mem_bus.peek_word(i+1),
+               mem_bus.peek_word(i+2),
+               mem_bus.peek_word(i+3)
         );
         let prep_start = time::get_time();
         let mut unset_flag = false;
@@ -55,7 +83,11 @@ impl Display {
             let word = mem_bus.read_word(i + y_offset);
             for x_offset in 0..8 {
                 let pixel = (word >> (7 - x_offset)) & 1;
-                let (_x, _y) = (x as usize + x_offset, y as usize + y_offset);
+                let (mut _x, mut _y) = (x as usize + x_offset, y as usize + y_offset);
+                if wrap {
+                    _x %= WIDTH;
+                    _y %= HEIGHT;
+                }
                 // ignore pixels that run off the edge of the row
                 if _x >= WIDTH || _y >= HEIGHT {
                     continue;
@@ -86,9 +118,7 @@ impl Display {
             }
         }
         let prep_end = time::get_time();
-        self.window.lock()
-                   .expect("failed to aquire lock")
-                   .draw(&self.grid);
+        self.refresh();
         let draw_end = time::get_time();
 
         let prep_time = (prep_end - prep_start).num_nanoseconds().unwrap();
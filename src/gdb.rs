@@ -0,0 +1,544 @@
+//! A stub speaking the GDB Remote Serial Protocol, so gdb or any other RSP client can
+//! control the emulator over a local TCP socket.
+//!
+//! Registers are numbered V0-VF (0-15), then I (16), PC (17), SP (18), DT (19) and ST (20).
+//! I and PC are 16 bits and the rest 8 bits, all sent big-endian like the rest of CHIP-8.
+//! SP is the depth of the call stack, which lives outside of memory; it can be lowered to
+//! unwind the stack but not raised.
+
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+
+use cpu::{Cpu, GP_REG_COUNT};
+use debugger::Action;
+use memory_bus::{MemoryBus, MEMORY_SIZE};
+use timer::Timer;
+
+const REG_I: usize = GP_REG_COUNT;
+const REG_PC: usize = GP_REG_COUNT + 1;
+const REG_SP: usize = GP_REG_COUNT + 2;
+const REG_DT: usize = GP_REG_COUNT + 3;
+const REG_ST: usize = GP_REG_COUNT + 4;
+const REG_COUNT: usize = GP_REG_COUNT + 5;
+
+// sent by the client outside of any packet to stop a running target
+const INTERRUPT: u8 = 0x03;
+
+// signals reported when the target stops
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The parts of the emulator the client can inspect and change while it's stopped
+pub struct Target<'a> {
+    pub cpu: &'a mut Cpu,
+    pub mem_bus: &'a mut MemoryBus,
+    pub delay_timer: &'a mut Timer,
+    pub sound_timer: &'a mut Timer,
+}
+
+fn reg_size(reg: usize) -> usize {
+    match reg {
+        REG_I | REG_PC => 2,
+        _ => 1,
+    }
+}
+
+impl<'a> Target<'a> {
+    fn read_register(&self, reg: usize) -> Option<u16> {
+        let state = self.cpu.state();
+        Some(match reg {
+            0x0...0xF => state.v[reg] as u16,
+            REG_I => state.i,
+            REG_PC => state.pc as u16,
+            REG_SP => state.stack.len() as u16,
+            REG_DT => self.delay_timer.get_value() as u16,
+            REG_ST => self.sound_timer.get_value() as u16,
+            _ => return None,
+        })
+    }
+
+    fn write_register(&mut self, reg: usize, value: u16) -> bool {
+        let mut state = self.cpu.state();
+        match reg {
+            0x0...0xF => state.v[reg] = value as u8,
+            REG_I => state.i = value,
+            // the pc has to leave room for a whole instruction
+            REG_PC if (value as usize) + 1 < MEMORY_SIZE => state.pc = value as usize,
+            REG_SP if (value as usize) <= state.stack.len() => state.stack.truncate(value as usize),
+            REG_DT => self.delay_timer.set_value(value as u8),
+            REG_ST => self.sound_timer.set_value(value as u8),
+            _ => return false,
+        }
+        self.cpu.set_state(&state);
+        true
+    }
+
+    fn registers(&self) -> String {
+        (0..REG_COUNT).map(|reg| encode_register(reg, self.read_register(reg).expect("every register is readable")))
+                      .collect()
+    }
+
+    fn set_registers(&mut self, hex: &str) -> bool {
+        let mut offset = 0;
+        for reg in 0..REG_COUNT {
+            let digits = reg_size(reg) * 2;
+            let value = match hex.get(offset..offset + digits).and_then(|h| u16::from_str_radix(h, 16).ok()) {
+                Some(value) => value,
+                None => return false,
+            };
+            offset += digits;
+            if reg == REG_SP && value as usize == self.cpu.state().stack.len() {
+                continue;
+            }
+            if !self.write_register(reg, value) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Option<String> {
+        match addr.checked_add(len) {
+            Some(end) if end <= MEMORY_SIZE => (),
+            _ => return None,
+        }
+        Some((addr..addr + len).map(|a| format!("{:02x}", self.mem_bus.peek_word(a))).collect())
+    }
+
+    fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> bool {
+        match addr.checked_add(bytes.len()) {
+            Some(end) if end <= MEMORY_SIZE => (),
+            _ => return false,
+        }
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.mem_bus.poke_word(addr + offset, byte);
+        }
+        true
+    }
+}
+
+fn encode_register(reg: usize, value: u16) -> String {
+    match reg_size(reg) {
+        2 => format!("{:04x}", value),
+        _ => format!("{:02x}", value),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.as_bytes()
+       .chunks(2)
+       .map(|pair| str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+       .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+// `ADDR,LEN` as used by memory and breakpoint packets
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, ',');
+    match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+        (Some(addr), Some(len)) => Some((addr, len)),
+        _ => None,
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+/// What to do after handling a packet
+enum Response {
+    Reply(String),
+    Resume,
+    Quit,
+}
+
+/// A connected RSP client and the breakpoints it has set
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: BTreeSet<usize>,
+    // stop before the next instruction
+    stepping: bool,
+    // true while the client is waiting for the target to stop
+    running: bool,
+    // the client asked for packets to go unacknowledged
+    no_ack: bool,
+    attached: bool,
+}
+
+impl GdbStub {
+    /// wait on `port` of the loopback interface for a client to connect. The target starts
+    /// out stopped before its first instruction.
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("waiting for gdb to connect to 127.0.0.1:{}", port);
+        let (stream, peer) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        info!("gdb connected from {}", peer);
+        Ok(GdbStub::new(stream))
+    }
+
+    fn new(stream: TcpStream) -> GdbStub {
+        GdbStub {
+            stream: stream,
+            breakpoints: BTreeSet::new(),
+            stepping: true,
+            running: false,
+            no_ack: false,
+            attached: true,
+        }
+    }
+
+    /// the signal to stop with before the instruction at `pc`, if the target should stop
+    pub fn should_stop(&mut self, pc: usize) -> Option<u8> {
+        if !self.attached {
+            return None;
+        }
+        if self.stepping || self.breakpoints.contains(&pc) {
+            self.stepping = false;
+            return Some(SIGTRAP);
+        }
+        if self.interrupted() {
+            return Some(SIGINT);
+        }
+        None
+    }
+
+    // check for an interrupt without waiting
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        let read = self.stream.set_nonblocking(true).and_then(|()| self.stream.read(&mut byte));
+        if let Err(e) = self.stream.set_nonblocking(false) {
+            warn!("gdb connection failed: {}", e);
+            self.detach();
+            return false;
+        }
+        match read {
+            Ok(0) => {
+                warn!("gdb disconnected");
+                self.detach();
+                false
+            }
+            Ok(_) => byte[0] == INTERRUPT,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => {
+                warn!("gdb connection failed: {}", e);
+                self.detach();
+                false
+            }
+        }
+    }
+
+    fn detach(&mut self) {
+        self.attached = false;
+        self.breakpoints.clear();
+        self.stepping = false;
+    }
+
+    /// report the stop to the client and handle its requests until it resumes the target
+    pub fn serve(&mut self, signal: u8, target: &mut Target) -> Action {
+        if self.running {
+            self.running = false;
+            if let Err(e) = self.send_packet(&format!("S{:02x}", signal)) {
+                warn!("gdb connection failed: {}", e);
+                self.detach();
+                return Action::Run;
+            }
+        }
+        loop {
+            let response = match self.read_packet() {
+                Ok(packet) => self.handle(&packet, target),
+                Err(e) => {
+                    warn!("gdb connection failed: {}", e);
+                    self.detach();
+                    return Action::Run;
+                }
+            };
+            match response {
+                Response::Reply(reply) => {
+                    if let Err(e) = self.send_packet(&reply) {
+                        warn!("gdb connection failed: {}", e);
+                        self.detach();
+                        return Action::Run;
+                    }
+                }
+                Response::Resume => return Action::Run,
+                Response::Quit => return Action::Quit,
+            }
+        }
+    }
+
+    /// tell a waiting client that the rom has finished
+    pub fn exited(&mut self) {
+        if self.attached && self.running {
+            if let Err(e) = self.send_packet("W00") {
+                warn!("could not tell gdb the rom exited: {}", e);
+            }
+        }
+        self.detach();
+    }
+
+    fn handle(&mut self, packet: &str, target: &mut Target) -> Response {
+        let split = packet.char_indices().nth(1).map(|(idx, _)| idx).unwrap_or(packet.len());
+        let (command, args) = packet.split_at(split);
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => target.registers(),
+            "G" => if target.set_registers(args) { "OK".into() } else { error(1) },
+            "p" => match parse_hex(args).and_then(|reg| target.read_register(reg).map(|v| encode_register(reg, v))) {
+                Some(value) => value,
+                None => error(1),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let reg = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(decode_hex);
+                match (reg, value) {
+                    (Some(reg), Some(ref bytes)) if bytes.len() == reg_size(reg) => {
+                        let value = bytes.iter().fold(0u16, |value, &b| (value << 8) | b as u16);
+                        if target.write_register(reg, value) { "OK".into() } else { error(1) }
+                    }
+                    _ => error(1),
+                }
+            }
+            "m" => match parse_range(args).and_then(|(addr, len)| target.read_memory(addr, len)) {
+                Some(hex) => hex,
+                None => error(1),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let bytes = parts.next().and_then(decode_hex);
+                match (range, bytes) {
+                    (Some((addr, len)), Some(ref bytes)) if bytes.len() == len => {
+                        if target.write_memory(addr, bytes) { "OK".into() } else { error(1) }
+                    }
+                    _ => error(1),
+                }
+            }
+            // software and hardware breakpoints are both kept by the emulator, so memory is
+            // never patched
+            "Z" | "z" => {
+                let mut parts = args.splitn(2, ',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(parse_range).map(|(addr, _)| addr);
+                match (kind, addr) {
+                    (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                        if command == "Z" {
+                            self.breakpoints.insert(addr);
+                        } else {
+                            self.breakpoints.remove(&addr);
+                        }
+                        "OK".into()
+                    }
+                    // watchpoints aren't supported
+                    _ => String::new(),
+                }
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(addr) => {
+                            target.write_register(REG_PC, addr as u16);
+                        }
+                        None => return Response::Reply(error(1)),
+                    }
+                }
+                self.stepping = command == "s";
+                self.running = true;
+                return Response::Resume;
+            }
+            "D" => {
+                if let Err(e) = self.send_packet("OK") {
+                    warn!("gdb connection failed: {}", e);
+                }
+                info!("gdb detached");
+                self.detach();
+                return Response::Resume;
+            }
+            "k" => {
+                self.detach();
+                return Response::Quit;
+            }
+            "H" => "OK".into(),
+            "T" => "OK".into(),
+            "q" | "Q" | "v" => match packet {
+                p if p.starts_with("qSupported") => "PacketSize=1000;QStartNoAckMode+".into(),
+                "QStartNoAckMode" => {
+                    if let Err(e) = self.send_packet("OK") {
+                        warn!("gdb connection failed: {}", e);
+                    }
+                    self.no_ack = true;
+                    return self.handle_next(target);
+                }
+                "qAttached" => "1".into(),
+                "qC" => "QC1".into(),
+                "qfThreadInfo" => "m1".into(),
+                "qsThreadInfo" => "l".into(),
+                "vKill" => {
+                    if let Err(e) = self.send_packet("OK") {
+                        warn!("gdb connection failed: {}", e);
+                    }
+                    self.detach();
+                    return Response::Quit;
+                }
+                _ => String::new(),
+            },
+            // an empty reply tells the client a packet isn't supported
+            _ => String::new(),
+        };
+        Response::Reply(reply)
+    }
+
+    // the reply to `QStartNoAckMode` has to go out before acks stop, so it's sent directly
+    // and the next packet handled in its place
+    fn handle_next(&mut self, target: &mut Target) -> Response {
+        match self.read_packet() {
+            Ok(packet) => self.handle(&packet, target),
+            Err(e) => {
+                warn!("gdb connection failed: {}", e);
+                self.detach();
+                Response::Resume
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            // skip acks, and interrupts sent while the target is already stopped
+            if self.read_byte()? != b'$' {
+                continue;
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let sum = [self.read_byte()?, self.read_byte()?];
+            let valid = str::from_utf8(&sum).ok()
+                                            .and_then(|s| u8::from_str_radix(s, 16).ok())
+                                            .map(|sum| sum == checksum(&data))
+                                            .unwrap_or(false);
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return String::from_utf8(data).map_err(|_| io::Error::new(ErrorKind::InvalidData, "packet is not utf-8"));
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // resend until acknowledged
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::{checksum, GdbStub, Target, SIGTRAP};
+    use cpu::Cpu;
+    use debugger::Action;
+    use memory_bus::{MemoryBus, ROM_START};
+    use timer::Timer;
+
+    // a connected client and the stub serving it
+    fn connect() -> (TcpStream, GdbStub) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("can listen on loopback");
+        let client = TcpStream::connect(listener.local_addr().expect("listener has an address"))
+                               .expect("can connect to the listener");
+        let (stream, _) = listener.accept().expect("client connects");
+        (client, GdbStub::new(stream))
+    }
+
+    // the packets as a client would send them, each followed by the ack for its reply if it
+    // gets one
+    fn script(packets: &[&str]) -> Vec<u8> {
+        packets.iter()
+               .map(|&data| {
+                   let ack = if data.starts_with('c') { "" } else { "+" };
+                   format!("${}#{:02x}{}", data, checksum(data.as_bytes()), ack)
+               })
+               .collect::<String>()
+               .into_bytes()
+    }
+
+    // the replies in what the stub sent, leaving out its acks
+    fn replies(sent: &str) -> Vec<String> {
+        sent.split('$').skip(1).map(|packet| packet.splitn(2, '#').next().unwrap_or("").to_owned()).collect()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let (mut client, mut stub) = connect();
+        let mut cpu = Cpu::new();
+        let mut mem_bus = MemoryBus::new();
+        mem_bus.load_rom(&[0x60, 0x2A, 0x12, 0x02]).expect("rom fits");
+        let (mut delay_timer, mut sound_timer) = (Timer::new(), Timer::new());
+
+        let packets = ["?", "g", "m200,4", "m0,ffffffffffffffff", "Mffffffffffffffff,1:00", "P11=ffff",
+                       "P11=0fff", "Z0,202,2", "c"];
+        client.write_all(&script(&packets)).expect("client can send");
+        {
+            let mut target = Target {
+                cpu: &mut cpu,
+                mem_bus: &mut mem_bus,
+                delay_timer: &mut delay_timer,
+                sound_timer: &mut sound_timer,
+            };
+            assert_eq!(stub.should_stop(ROM_START), Some(SIGTRAP));
+            match stub.serve(SIGTRAP, &mut target) {
+                Action::Run => (),
+                _ => panic!("c should resume the target"),
+            }
+        }
+        // running to the breakpoint stops there
+        assert_eq!(stub.should_stop(0x202), Some(SIGTRAP));
+        // closing the stub's end lets the client read everything it sent
+        drop(stub);
+
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).expect("client can read the replies");
+        let replies = replies(&sent);
+        let registers = format!("{}{:04x}{:04x}000000", "00".repeat(16), 0, ROM_START);
+        assert_eq!(replies, vec!["S05".to_owned(), registers, "602a1202".to_owned(), "E01".to_owned(),
+                                 "E01".to_owned(), "E01".to_owned(), "E01".to_owned(), "OK".to_owned()]);
+    }
+}
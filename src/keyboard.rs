@@ -3,25 +3,62 @@ use glium::glutin::{ElementState, VirtualKeyCode};
 
 use window::Window;
 
+/// Emulator controls bound to keys outside of the CHIP-8 keypad
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    Screenshot,
+    ToggleRecording,
+    ToggleMute,
+    TogglePause,
+    Step,
+    SaveState,
+    LoadState,
+}
+
 pub struct Keyboard {
-    window: Arc<Mutex<Window>>,
+    // no window is attached when running headless
+    window: Option<Arc<Mutex<Window>>>,
 }
 
 impl Keyboard {
-    pub fn new(window: Arc<Mutex<Window>>) -> Keyboard {
+    pub fn new(window: Option<Arc<Mutex<Window>>>) -> Keyboard {
         Keyboard {
             window: window,
         }
     }
 
+    /// also play the keypad key `hex_code` with `key`
+    pub fn bind(&mut self, key: VirtualKeyCode, hex_code: u8) {
+        if let Some(ref window) = self.window {
+            window.lock().expect("failed to aquire lock").bind_key(key, hex_code);
+        }
+    }
+
     /// block the next valid keypress and return it as a hex value 0x0 - 0xF
     pub fn get_key(&mut self) -> u8 {
-        let mut window = self.window.lock().expect("failed to aquire lock");
-        window.get_key()
+        match self.window {
+            Some(ref window) => window.lock().expect("failed to aquire lock").get_key(),
+            None => {
+                warn!("waiting for a key press while headless; pretending 0 was pressed");
+                0x0
+            }
+        }
+    }
+
+    /// hotkeys pressed since the last call
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        match self.window {
+            Some(ref window) => window.lock().expect("failed to aquire lock").take_hotkeys(),
+            None => vec![],
+        }
     }
 
     pub fn is_key_pressed(&mut self, hex_code: u8) -> bool {
-        let mut window = self.window.lock().expect("failed to aquire lock");
+        let window = match self.window {
+            Some(ref window) => window,
+            None => return false,
+        };
+        let mut window = window.lock().expect("failed to aquire lock");
         let key_states = window.get_key_states();
         debug!("looking for key {:X} in {:?}", hex_code, key_states);
         match key_states[&hex_code] {
@@ -30,6 +67,15 @@ impl Keyboard {
         }
     }
 
+    /// whether each key from 0x0 to 0xF is held down
+    pub fn pressed_keys(&mut self) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for (code, pressed) in keys.iter_mut().enumerate() {
+            *pressed = self.is_key_pressed(code as u8);
+        }
+        keys
+    }
+
     pub fn remap_code(hex_code: u8) -> VirtualKeyCode {
         use self::VirtualKeyCode::*;
         match hex_code {
@@ -78,4 +124,36 @@ impl Keyboard {
             _ => None
         }
     }
+
+    /// the key for a game action named as in the chip-8-database, like `up` or `a`
+    pub fn action_key(action: &str) -> Option<VirtualKeyCode> {
+        use self::VirtualKeyCode::*;
+        match action {
+            "up" => Some(Up),
+            "down" => Some(Down),
+            "left" => Some(Left),
+            "right" => Some(Right),
+            "a" => Some(Space),
+            "b" => Some(Return),
+            "player2Up" => Some(I),
+            "player2Down" => Some(K),
+            "player2Left" => Some(J),
+            "player2Right" => Some(L),
+            _ => None,
+        }
+    }
+
+    pub fn remap_hotkey(key: VirtualKeyCode) -> Option<Hotkey> {
+        use self::VirtualKeyCode::*;
+        match key {
+            F12 => Some(Hotkey::Screenshot),
+            F11 => Some(Hotkey::ToggleRecording),
+            F8 => Some(Hotkey::ToggleMute),
+            F5 => Some(Hotkey::TogglePause),
+            F10 => Some(Hotkey::Step),
+            F6 => Some(Hotkey::SaveState),
+            F7 => Some(Hotkey::LoadState),
+            _ => None
+        }
+    }
 }
@@ -1,33 +1,74 @@
 #![allow(dead_code,unused_variables)]
 
+extern crate base64;
 extern crate clap;
 extern crate env_logger;
+extern crate gif;
 #[macro_use]
 extern crate glium;
 #[macro_use]
 extern crate log;
+extern crate png;
 extern crate portaudio;
 extern crate rand;
+#[macro_use]
+extern crate serde_json;
+extern crate sha1;
 extern crate termion;
 extern crate time;
+extern crate zip;
 
+mod assembler;
+mod capture;
+mod cfg;
+mod cdp1802;
+mod cheats;
 mod chip8;
+mod coverage;
 mod cpu;
+mod dap;
+mod debugger;
+mod disassembler;
 mod display;
+mod gdb;
 mod keyboard;
 mod memory_bus;
+mod native;
+mod octo;
 mod opcodes;
+mod overlay;
+mod picker;
+mod profile;
+mod quirks;
+mod rom;
+mod rom_db;
 mod sound;
+mod source_map;
+mod symbols;
 mod timer;
+mod timing;
+mod trace;
+mod trace_diff;
+mod vip;
+mod watch;
 mod window;
 
 use std::env;
-use std::fmt;
 use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
 
-use clap::{Arg, App};
+use capture::Palette;
+use native::{Routine, SysMode};
+use rom_db::RomDb;
+use source_map::SourceMap;
+use sound::{AudioBackend, Waveform};
+use symbols::Symbols;
+use timing::Timing;
+use trace::TraceFilter;
+use watch::Reload;
 
 fn main() {
 
@@ -46,47 +87,652 @@ fn main() {
     let args = App::new("CHIP EMUL8")
         .version("0.1")
         .author("Jeff Belgum <jeffbelgum@gmail.com>")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("asm")
+             .about("assembles a CHIP-8 source file into a rom")
+             .arg(Arg::with_name("SOURCE_FILE")
+                  .help("the source file to assemble; .8o files are compiled as Octo")
+                  .required(true)
+                  .index(1))
+             .arg(Arg::with_name("output")
+                  .short("o")
+                  .long("output")
+                  .takes_value(true)
+                  .help("where to write the rom [default: SOURCE_FILE with a .ch8 extension]"))
+             .arg(Arg::with_name("symbols")
+                  .long("symbols")
+                  .takes_value(true)
+                  .help("where to write the symbol map [default: the rom path with a .sym extension]")))
+        .subcommand(SubCommand::with_name("dap")
+             .about("runs a debug adapter protocol server, so editors can launch and debug roms")
+             .arg(Arg::with_name("port")
+                  .long("port")
+                  .takes_value(true)
+                  .help("serves a client connecting to this port on localhost instead of stdin and stdout")))
+        .subcommand(SubCommand::with_name("trace-diff")
+             .about("compares two traces written with --trace and reports where they diverge")
+             .arg(Arg::with_name("LEFT")
+                  .required(true)
+                  .index(1))
+             .arg(Arg::with_name("RIGHT")
+                  .required(true)
+                  .index(2))
+             .arg(Arg::with_name("context")
+                  .long("context")
+                  .takes_value(true)
+                  .help("instructions to show either side of the first divergence [default: 5]"))
+             .arg(Arg::with_name("all")
+                  .long("all")
+                  .help("also lists every region where the traces differ")))
         .arg(Arg::with_name("ROM_FILE")
-             .help("Sets the rom file to load")
+             .help("Sets the rom file to load; .8o files are compiled as Octo source, zip archives are searched for roms, - reads from stdin, and a directory shows a menu of its roms")
              .required(true)
              .index(1))
         .arg(Arg::with_name("debug")
              .short("d")
              .long("debug")
              .help("starts the emulator up in debug mode to step through instructions one at a time"))
+        .arg(Arg::with_name("break")
+             .short("b")
+             .long("break")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("LOCATION")
+             .help("stops in the debugger before the instruction at a symbol, hex address or FILE:LINE"))
+        .arg(Arg::with_name("gdb")
+             .long("gdb")
+             .takes_value(true)
+             .value_name("PORT")
+             .conflicts_with_all(&["debug", "break"])
+             .help("waits for gdb to connect to this port on localhost and lets it control the emulator"))
+        .arg(Arg::with_name("platform")
+             .long("platform")
+             .takes_value(true)
+             .value_name("ID")
+             .help("runs with the quirks and speed of a chip-8-database platform like originalChip8 or superchip, instead of the rom's own"))
+        .arg(Arg::with_name("tickrate")
+             .long("tickrate")
+             .takes_value(true)
+             .value_name("N")
+             .help("runs N instructions per 60 Hz frame [default: the rom's or platform's, or about 8]"))
+        .arg(Arg::with_name("timing")
+             .long("timing")
+             .takes_value(true)
+             .possible_values(&["fixed", "vip"])
+             .conflicts_with("tickrate")
+             .help("vip runs each instruction as slowly as the COSMAC VIP's interpreter did, with draws waiting for the vertical blank [default: fixed]"))
+        .arg(Arg::with_name("sys")
+             .long("sys")
+             .takes_value(true)
+             .possible_values(&["ignore", "log", "1802"])
+             .help("what 0NNN machine code calls do: nothing, nothing with a warning, or run on an emulated 1802 with memory laid out as the VIP's interpreter had it, which needs the rom to end before EA0 [default: log]"))
+        .arg(Arg::with_name("sys-routine")
+             .long("sys-routine")
+             .takes_value(true)
+             .value_name("ADDR=NAME")
+             .multiple(true)
+             .number_of_values(1)
+             .help("emulates the machine code routine at ADDR (hex) as ret (return at once) or cls (clear the screen)"))
+        .arg(Arg::with_name("vip-interpreter")
+             .long("vip-interpreter")
+             .takes_value(true)
+             .value_name("FILE")
+             .conflicts_with_all(&["debug", "break", "gdb", "platform", "tickrate", "timing", "sys", "sys-routine", "watch",
+                                   "cheat", "cheats", "jump-log", "trace", "profile", "profile-folded", "coverage",
+                                   "coverage-listing", "cycles", "overlay", "record", "audio-sync"])
+             .help("emulates a whole COSMAC VIP running this dump of its original 512-byte CHIP-8 interpreter, which also runs 0NNN machine code"))
+        .arg(Arg::with_name("frames")
+             .long("frames")
+             .takes_value(true)
+             .value_name("N")
+             .requires("vip-interpreter")
+             .help("number of 60 Hz frames to run the COSMAC VIP for before exiting"))
+        .arg(Arg::with_name("check")
+             .long("check")
+             .help("warns about reachable instructions the rom's platform doesn't have, or that can't be emulated, before running it"))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("reloads the rom, or recompiles the .8o source, whenever the file changes"))
+        .arg(Arg::with_name("on-reload")
+             .long("on-reload")
+             .takes_value(true)
+             .value_name("STATE")
+             .possible_values(&["reset", "keep", "saved"])
+             .requires("watch")
+             .help("what a reloaded rom starts from: a reset, the state it was in, or the state saved with F6 [default: reset]"))
+        .arg(Arg::with_name("rom-db")
+             .long("rom-db")
+             .takes_value(true)
+             .value_name("DIR")
+             .help("looks roms up in this chip-8-database directory for their title, platform, quirks, speed, keys and colors [default: ~/.chip8-database, if there is one]"))
+        .arg(Arg::with_name("cheat")
+             .long("cheat")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("ADDR=VALUE")
+             .help("freezes the byte at a hex address at a hex value every frame, and saves the code for this rom to the --cheats file if one is given"))
+        .arg(Arg::with_name("cheats")
+             .long("cheats")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("keeps cheat codes for each rom in FILE, loading them at startup and saving any added"))
+        .arg(Arg::with_name("symbols")
+             .long("symbols")
+             .takes_value(true)
+             .value_name("SYM_FILE")
+             .help("names addresses from an 'ADDR name' file [default: ROM_FILE with a .sym extension, if there is one]"))
         .arg(Arg::with_name("disassemble")
              .long("dis")
              .help("prints disassembled rom"))
+        .arg(Arg::with_name("cfg")
+             .long("cfg")
+             .takes_value(true)
+             .value_name("FORMAT")
+             .possible_values(&["dot", "json"])
+             .help("prints the rom's control-flow graph as graphviz dot or json"))
+        .arg(Arg::with_name("call-graph")
+             .long("call-graph")
+             .requires("cfg")
+             .help("prints the call graph instead of the control-flow graph"))
+        .arg(Arg::with_name("jumps")
+             .long("jumps")
+             .takes_value(true)
+             .value_name("FILE")
+             .requires("cfg")
+             .help("merges indirect jumps recorded with --jump-log into the graph"))
+        .arg(Arg::with_name("jump-log")
+             .long("jump-log")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("records the targets of indirect jumps taken while running, for --jumps"))
+        .arg(Arg::with_name("trace")
+             .long("trace")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("writes a json record of every instruction executed, one per line"))
+        .arg(Arg::with_name("trace-addrs")
+             .long("trace-addrs")
+             .takes_value(true)
+             .value_name("FIRST-LAST")
+             .requires("trace")
+             .help("only traces instructions in this range of hex addresses"))
+        .arg(Arg::with_name("trace-window")
+             .long("trace-window")
+             .takes_value(true)
+             .value_name("FIRST-LAST")
+             .requires("trace")
+             .help("only traces this range of instructions, counting from 1"))
+        .arg(Arg::with_name("profile")
+             .long("profile")
+             .help("prints where the rom spent its instructions on exit"))
+        .arg(Arg::with_name("profile-folded")
+             .long("profile-folded")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("writes call stacks weighted by instructions executed on exit, for flame graphs"))
+        .arg(Arg::with_name("coverage")
+             .long("coverage")
+             .takes_value(true)
+             .value_name("LCOV_FILE")
+             .help("records which instructions ran and which bytes were read or written, adding to the counts already in the file"))
+        .arg(Arg::with_name("coverage-listing")
+             .long("coverage-listing")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("writes a disassembly annotated with the coverage on exit"))
         .arg(Arg::with_name("cycles")
              .short("c")
              .long("cycles")
              .takes_value(true)
              .help("number for cycles to execute before exiting the emulator"))
+        .arg(Arg::with_name("headless")
+             .long("headless")
+             .help("runs without opening a window"))
+        .arg(Arg::with_name("overlay")
+             .long("overlay")
+             .conflicts_with("headless")
+             .help("shows registers, the stack, disassembly and memory under the display; F5 pauses and F10 steps, and --debug and --break stop in the window"))
+        .arg(Arg::with_name("screenshot")
+             .long("screenshot")
+             .takes_value(true)
+             .value_name("PNG_FILE")
+             .help("saves the display as a png when the emulator exits"))
+        .arg(Arg::with_name("record")
+             .long("record")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("records every frame to an animated gif (.gif) or a raw frame sequence (any other extension)"))
+        .arg(Arg::with_name("scale")
+             .long("scale")
+             .takes_value(true)
+             .help("size in image pixels of a CHIP-8 pixel in screenshots and recordings [default: 10]"))
+        .arg(Arg::with_name("palette")
+             .long("palette")
+             .takes_value(true)
+             .value_name("ON,OFF")
+             .help("pixel colors for screenshots and recordings as hex RRGGBB,RRGGBB"))
+        .arg(Arg::with_name("tone")
+             .long("tone")
+             .takes_value(true)
+             .value_name("HZ")
+             .help("frequency of the beeper tone [default: 440]"))
+        .arg(Arg::with_name("waveform")
+             .long("waveform")
+             .takes_value(true)
+             .possible_values(&["square", "sine", "triangle"])
+             .help("shape of the beeper tone [default: square]"))
+        .arg(Arg::with_name("volume")
+             .long("volume")
+             .takes_value(true)
+             .help("beeper volume from 0.0 to 1.0 [default: 0.25]"))
+        .arg(Arg::with_name("audio")
+             .long("audio")
+             .takes_value(true)
+             .possible_values(&["auto", "portaudio", "null"])
+             .help("audio output; auto falls back to null when no device is available [default: auto]"))
+        .arg(Arg::with_name("wav")
+             .long("wav")
+             .takes_value(true)
+             .value_name("WAV_FILE")
+             .conflicts_with("audio")
+             .help("writes the beeper output to a wav file instead of playing it"))
+        .arg(Arg::with_name("audio-sync")
+             .long("audio-sync")
+             .help("runs the emulator exactly as fast as the audio device plays sound"))
         .get_matches();
 
-    // load rom
+    if let Some(args) = args.subcommand_matches("asm") {
+        assemble(args);
+        return;
+    }
+    if let Some(args) = args.subcommand_matches("trace-diff") {
+        diff_traces(args);
+        return;
+    }
+    if let Some(args) = args.subcommand_matches("dap") {
+        debug_adapter(args);
+        return;
+    }
+
     let path = args.value_of("ROM_FILE").unwrap();
-    let bin_file = load_bin(path);
+    let result = if path != "-" && Path::new(path).is_dir() {
+        pick_rom(&args, Path::new(path))
+    } else {
+        run_rom(&args, path)
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run_rom(args: &ArgMatches, path: &str) -> Result<(), String> {
+    if args.is_present("watch") && (path == "-" || is_zip(path)) {
+        return Err("--watch needs a rom or .8o file to watch".to_owned());
+    }
+    let (bin_file, source_map, mut symbols) = load_program(path)?;
+    symbols.merge(&load_symbols(path, args.value_of("symbols")));
 
     if args.is_present("disassemble") {
         // print disassembled code
-        chip8::Chip8::disassemble(&bin_file);
+        chip8::Chip8::disassemble(&bin_file, &symbols);
+    } else if let Some(format) = args.value_of("cfg") {
+        print_cfg(&bin_file, &symbols, format, args.is_present("call-graph"), args.value_of("jumps"));
     } else {
-        let step = args.is_present("debug");
-        let cycles: Option<u64> = args.value_of("cycles")
-                                      .map(|c| c.parse().ok())
-                                      .unwrap_or(None);
-        // create and run chip-8 emulator
-        chip8::Chip8::run(&bin_file, step, cycles);
+        let mut config = chip8::Config::default();
+        config.rom_path = Some(PathBuf::from(path));
+        // the database's settings come first so the flags below can override them
+        let db = load_rom_db(args.value_of("rom-db"));
+        apply_rom_db(&mut config, &db, &bin_file);
+        if let Some(id) = args.value_of("platform") {
+            match db.platform(id) {
+                Some(platform) => {
+                    config.platform = Some(platform.id.clone());
+                    config.quirks = platform.quirks;
+                    config.tickrate = Some(platform.tickrate);
+                }
+                None => {
+                    return Err(format!("unknown platform {}; expected one of {}", id, db.platform_ids().join(", ")));
+                }
+            }
+        }
+        if let Some(tickrate) = args.value_of("tickrate") {
+            config.tickrate = match tickrate.parse() {
+                Ok(tickrate) if tickrate > 0 => Some(tickrate),
+                _ => return Err(format!("tickrate must be a positive number of instructions, not {}", tickrate)),
+            };
+        }
+        if let Some(timing) = args.value_of("timing") {
+            config.timing = Timing::parse(timing).expect("possible values are checked by clap");
+        }
+        if let Some(sys) = args.value_of("sys") {
+            config.sys = SysMode::parse(sys).expect("possible values are checked by clap");
+        }
+        config.sys_routines = match args.values_of("sys-routine") {
+            Some(bindings) => bindings.map(|binding| {
+                Routine::parse_binding(binding).ok_or(format!("sys-routine must look like 2A0=cls, not {}", binding))
+            }).collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        if args.is_present("check") {
+            for warning in rom::prescan(&bin_file, config.platform.as_ref().map(|p| p.as_str())) {
+                warn!("{}", warning);
+            }
+        }
+        if args.is_present("watch") {
+            config.watch = Some(args.value_of("on-reload").and_then(Reload::parse).unwrap_or(Reload::Reset));
+        }
+        config.step = args.is_present("debug");
+        config.breakpoints = args.values_of("break")
+                                 .map(|locations| locations.map(String::from).collect())
+                                 .unwrap_or(vec![]);
+        config.cycles = args.value_of("cycles")
+                            .map(|c| c.parse().ok())
+                            .unwrap_or(None);
+        config.frames = args.value_of("frames")
+                            .map(|f| f.parse().ok())
+                            .unwrap_or(None);
+        config.headless = args.is_present("headless");
+        config.overlay = args.is_present("overlay");
+        config.screenshot = args.value_of("screenshot").map(PathBuf::from);
+        config.record = args.value_of("record").map(PathBuf::from);
+        if let Some(scale) = args.value_of("scale") {
+            // gif recordings are at most 65535 pixels wide
+            config.scale = match scale.parse() {
+                Ok(scale) if scale >= 1 && scale <= 1023 => scale,
+                _ => return Err(format!("scale must be a whole number from 1 to 1023, not {}", scale)),
+            };
+        }
+        if let Some(palette) = args.value_of("palette") {
+            config.palette = Palette::parse(palette).ok_or(format!("palette must look like FF2929,121212, not {}", palette))?;
+        }
+        if let Some(tone) = args.value_of("tone") {
+            config.sound.frequency = match tone.parse() {
+                Ok(frequency) if frequency > 0.0 => frequency,
+                _ => return Err(format!("tone must be a frequency in Hz, not {}", tone)),
+            };
+        }
+        if let Some(waveform) = args.value_of("waveform") {
+            config.sound.waveform = Waveform::parse(waveform).ok_or(format!("unknown waveform {}", waveform))?;
+        }
+        if let Some(volume) = args.value_of("volume") {
+            config.sound.volume = match volume.parse() {
+                Ok(volume) if volume >= 0.0 && volume <= 1.0 => volume,
+                _ => return Err(format!("volume must be a number from 0.0 to 1.0, not {}", volume)),
+            };
+        }
+        config.audio = match (args.value_of("audio"), args.value_of("wav")) {
+            (_, Some(wav)) => AudioBackend::Wav(PathBuf::from(wav)),
+            (Some("portaudio"), _) => AudioBackend::PortAudio,
+            (Some("null"), _) => AudioBackend::Null,
+            _ => AudioBackend::Auto,
+        };
+        config.audio_sync = args.is_present("audio-sync");
+        config.source_map = source_map;
+        config.symbols = symbols;
+        config.jump_log = args.value_of("jump-log").map(PathBuf::from);
+        config.trace = args.value_of("trace").map(PathBuf::from);
+        config.profile = args.is_present("profile");
+        config.profile_folded = args.value_of("profile-folded").map(PathBuf::from);
+        config.coverage = args.value_of("coverage").map(PathBuf::from);
+        config.coverage_listing = args.value_of("coverage-listing").map(PathBuf::from);
+        if let Some(port) = args.value_of("gdb") {
+            config.gdb = Some(port.parse().map_err(|_| format!("gdb port must be a number, not {}", port))?);
+        }
+        config.cheats = match args.values_of("cheat") {
+            Some(codes) => codes.map(cheats::parse_code).collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        config.cheats_file = args.value_of("cheats").map(PathBuf::from);
+        if let Some(range) = args.value_of("trace-addrs") {
+            let (first, last) = TraceFilter::parse_range(range, 16)
+                                            .map_err(|e| format!("trace-addrs must look like 200-2FF: {}", e))?;
+            config.trace_filter.addrs = Some((first as usize, last as usize));
+        }
+        if let Some(range) = args.value_of("trace-window") {
+            config.trace_filter.window = Some(TraceFilter::parse_range(range, 10)
+                                                 .map_err(|e| format!("trace-window must look like 1000-2000: {}", e))?);
+        }
+        if let Some(interpreter) = args.value_of("vip-interpreter") {
+            vip::run(&load_interpreter(interpreter)?, &bin_file, &config)?;
+        } else {
+            // create and run chip-8 emulator
+            chip8::Chip8::run(&bin_file, &config)?;
+        }
     }
+    Ok(())
 }
 
-fn load_bin<P>(path: P) -> Vec<u8>
-    where P: AsRef<Path> + fmt::Debug
-{
-    let mut file = File::open(&path).expect("Could not open ROM file");
-    let mut buf = vec![];
-    file.read_to_end(&mut buf).expect("Could not read ROM file contents");
-    debug!("Read {} bytes from bin file {:?}", buf.len(), path);
-    buf
+// a menu of the roms in `dir`, named from the rom database where it knows them. Each rom
+// chosen runs in turn until the user quits the menu.
+fn pick_rom(args: &ArgMatches, dir: &Path) -> Result<(), String> {
+    let paths = rom::list_dir(dir).map_err(|e| format!("could not list {}: {}", dir.display(), e))?;
+    if paths.is_empty() {
+        return Err(format!("no roms in {}", dir.display()));
+    }
+    let db = load_rom_db(args.value_of("rom-db"));
+    let names: Vec<String> = paths.iter().map(|path| {
+        let file = path.file_name().expect("listed paths are files").to_string_lossy();
+        let title = rom::load(path).ok().and_then(|rom| db.lookup(&rom_db::rom_hash(&rom)));
+        match title {
+            Some(info) => format!("{}  ({})", info.title, file),
+            None => file.into_owned(),
+        }
+    }).collect();
+    let heading = format!("roms in {}:", dir.display());
+    while let Some(idx) = picker::choose(&heading, &names) {
+        // a rom that won't load shouldn't take the menu down with it
+        if let Err(e) = run_rom(args, &paths[idx].to_string_lossy()) {
+            error!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+fn assemble(args: &ArgMatches) {
+    let source = Path::new(args.value_of("SOURCE_FILE").unwrap());
+    let rom_path = args.value_of("output")
+                       .map(PathBuf::from)
+                       .unwrap_or_else(|| source.with_extension("ch8"));
+    let sym_path = args.value_of("symbols")
+                       .map(PathBuf::from)
+                       .unwrap_or_else(|| rom_path.with_extension("sym"));
+
+    let assembly = if is_octo(source) {
+        compile_octo(source).assembly
+    } else {
+        match assembler::assemble(source) {
+            Ok(assembly) => assembly,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    File::create(&rom_path)
+        .and_then(|mut f| f.write_all(&assembly.rom))
+        .expect("Could not write ROM file");
+    File::create(&sym_path)
+        .and_then(|mut f| assembly.write_symbols(&mut f))
+        .expect("Could not write symbol file");
+    info!("Assembled {} bytes to {:?}, symbols in {:?}", assembly.rom.len(), rom_path, sym_path);
+}
+
+fn diff_traces(args: &ArgMatches) {
+    let left = trace::read_trace(args.value_of("LEFT").unwrap()).expect("Could not read left trace");
+    let right = trace::read_trace(args.value_of("RIGHT").unwrap()).expect("Could not read right trace");
+    let context = args.value_of("context")
+                      .map(|c| c.parse().expect("context must be a number of instructions"))
+                      .unwrap_or(5);
+
+    let diff = trace_diff::TraceDiff::new(&left, &right);
+    match diff.report(context) {
+        Some(report) => {
+            print!("{}", report);
+            if args.is_present("all") {
+                println!("");
+                print!("{}", diff.summary());
+            }
+            std::process::exit(1);
+        }
+        None => println!("traces match ({} instructions)", left.len()),
+    }
+}
+
+fn debug_adapter(args: &ArgMatches) {
+    let port = args.value_of("port").map(|port| port.parse().expect("port must be a number"));
+    let mut server = dap::DapServer::connect(port).expect("Could not start debug adapter");
+    let launch = match server.wait_for_launch() {
+        Some(launch) => launch,
+        None => return,
+    };
+
+    // report problems with the rom to the client rather than exiting
+    let loaded = watch::load(&launch.program);
+    let (rom, source_map, mut symbols) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            server.launch_failed(&e);
+            return;
+        }
+    };
+    let program = launch.program.to_string_lossy().into_owned();
+    let sym_path = launch.symbols.as_ref().map(|path| path.to_string_lossy().into_owned());
+    symbols.merge(&load_symbols(&program, sym_path.as_ref().map(|path| path.as_str())));
+
+    let mut config = chip8::Config::default();
+    config.rom_path = Some(launch.program.clone());
+    apply_rom_db(&mut config, &load_rom_db(None), &rom);
+    config.headless = launch.headless;
+    config.source_map = source_map;
+    config.symbols = symbols;
+    if let Err(e) = chip8::Chip8::debug_adapter(&rom, &config, server) {
+        error!("{}", e);
+    }
+}
+
+// the database in `dir`, or in ~/.chip8-database if there is one; otherwise only the
+// bundled platforms
+fn load_rom_db(dir: Option<&str>) -> RomDb {
+    if let Some(dir) = dir {
+        return RomDb::load(dir).expect("Could not read rom database");
+    }
+    let dir = match env::var("HOME") {
+        Ok(home) => Path::new(&home).join(".chip8-database"),
+        Err(_) => return RomDb::bundled(),
+    };
+    if !dir.exists() {
+        return RomDb::bundled();
+    }
+    match RomDb::load(&dir) {
+        Ok(db) => db,
+        Err(e) => {
+            warn!("could not read rom database from {:?}: {}", dir, e);
+            RomDb::bundled()
+        }
+    }
+}
+
+fn apply_rom_db(config: &mut chip8::Config, db: &RomDb, rom: &[u8]) {
+    let info = match db.lookup(&rom_db::rom_hash(rom)) {
+        Some(info) => info,
+        None => return,
+    };
+    info!("{} for {}, at {} instructions per frame", info.title, info.platform, info.tickrate);
+    config.title = Some(info.title);
+    config.platform = Some(info.platform);
+    config.quirks = info.quirks;
+    config.tickrate = Some(info.tickrate);
+    config.keys = info.keys;
+    if let Some(palette) = info.palette {
+        config.palette = palette;
+    }
+}
+
+// symbols from the file given, or from a .sym file next to the rom if there is one
+fn load_symbols(rom_path: &str, sym_path: Option<&str>) -> Symbols {
+    let path = match sym_path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let path = Path::new(rom_path).with_extension("sym");
+            if !path.exists() {
+                return Symbols::new();
+            }
+            path
+        }
+    };
+    let symbols = Symbols::load(&path).expect("Could not read symbol file");
+    debug!("Read symbols from {:?}", path);
+    symbols
+}
+
+fn print_cfg(rom: &[u8], symbols: &Symbols, format: &str, call_graph: bool, jumps: Option<&str>) {
+    let dynamic = match jumps {
+        Some(path) => cfg::read_dynamic_edges(path).expect("Could not read jumps file"),
+        None => vec![],
+    };
+    let graph = cfg::Cfg::new(rom, &dynamic, symbols);
+    let out = match (format, call_graph) {
+        ("dot", false) => graph.cfg_dot(),
+        ("dot", true) => graph.call_graph_dot(),
+        (_, false) => graph.cfg_json(),
+        (_, true) => graph.call_graph_json(),
+    };
+    println!("{}", out.trim_right());
+}
+
+// the rom at `path`, compiling it first if it's octo source. `-` reads a rom from stdin, and
+// zip archives are searched for roms, asking which to run if there are several.
+fn load_program(path: &str) -> Result<(Vec<u8>, Option<SourceMap>, Symbols), String> {
+    let loaded = if path == "-" {
+        rom::read(io::stdin())
+    } else if is_zip(path) {
+        let names = rom::zip_roms(path).map_err(|e| format!("could not load {}: {}", path, e))?;
+        let idx = match names.len() {
+            1 => 0,
+            _ => match picker::choose(&format!("roms in {}:", path), &names) {
+                Some(idx) => idx,
+                None => return Err(format!("no rom chosen from {}", path)),
+            },
+        };
+        rom::load_zip(path, &names[idx])
+    } else {
+        return watch::load(path);
+    };
+    match loaded {
+        Ok(rom) => Ok((rom, None, Symbols::new())),
+        Err(e) => Err(format!("could not load {}: {}", path, e)),
+    }
+}
+
+// a COSMAC VIP CHIP-8 interpreter, which has to fit below the rom
+fn load_interpreter(path: &str) -> Result<Vec<u8>, String> {
+    let mut interpreter = vec![];
+    File::open(path).and_then(|mut f| f.read_to_end(&mut interpreter))
+                    .map_err(|e| format!("could not read the interpreter {}: {}", path, e))?;
+    if interpreter.is_empty() || interpreter.len() > 0x200 {
+        return Err(format!("{} is {} bytes; a VIP interpreter is at most 512", path, interpreter.len()));
+    }
+    Ok(interpreter)
+}
+
+fn is_zip<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().map(|ext| ext.to_string_lossy().to_lowercase() == "zip").unwrap_or(false)
+}
+
+fn is_octo<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().map(|ext| ext == "8o").unwrap_or(false)
+}
+
+fn compile_octo<P: AsRef<Path>>(path: P) -> octo::Compiled {
+    match octo::compile_file(path) {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -1,4 +1,8 @@
-const MEMORY_SIZE: usize = 4 * 1024;
+use cheats::Cheats;
+use coverage::Coverage;
+use rom::{RomError, MAX_ROM_SIZE};
+
+pub const MEMORY_SIZE: usize = 4 * 1024;
 pub const ROM_START: usize = 0x200;
 
 const FONT_START: usize = 0x050;
@@ -25,7 +29,14 @@ const FONT_SPRITES: [u8; 80] =
 ];
 
 pub struct MemoryBus {
-    mem: Box<[u8]>
+    mem: Box<[u8]>,
+    // memory as it was at the end of the last frame
+    last_frame: Box<[u8]>,
+    // every write as (address, value), while logging is turned on
+    writes: Option<Vec<(usize, u8)>>,
+    coverage: Option<Coverage>,
+    // values frozen at the end of every frame
+    cheats: Cheats,
 }
 
 impl MemoryBus {
@@ -44,19 +55,50 @@ impl MemoryBus {
             dst.copy_from_slice(&FONT_SPRITES);
         }
         MemoryBus {
+            last_frame: mem.clone(),
             mem: mem,
+            writes: None,
+            coverage: None,
+            cheats: Cheats::new(),
         }
     }
 
     pub fn read_word(&self, addr: usize) -> u8 {
+        if let Some(ref coverage) = self.coverage {
+            coverage.mark_read(addr);
+        }
+        self.peek_word(addr)
+    }
+
+    /// read a byte without it counting as a data read in the coverage
+    pub fn peek_word(&self, addr: usize) -> u8 {
         *self.mem.get(addr).expect("attempting to read from invalid memory address")
     }
 
+    /// all of memory, for a save state
+    pub fn contents(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /// write a byte without it being logged or counted as a data write
+    pub fn poke_word(&mut self, addr: usize, value: u8) {
+        match self.mem.get_mut(addr) {
+            Some(word) => *word = value,
+            None => panic!("attempting to write invalid memory address"),
+        }
+    }
+
     pub fn write_word(&mut self, addr: usize, value: u8) {
         match self.mem.get_mut(addr) {
             Some(word) => *word = value,
             None => panic!("attempting to write invalid memory address"),
         }
+        if let Some(ref mut writes) = self.writes {
+            writes.push((addr, value));
+        }
+        if let Some(ref coverage) = self.coverage {
+            coverage.mark_written(addr);
+        }
     }
 
     pub fn write_words(&mut self, addr: usize, src: &[u8]) {
@@ -67,6 +109,63 @@ impl MemoryBus {
         }
         let dst = &mut self.mem[start..end];
         dst.copy_from_slice(src);
+        if let Some(ref mut writes) = self.writes {
+            writes.extend(src.iter().enumerate().map(|(offset, &value)| (start + offset, value)));
+        }
+        if let Some(ref coverage) = self.coverage {
+            for addr in start..end {
+                coverage.mark_written(addr);
+            }
+        }
+    }
+
+    /// apply the cheats, then remember memory as it is now, for `changed`
+    pub fn end_frame(&mut self) {
+        for (&addr, &value) in &self.cheats {
+            self.mem[addr] = value;
+        }
+        self.last_frame.copy_from_slice(&self.mem);
+    }
+
+    /// freeze the byte at `addr` at `value`, starting now
+    pub fn set_cheat(&mut self, addr: usize, value: u8) {
+        self.poke_word(addr, value);
+        self.cheats.insert(addr, value);
+    }
+
+    pub fn remove_cheat(&mut self, addr: usize) -> bool {
+        self.cheats.remove(&addr).is_some()
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    /// true if the byte at `addr` has changed since the end of the last frame
+    pub fn changed(&self, addr: usize) -> bool {
+        self.mem.get(addr) != self.last_frame.get(addr)
+    }
+
+    /// start counting executions, reads and writes of each address into `coverage`
+    pub fn track_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// start or stop recording writes for `take_writes`
+    pub fn log_writes(&mut self, enabled: bool) {
+        self.writes = if enabled { Some(vec![]) } else { None };
+    }
+
+    /// the writes made since the last call, if logging is on
+    pub fn take_writes(&mut self) -> Vec<(usize, u8)> {
+        match self.writes {
+            Some(ref mut writes) => writes.drain(..).collect(),
+            None => vec![],
+        }
     }
 
     pub fn read_words(&self, addr: usize, len: usize) -> &[u8] {
@@ -75,6 +174,11 @@ impl MemoryBus {
         if end > self.mem.len() {
             panic!("attempting to read invalid memory address");
         }
+        if let Some(ref coverage) = self.coverage {
+            for addr in start..end {
+                coverage.mark_read(addr);
+            }
+        }
         &self.mem[start..end]
     }
 
@@ -92,10 +196,15 @@ impl MemoryBus {
         }
     }
 
-    pub fn load_rom(&mut self, rom: &[u8]) {
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), RomError> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(RomError::TooLarge { size: rom.len(), max: MAX_ROM_SIZE });
+        }
         let start = ROM_START;
         let end = start + rom.len();
         let dst = &mut self.mem[start..end];
         dst.copy_from_slice(rom);
+        self.end_frame();
+        Ok(())
     }
 }
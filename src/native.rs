@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+
+use cdp1802::{Bus, Cdp1802};
+use cpu::GP_REG_COUNT;
+use display::{Display, HEIGHT, WIDTH};
+use memory_bus::{MemoryBus, MEMORY_SIZE, ROM_START};
+use vip::INTERPRETER_RAM_START;
+
+// where the VIP's interpreter kept its stack, V0 to VF and display buffer, which routines
+// written for it expect to find there
+const VIP_STACK: u16 = 0xECF;
+const VIP_V_REGISTERS: usize = 0xEF0;
+const VIP_DISPLAY: usize = 0xF00;
+// the interpreter runs machine code with R3 as the program counter and gets control back
+// when it switches to R4 with SEP R4
+const ROUTINE_PC: usize = 3;
+const INTERPRETER_PC: usize = 4;
+// machine cycles a routine gets to return before it's assumed to be stuck, about 10 seconds
+const MAX_ROUTINE_CYCLES: u64 = 2_200_000;
+// a routine runs a frame's worth of machine cycles for each time its 0NNN is run, so the
+// emulator keeps drawing and polling the window while a long one works
+const SLICE_CYCLES: u64 = 3668;
+
+/// What a machine code routine called with 0NNN can get at
+pub struct Machine<'a> {
+    pub v: &'a mut [u8; GP_REG_COUNT],
+    pub i: &'a mut u16,
+    pub mem_bus: &'a mut MemoryBus,
+    pub display: &'a mut Display,
+}
+
+/// How far a call to a routine got
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    Returned,
+    // the routine isn't done; its 0NNN runs again to carry on with it
+    Running,
+}
+
+/// Runs the machine code routines roms call with 0NNN, which the original interpreter
+/// jumped straight into
+pub trait NativeHandler {
+    /// run the routine at `addr`, or say why it can't be run
+    fn call(&mut self, addr: usize, machine: &mut Machine) -> Result<Progress, String>;
+}
+
+/// How 0NNN calls are handled, before any routines are emulated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SysMode {
+    Ignore,
+    Log,
+    Cdp1802,
+}
+
+impl SysMode {
+    pub fn parse(name: &str) -> Option<SysMode> {
+        match name {
+            "ignore" => Some(SysMode::Ignore),
+            "log" => Some(SysMode::Log),
+            "1802" => Some(SysMode::Cdp1802),
+            _ => None,
+        }
+    }
+}
+
+/// the handler for `mode` and a rom `rom_len` bytes long, running `routines` natively in
+/// place of the machine code at their addresses
+pub fn handler(mode: SysMode, routines: &[(usize, Routine)], rom_len: usize) -> Box<NativeHandler> {
+    let fallback: Box<NativeHandler> = match mode {
+        SysMode::Ignore => Box::new(Ignore),
+        SysMode::Log => Box::new(Log::new()),
+        SysMode::Cdp1802 => Box::new(Native1802::new(rom_len)),
+    };
+    if routines.is_empty() {
+        return fallback;
+    }
+    Box::new(Emulate {
+        routines: routines.iter().cloned().collect(),
+        fallback: fallback,
+    })
+}
+
+/// Carries on as if every routine returned straight away
+pub struct Ignore;
+
+impl NativeHandler for Ignore {
+    fn call(&mut self, _addr: usize, _machine: &mut Machine) -> Result<Progress, String> {
+        Ok(Progress::Returned)
+    }
+}
+
+/// Carries on as if every routine returned straight away, warning about each one the first
+/// time it's called
+pub struct Log {
+    seen: HashSet<usize>,
+}
+
+impl Log {
+    pub fn new() -> Log {
+        Log { seen: HashSet::new() }
+    }
+}
+
+impl NativeHandler for Log {
+    fn call(&mut self, addr: usize, _machine: &mut Machine) -> Result<Progress, String> {
+        if self.seen.insert(addr) {
+            warn!("skipping the machine code routine at {:03X}", addr);
+        }
+        Ok(Progress::Returned)
+    }
+}
+
+/// Routines that can be emulated without running their machine code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Routine {
+    // returns straight away, like a lone SEP R4
+    Return,
+    ClearScreen,
+}
+
+impl Routine {
+    pub fn parse(name: &str) -> Option<Routine> {
+        match name {
+            "ret" => Some(Routine::Return),
+            "cls" => Some(Routine::ClearScreen),
+            _ => None,
+        }
+    }
+
+    /// a routine for `ADDR=NAME`, with the address in hex
+    pub fn parse_binding(binding: &str) -> Option<(usize, Routine)> {
+        let mut parts = binding.splitn(2, '=');
+        let addr = parts.next().and_then(|addr| usize::from_str_radix(addr.trim(), 16).ok());
+        let routine = parts.next().and_then(|name| Routine::parse(name.trim()));
+        match (addr, routine) {
+            (Some(addr), Some(routine)) if addr < MEMORY_SIZE => Some((addr, routine)),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the routines it knows by address natively and hands the rest on
+pub struct Emulate {
+    routines: HashMap<usize, Routine>,
+    fallback: Box<NativeHandler>,
+}
+
+impl NativeHandler for Emulate {
+    fn call(&mut self, addr: usize, machine: &mut Machine) -> Result<Progress, String> {
+        match self.routines.get(&addr) {
+            Some(&Routine::Return) => Ok(Progress::Returned),
+            Some(&Routine::ClearScreen) => {
+                machine.display.clear();
+                Ok(Progress::Returned)
+            }
+            None => self.fallback.call(addr, machine),
+        }
+    }
+}
+
+/// Runs routines on an 1802, with V0 to VF, I and the display where the VIP's interpreter
+/// kept them for the routine to use. That memory is above E9F, so a rom reaching that far
+/// can't have its routines run.
+pub struct Native1802 {
+    rom_end: usize,
+    // the routine part way through: where it was called, the 1802 and the cycles it's run
+    running: Option<(usize, Cdp1802, u64)>,
+}
+
+impl Native1802 {
+    pub fn new(rom_len: usize) -> Native1802 {
+        Native1802 {
+            rom_end: ROM_START + rom_len,
+            running: None,
+        }
+    }
+
+    // an 1802 about to run the routine at `addr`, as the interpreter would have left it
+    fn start(&self, addr: usize, machine: &mut Machine) -> Result<Cdp1802, String> {
+        if self.rom_end > INTERPRETER_RAM_START {
+            return Err(format!("the rom runs past {:03X}, into the memory the routine at {:03X} expects the \
+                                interpreter to have", INTERPRETER_RAM_START - 1, addr));
+        }
+        // copied in as the interpreter would have them, not as writes the rom made
+        for (offset, &value) in machine.v.iter().enumerate() {
+            machine.mem_bus.poke_word(VIP_V_REGISTERS + offset, value);
+        }
+        let mut buffer = vec![0; WIDTH * HEIGHT / 8];
+        for (idx, &pixel) in machine.display.grid().iter().enumerate() {
+            buffer[idx / 8] |= pixel << (7 - idx % 8);
+        }
+        for (offset, &byte) in buffer.iter().enumerate() {
+            machine.mem_bus.poke_word(VIP_DISPLAY + offset, byte);
+        }
+
+        let mut cpu = Cdp1802::new();
+        cpu.set_register(2, VIP_STACK);
+        cpu.set_register(ROUTINE_PC, addr as u16);
+        cpu.set_register(6, VIP_V_REGISTERS as u16);
+        cpu.set_register(0xA, *machine.i);
+        cpu.set_register(0xB, VIP_DISPLAY as u16);
+        cpu.select(ROUTINE_PC, 2);
+        Ok(cpu)
+    }
+}
+
+struct MachineBus<'a, 'b: 'a> {
+    machine: &'a mut Machine<'b>,
+}
+
+impl<'a, 'b> Bus for MachineBus<'a, 'b> {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.machine.mem_bus.read_word(addr as usize % MEMORY_SIZE)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.machine.mem_bus.write_word(addr as usize % MEMORY_SIZE, value);
+    }
+
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    fn flag(&mut self, _flag: usize) -> bool {
+        false
+    }
+
+    fn set_q(&mut self, _on: bool) {}
+}
+
+impl NativeHandler for Native1802 {
+    fn call(&mut self, addr: usize, machine: &mut Machine) -> Result<Progress, String> {
+        let (mut cpu, mut cycles) = match self.running.take() {
+            Some((running, cpu, cycles)) if running == addr => (cpu, cycles),
+            _ => (self.start(addr, machine)?, 0),
+        };
+        {
+            let mut bus = MachineBus { machine: &mut *machine };
+            let slice_end = cycles + SLICE_CYCLES;
+            while cpu.p() != INTERPRETER_PC && cycles < slice_end {
+                cycles += cpu.step(&mut bus) as u64;
+            }
+        }
+
+        // the display is shown as the routine goes, for routines that animate
+        let mut grid = vec![0; WIDTH * HEIGHT];
+        for (idx, pixel) in grid.iter_mut().enumerate() {
+            *pixel = (machine.mem_bus.peek_word(VIP_DISPLAY + idx / 8) >> (7 - idx % 8)) & 1;
+        }
+        if grid[..] != *machine.display.grid() {
+            machine.display.set_grid(&grid);
+        }
+        if cpu.p() != INTERPRETER_PC {
+            if cycles >= MAX_ROUTINE_CYCLES {
+                return Err(format!("the routine at {:03X} didn't return with SEP R4", addr));
+            }
+            self.running = Some((addr, cpu, cycles));
+            return Ok(Progress::Running);
+        }
+
+        for (offset, v) in machine.v.iter_mut().enumerate() {
+            *v = machine.mem_bus.peek_word(VIP_V_REGISTERS + offset);
+        }
+        *machine.i = cpu.register(0xA) & 0xFFF;
+        Ok(Progress::Returned)
+    }
+}
@@ -0,0 +1,910 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use assembler::{AsmError, Assembly, parse_number};
+use memory_bus::{MEMORY_SIZE, ROM_START};
+use source_map::SourceMap;
+
+// how deeply macros may expand inside each other before one is taken to expand itself
+const MAX_MACRO_DEPTH: usize = 256;
+
+/// A compiled Octo program
+pub struct Compiled {
+    pub assembly: Assembly,
+    pub source_map: SourceMap,
+}
+
+/// Compile an Octo (`.8o`) source file.
+///
+/// Supports labels (`: name`), `:const`, `:alias`, `:unpack`, `:next`, `:org`, `:byte`,
+/// `:call`, `:macro`, `:calc`, the register and `i` assignment statements, `if ... then`,
+/// `if ... begin ... else ... end`, `loop ... while ... again`, and bare numbers as data.
+/// As with Octo, execution starts with a jump to the `main` label.
+pub fn compile_file<P: AsRef<Path>>(path: P) -> Result<Compiled, AsmError> {
+    let path = path.as_ref();
+    let mut source = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut source)).map_err(|e| AsmError {
+        file: path.to_owned(),
+        line: 0,
+        message: format!("could not read {}: {}", path.display(), e),
+    })?;
+    compile(path, &source)
+}
+
+pub fn compile(path: &Path, source: &str) -> Result<Compiled, AsmError> {
+    let mut compiler = Compiler {
+        file: path.to_owned(),
+        tokens: tokenize(source),
+        mem: vec![0; MEMORY_SIZE],
+        here: ROM_START,
+        end: ROM_START,
+        // no line yet, so the jump to main isn't attributed to any source
+        line: 0,
+        depth: 0,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: vec![],
+        branches: vec![],
+        loops: vec![],
+        source_map: SourceMap::new(path, source),
+    };
+
+    // execution starts at main, wherever it ends up
+    compiler.emit_addr(0x1000, "main")?;
+    while !compiler.tokens.is_empty() {
+        compiler.statement()?;
+    }
+    compiler.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    // how many macro expansions produced it
+    depth: usize,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (idx, line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        let code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        for text in code.split_whitespace() {
+            tokens.push_back(Token { text: text.to_owned(), line: line_no, depth: 0 });
+        }
+    }
+    tokens
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+// how an unresolved label is patched into the program once it's defined
+#[derive(Clone, Copy, Debug)]
+enum FixupKind {
+    // the low 12 bits of an instruction
+    Nnn,
+    // `:unpack` high byte: nibble in the top 4 bits, address bits 8-11 below
+    UnpackHi(u8),
+    // `:unpack` low byte
+    UnpackLo,
+}
+
+struct Fixup {
+    addr: usize,
+    kind: FixupKind,
+    label: String,
+    line: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Key,
+    NotKey,
+}
+
+impl Cmp {
+    fn invert(self) -> Cmp {
+        match self {
+            Cmp::Eq => Cmp::Ne,
+            Cmp::Ne => Cmp::Eq,
+            Cmp::Lt => Cmp::Ge,
+            Cmp::Ge => Cmp::Lt,
+            Cmp::Gt => Cmp::Le,
+            Cmp::Le => Cmp::Gt,
+            Cmp::Key => Cmp::NotKey,
+            Cmp::NotKey => Cmp::Key,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    Reg(usize),
+    Num(u8),
+}
+
+struct Cond {
+    reg: usize,
+    cmp: Cmp,
+    rhs: Option<Operand>,
+}
+
+struct Compiler {
+    file: PathBuf,
+    tokens: VecDeque<Token>,
+    mem: Vec<u8>,
+    // address of the next byte
+    here: usize,
+    // one past the highest address written
+    end: usize,
+    // line of the statement being compiled
+    line: usize,
+    // macro depth of the last token read
+    depth: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, usize>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    // addresses of jumps emitted by `if ... begin` and `else`, waiting for their target
+    branches: Vec<(usize, usize)>,
+    // open loops: start address and the jumps emitted by `while` that exit the loop
+    loops: Vec<(usize, Vec<usize>, usize)>,
+    source_map: SourceMap,
+}
+
+impl Compiler {
+    fn error<S: Into<String>>(&self, message: S) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Result<String, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                self.depth = token.depth;
+                Ok(token.text)
+            }
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), AsmError> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(self.error(format!("expected '{}' but found '{}'", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), AsmError> {
+        if self.here >= MEMORY_SIZE {
+            return Err(self.error("program does not fit in memory"));
+        }
+        self.mem[self.here] = byte;
+        self.here += 1;
+        if self.here > self.end {
+            self.end = self.here;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, instr: u16) -> Result<(), AsmError> {
+        let (addr, line) = (self.here, self.line);
+        if line > 0 {
+            self.source_map.insert(addr, line);
+        }
+        self.emit_byte((instr >> 8) as u8)?;
+        self.emit_byte(instr as u8)
+    }
+
+    /// emit an instruction whose low 12 bits are the address of `label`
+    fn emit_addr(&mut self, op: u16, label: &str) -> Result<(), AsmError> {
+        match self.labels.get(label).cloned() {
+            Some(addr) => self.emit(op | addr as u16),
+            None => {
+                self.fixups.push(Fixup { addr: self.here, kind: FixupKind::Nnn, label: label.to_owned(), line: self.line });
+                self.emit(op)
+            }
+        }
+    }
+
+    /// emit an instruction taking an address that is either a number, a constant or a label
+    fn emit_target(&mut self, op: u16, token: &str) -> Result<(), AsmError> {
+        match self.number(token) {
+            Some(addr) => {
+                let addr = self.check_addr(addr)?;
+                self.emit(op | addr)
+            }
+            None if is_name(token) => self.emit_addr(op, token),
+            None => Err(self.error(format!("expected an address but found '{}'", token))),
+        }
+    }
+
+    // patch a previously emitted jump to go to `target`
+    fn patch(&mut self, addr: usize, target: usize) {
+        self.mem[addr] = (self.mem[addr] & 0xF0) | ((target >> 8) as u8 & 0x0F);
+        self.mem[addr + 1] = target as u8;
+    }
+
+    fn check_addr(&self, value: i64) -> Result<u16, AsmError> {
+        if value < 0 || value > 0xFFF {
+            return Err(self.error(format!("address {} is out of range", value)));
+        }
+        Ok(value as u16)
+    }
+
+    fn number(&self, token: &str) -> Option<i64> {
+        if let Some(value) = self.constants.get(token) {
+            return Some(*value);
+        }
+        if let Some(addr) = self.labels.get(token) {
+            return Some(*addr as i64);
+        }
+        let starts_numeric = token.chars().next().map(|c| c.is_digit(10) || c == '-').unwrap_or(false);
+        if !starts_numeric {
+            return None;
+        }
+        if token.starts_with('-') {
+            return parse_number(&token[1..]).ok().map(|n| -n);
+        }
+        parse_number(token).ok()
+    }
+
+    fn byte_value(&mut self, token: &str) -> Result<u8, AsmError> {
+        match self.number(token) {
+            Some(n) if n >= -128 && n <= 255 => Ok(n as u8),
+            Some(n) => Err(self.error(format!("{} does not fit in a byte", n))),
+            None => Err(self.error(format!("expected a number but found '{}'", token))),
+        }
+    }
+
+    fn register(&self, token: &str) -> Option<usize> {
+        if let Some(reg) = self.aliases.get(token) {
+            return Some(*reg);
+        }
+        let lower = token.to_lowercase();
+        let mut chars = lower.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(c), None) => c.to_digit(16).map(|x| x as usize),
+            _ => None,
+        }
+    }
+
+    fn expect_register(&mut self) -> Result<usize, AsmError> {
+        let token = self.next()?;
+        match self.register(&token) {
+            Some(reg) => Ok(reg),
+            None => Err(self.error(format!("expected a register but found '{}'", token))),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, AsmError> {
+        let token = self.next()?;
+        match self.register(&token) {
+            Some(reg) => Ok(Operand::Reg(reg)),
+            None => self.byte_value(&token).map(Operand::Num),
+        }
+    }
+
+    fn define_label(&mut self, name: String, addr: usize) -> Result<(), AsmError> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return Err(self.error(format!("{} is already defined", name)));
+        }
+        self.labels.insert(name, addr);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let token = self.next()?;
+        match &*token {
+            ":" => {
+                let name = self.next()?;
+                let here = self.here;
+                self.define_label(name, here)
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                match self.number(&value) {
+                    Some(value) => {
+                        self.constants.insert(name, value);
+                        Ok(())
+                    }
+                    None => Err(self.error(format!("constant value '{}' is not known", value))),
+                }
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let reg = self.expect_register()?;
+                self.aliases.insert(name, reg);
+                Ok(())
+            }
+            ":unpack" => {
+                let nibble = self.next()?;
+                let nibble = self.byte_value(&nibble)? & 0xF;
+                let label = self.next()?;
+                self.unpack(nibble, &label)
+            }
+            ":next" => {
+                // names the immediate operand of the following instruction
+                let name = self.next()?;
+                let addr = self.here + 1;
+                self.define_label(name, addr)
+            }
+            ":org" => {
+                let addr = self.next()?;
+                match self.number(&addr) {
+                    Some(addr) if addr >= ROM_START as i64 && addr < MEMORY_SIZE as i64 => {
+                        self.here = addr as usize;
+                        Ok(())
+                    }
+                    _ => Err(self.error(format!("invalid :org address '{}'", addr))),
+                }
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.calc()?
+                } else {
+                    let token = self.next()?;
+                    self.byte_value(&token)? as i64
+                };
+                self.emit_byte(value as u8)
+            }
+            ":call" => {
+                let target = self.next()?;
+                self.emit_target(0x2000, &target)
+            }
+            ":macro" => self.define_macro(),
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":breakpoint" => {
+                self.next()?;
+                Ok(())
+            }
+            "clear" => self.emit(0x00E0),
+            "return" | ";" => self.emit(0x00EE),
+            "bcd" => self.reg_op(0xF033),
+            "save" => self.reg_op(0xF055),
+            "load" => self.reg_op(0xF065),
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let n = self.next()?;
+                let n = self.byte_value(&n)?;
+                if n > 0xF {
+                    return Err(self.error("sprite height must be between 0 and 15"));
+                }
+                self.emit(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n as u16)
+            }
+            "jump" => {
+                let target = self.next()?;
+                self.emit_target(0x1000, &target)
+            }
+            "jump0" => {
+                let target = self.next()?;
+                self.emit_target(0xB000, &target)
+            }
+            "native" => {
+                let target = self.next()?;
+                self.emit_target(0x0000, &target)
+            }
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                self.emit(if token == "delay" { 0xF015 } else { 0xF018 } | x << 8)
+            }
+            "i" => self.i_statement(),
+            "if" => self.if_statement(),
+            "else" => {
+                let (jump, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error("else without if ... begin")),
+                };
+                let else_jump = self.here;
+                let line = self.line;
+                self.emit(0x1000)?;
+                let here = self.here;
+                self.patch(jump, here);
+                self.branches.push((else_jump, line));
+                Ok(())
+            }
+            "end" => {
+                let (jump, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error("end without if ... begin")),
+                };
+                let here = self.here;
+                self.patch(jump, here);
+                Ok(())
+            }
+            "loop" => {
+                let (here, line) = (self.here, self.line);
+                self.loops.push((here, vec![], line));
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error("while outside of a loop"));
+                }
+                let cond = self.condition()?;
+                // skip the exit jump while the condition holds
+                self.emit_skip_unless(&Cond { reg: cond.reg, cmp: cond.cmp.invert(), rhs: cond.rhs })?;
+                let exit = self.here;
+                self.emit(0x1000)?;
+                self.loops.last_mut().expect("checked above").1.push(exit);
+                Ok(())
+            }
+            "again" => {
+                let (start, exits, _) = match self.loops.pop() {
+                    Some(open) => open,
+                    None => return Err(self.error("again without loop")),
+                };
+                self.emit(0x1000 | start as u16)?;
+                let here = self.here;
+                for exit in exits {
+                    self.patch(exit, here);
+                }
+                Ok(())
+            }
+            _ => {
+                if let Some(x) = self.register(&token) {
+                    return self.register_statement(x);
+                }
+                if let Some(value) = self.number(&token) {
+                    if !self.labels.contains_key(&token) {
+                        // bare numbers and constants are data
+                        return match value {
+                            -128...255 => self.emit_byte(value as u8),
+                            _ => Err(self.error(format!("{} does not fit in a byte", value))),
+                        };
+                    }
+                }
+                if self.macros.contains_key(&token) {
+                    return self.expand_macro(&token);
+                }
+                if is_name(&token) {
+                    // a bare label is a subroutine call
+                    return self.emit_addr(0x2000, &token);
+                }
+                Err(self.error(format!("unexpected '{}'", token)))
+            }
+        }
+    }
+
+    fn reg_op(&mut self, op: u16) -> Result<(), AsmError> {
+        let x = self.expect_register()? as u16;
+        self.emit(op | x << 8)
+    }
+
+    fn unpack(&mut self, nibble: u8, label: &str) -> Result<(), AsmError> {
+        let (hi, lo) = match self.labels.get(label).cloned() {
+            Some(addr) => ((nibble << 4) | (addr >> 8) as u8, addr as u8),
+            None => {
+                let line = self.line;
+                // v0 := hi is emitted at here, v1 := lo two bytes later
+                self.fixups.push(Fixup { addr: self.here, kind: FixupKind::UnpackHi(nibble), label: label.to_owned(), line: line });
+                self.fixups.push(Fixup { addr: self.here + 2, kind: FixupKind::UnpackLo, label: label.to_owned(), line: line });
+                (nibble << 4, 0)
+            }
+        };
+        self.emit(0x6000 | hi as u16)?;
+        self.emit(0x6100 | lo as u16)
+    }
+
+    fn i_statement(&mut self) -> Result<(), AsmError> {
+        let op = self.next()?;
+        match &*op {
+            ":=" => {
+                let value = self.next()?;
+                if value == "hex" {
+                    return self.reg_op(0xF029);
+                }
+                self.emit_target(0xA000, &value)
+            }
+            "+=" => self.reg_op(0xF01E),
+            _ => Err(self.error(format!("unknown operator 'i {}'", op))),
+        }
+    }
+
+    fn register_statement(&mut self, x: usize) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let xy = |op: u16, y: usize| 0x8000 | (x as u16) << 8 | (y as u16) << 4 | op;
+        let x16 = (x as u16) << 8;
+        match &*op {
+            ":=" => {
+                match self.peek() {
+                    Some("random") => {
+                        self.next()?;
+                        let mask = self.next()?;
+                        let mask = self.byte_value(&mask)?;
+                        return self.emit(0xC000 | x16 | mask as u16);
+                    }
+                    Some("key") => {
+                        self.next()?;
+                        return self.emit(0xF00A | x16);
+                    }
+                    Some("delay") => {
+                        self.next()?;
+                        return self.emit(0xF007 | x16);
+                    }
+                    _ => (),
+                }
+                match self.operand()? {
+                    Operand::Reg(y) => self.emit(xy(0x0, y)),
+                    Operand::Num(nn) => self.emit(0x6000 | x16 | nn as u16),
+                }
+            }
+            "+=" => match self.operand()? {
+                Operand::Reg(y) => self.emit(xy(0x4, y)),
+                Operand::Num(nn) => self.emit(0x7000 | x16 | nn as u16),
+            },
+            "-=" => match self.operand()? {
+                Operand::Reg(y) => self.emit(xy(0x5, y)),
+                Operand::Num(nn) => self.emit(0x7000 | x16 | nn.wrapping_neg() as u16),
+            },
+            "=-" => {
+                let y = self.expect_register()?;
+                self.emit(xy(0x7, y))
+            }
+            "|=" | "&=" | "^=" | ">>=" | "<<=" => {
+                let y = self.expect_register()?;
+                let n = match &*op {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    ">>=" => 0x6,
+                    _ => 0xE,
+                };
+                self.emit(xy(n, y))
+            }
+            _ => Err(self.error(format!("unknown operator '{}'", op))),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Cond, AsmError> {
+        let reg = self.expect_register()?;
+        let op = self.next()?;
+        let cmp = match &*op {
+            "==" => Cmp::Eq,
+            "!=" => Cmp::Ne,
+            "<" => Cmp::Lt,
+            ">" => Cmp::Gt,
+            "<=" => Cmp::Le,
+            ">=" => Cmp::Ge,
+            "key" => return Ok(Cond { reg: reg, cmp: Cmp::Key, rhs: None }),
+            "-key" => return Ok(Cond { reg: reg, cmp: Cmp::NotKey, rhs: None }),
+            _ => return Err(self.error(format!("unknown comparison '{}'", op))),
+        };
+        let rhs = self.operand()?;
+        Ok(Cond { reg: reg, cmp: cmp, rhs: Some(rhs) })
+    }
+
+    /// emit code that skips the next instruction unless `cond` holds
+    fn emit_skip_unless(&mut self, cond: &Cond) -> Result<(), AsmError> {
+        let x16 = (cond.reg as u16) << 8;
+        match (cond.cmp, cond.rhs) {
+            (Cmp::Key, _) => self.emit(0xE0A1 | x16),
+            (Cmp::NotKey, _) => self.emit(0xE09E | x16),
+            (Cmp::Eq, Some(Operand::Num(nn))) => self.emit(0x4000 | x16 | nn as u16),
+            (Cmp::Eq, Some(Operand::Reg(y))) => self.emit(0x9000 | x16 | (y as u16) << 4),
+            (Cmp::Ne, Some(Operand::Num(nn))) => self.emit(0x3000 | x16 | nn as u16),
+            (Cmp::Ne, Some(Operand::Reg(y))) => self.emit(0x5000 | x16 | (y as u16) << 4),
+            (cmp, Some(rhs)) => {
+                // compute a >= b into vf, then test it
+                let (a, b) = match cmp {
+                    Cmp::Lt | Cmp::Ge => (Operand::Reg(cond.reg), rhs),
+                    _ => (rhs, Operand::Reg(cond.reg)),
+                };
+                match (a, b) {
+                    (Operand::Reg(a), Operand::Reg(b)) => {
+                        self.emit(0x8F00 | (a as u16) << 4)?;
+                        self.emit(0x8F05 | (b as u16) << 4)?;
+                    }
+                    (Operand::Reg(a), Operand::Num(nn)) => {
+                        // vf =- a, so vf ends up as the no-borrow flag of a - nn
+                        self.emit(0x6F00 | nn as u16)?;
+                        self.emit(0x8F07 | (a as u16) << 4)?;
+                    }
+                    (Operand::Num(nn), Operand::Reg(b)) => {
+                        self.emit(0x6F00 | nn as u16)?;
+                        self.emit(0x8F05 | (b as u16) << 4)?;
+                    }
+                    (Operand::Num(_), Operand::Num(_)) => unreachable!(),
+                }
+                // vf is 1 when a >= b
+                let want = match cmp {
+                    Cmp::Ge | Cmp::Le => 1,
+                    _ => 0,
+                };
+                self.emit(0x4F00 | want)
+            }
+            (_, None) => unreachable!(),
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<(), AsmError> {
+        let cond = self.condition()?;
+        let form = self.next()?;
+        match &*form {
+            "then" => self.emit_skip_unless(&cond),
+            "begin" => {
+                // jump past the block unless the condition holds
+                self.emit_skip_unless(&Cond { reg: cond.reg, cmp: cond.cmp.invert(), rhs: cond.rhs })?;
+                let (jump, line) = (self.here, self.line);
+                self.emit(0x1000)?;
+                self.branches.push((jump, line));
+                Ok(())
+            }
+            _ => Err(self.error(format!("expected 'then' or 'begin' but found '{}'", form))),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.next()?;
+        let mut args = vec![];
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            args.push(token);
+        }
+        let body = self.braced_tokens()?;
+        self.macros.insert(name, Macro { args: args, body: body });
+        Ok(())
+    }
+
+    // tokens up to the `}` matching an already consumed `{`
+    fn braced_tokens(&mut self) -> Result<Vec<Token>, AsmError> {
+        let mut depth = 1;
+        let mut body = vec![];
+        loop {
+            let token = match self.tokens.pop_front() {
+                Some(token) => token,
+                None => return Err(self.error("missing }")),
+            };
+            match &*token.text {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => (),
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), AsmError> {
+        let depth = self.depth + 1;
+        if depth > MAX_MACRO_DEPTH {
+            return Err(self.error(format!("macro {} expands itself", name)));
+        }
+        let arg_count = self.macros[name].args.len();
+        let mut values = HashMap::new();
+        for idx in 0..arg_count {
+            let value = self.next()?;
+            values.insert(self.macros[name].args[idx].clone(), value);
+        }
+        let expansion: Vec<Token> = self.macros[name].body.iter().map(|token| {
+            let text = values.get(&token.text).unwrap_or(&token.text).clone();
+            Token { text: text, line: token.line, depth: depth }
+        }).collect();
+        for token in expansion.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    /// evaluate a `{ ... }` expression. As in Octo, operators have no precedence and are
+    /// evaluated right to left; use parentheses to group.
+    fn calc(&mut self) -> Result<i64, AsmError> {
+        self.expect("{")?;
+        let tokens = self.braced_tokens()?;
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(self.error(format!("unexpected '{}' in expression", tokens[pos].text)));
+        }
+        Ok(value)
+    }
+
+    fn calc_expr(&self, tokens: &[Token], pos: &mut usize) -> Result<i64, AsmError> {
+        let lhs = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token.text.clone(),
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.calc_expr(tokens, pos)?;
+        let value = match &*op {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" | "%" if rhs == 0 => return Err(self.error("division by zero")),
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => lhs & rhs,
+            "|" => lhs | rhs,
+            "^" => lhs ^ rhs,
+            "<<" => lhs << (rhs & 63),
+            ">>" => lhs >> (rhs & 63),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => (lhs < rhs) as i64,
+            ">" => (lhs > rhs) as i64,
+            "<=" => (lhs <= rhs) as i64,
+            ">=" => (lhs >= rhs) as i64,
+            "==" => (lhs == rhs) as i64,
+            "!=" => (lhs != rhs) as i64,
+            _ => return Err(self.error(format!("unknown operator '{}'", op))),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<i64, AsmError> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token.text.clone(),
+            None => return Err(self.error("expression ended unexpectedly")),
+        };
+        *pos += 1;
+        match &*token {
+            "(" => {
+                let value = self.calc_expr(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(token) if token.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error("missing )")),
+                }
+            }
+            "-" => self.calc_term(tokens, pos).map(|n| -n),
+            "~" => self.calc_term(tokens, pos).map(|n| !n),
+            "!" => self.calc_term(tokens, pos).map(|n| (n == 0) as i64),
+            "HERE" => Ok(self.here as i64),
+            _ => match self.number(&token) {
+                Some(value) => Ok(value),
+                None => Err(self.error(format!("'{}' is not a known value", token))),
+            },
+        }
+    }
+
+    fn finish(mut self) -> Result<Compiled, AsmError> {
+        if let Some(&(_, line)) = self.branches.last() {
+            self.line = line;
+            return Err(self.error("if ... begin without end"));
+        }
+        if let Some(&(_, _, line)) = self.loops.last() {
+            self.line = line;
+            return Err(self.error("loop without again"));
+        }
+
+        let fixups: Vec<Fixup> = self.fixups.drain(..).collect();
+        for fixup in fixups {
+            self.line = fixup.line;
+            let target = match self.labels.get(&fixup.label) {
+                Some(addr) => *addr,
+                None => return Err(self.error(format!("undefined label {}", fixup.label))),
+            };
+            match fixup.kind {
+                FixupKind::Nnn => self.patch(fixup.addr, target),
+                FixupKind::UnpackHi(nibble) => self.mem[fixup.addr + 1] = (nibble << 4) | (target >> 8) as u8,
+                FixupKind::UnpackLo => self.mem[fixup.addr + 1] = target as u8,
+            }
+        }
+
+        let mut labels: Vec<(usize, String)> = self.labels.iter()
+                                                          .map(|(name, addr)| (*addr, name.clone()))
+                                                          .collect();
+        labels.sort();
+        Ok(Compiled {
+            assembly: Assembly {
+                rom: self.mem[ROM_START..self.end].to_vec(),
+                labels: labels,
+            },
+            source_map: self.source_map,
+        })
+    }
+}
+
+fn is_name(token: &str) -> bool {
+    let mut chars = token.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::compile;
+    use cpu::Cpu;
+    use display::Display;
+    use keyboard::Keyboard;
+    use memory_bus::MemoryBus;
+    use timer::Timer;
+
+    // run `source` until it hits the 0A00 end marker, returning V0 to VF
+    fn run(source: &str) -> [u8; 16] {
+        let compiled = compile(Path::new("test.8o"), source).expect("source should compile");
+        let mut mem_bus = MemoryBus::new();
+        mem_bus.load_rom(&compiled.assembly.rom).expect("rom should load");
+        let mut cpu = Cpu::new();
+        let mut display = Display::new(None);
+        let mut keyboard = Keyboard::new(None);
+        let (mut delay_timer, mut sound_timer) = (Timer::new(), Timer::new());
+        for _ in 0..100 {
+            if cpu.should_exit() {
+                return cpu.registers();
+            }
+            cpu.execute_instruction(&mut mem_bus, &mut display, &mut keyboard, &mut delay_timer, &mut sound_timer);
+        }
+        panic!("program didn't reach the end marker");
+    }
+
+    // v1 is 1 if `cond` held with v0 set to `v0` and v2 to 5
+    fn holds(cond: &str, v0: u8) -> bool {
+        let source = format!(": main v0 := {} v2 := 5 v1 := 0 if {} then v1 := 1 0x0A 0x00", v0, cond);
+        run(&source)[1] == 1
+    }
+
+    #[test]
+    fn relational_branches() {
+        for &v0 in &[0, 4, 5, 6, 255] {
+            assert_eq!(holds("v0 < 5", v0), v0 < 5, "v0 < 5 with v0 = {}", v0);
+            assert_eq!(holds("v0 > 5", v0), v0 > 5, "v0 > 5 with v0 = {}", v0);
+            assert_eq!(holds("v0 <= 5", v0), v0 <= 5, "v0 <= 5 with v0 = {}", v0);
+            assert_eq!(holds("v0 >= 5", v0), v0 >= 5, "v0 >= 5 with v0 = {}", v0);
+            assert_eq!(holds("v0 < v2", v0), v0 < 5, "v0 < v2 with v0 = {}", v0);
+            assert_eq!(holds("v0 >= v2", v0), v0 >= 5, "v0 >= v2 with v0 = {}", v0);
+        }
+    }
+
+    #[test]
+    fn recursive_macros() {
+        let v = run(":macro inc r { r += 1 } :macro inc2 r { inc r inc r } : main inc2 v3 0x0A 0x00");
+        assert_eq!(v[3], 2);
+        for source in &[":macro m { m } : main m", ":macro m { m m } : main m", ":macro a { b } :macro b { a } : main a"] {
+            match compile(Path::new("test.8o"), source) {
+                Ok(_) => panic!("{} should not compile", source),
+                Err(e) => assert!(e.message.ends_with("expands itself"), "{}: {}", source, e.message),
+            }
+        }
+    }
+}
@@ -6,6 +6,7 @@ pub const OP_SIZE: usize = 2;
 #[derive(Clone, Copy, Debug)]
 pub enum OpCode {
     Eof,                                 // 0x0A00
+    Sys { nnn: usize },                  // 0x0NNN
     DrawClr,                             // 0x00E0
     Return,                              // 0x00EE
     JpConst { nnn: usize },              // 0x1NNN
@@ -21,9 +22,9 @@ pub enum OpCode {
     SetRegBxor { x: usize, y: usize },   // 0x8XY3
     SetRegAdd { x: usize, y: usize },    // 0x8XY4
     SetRegSub { x: usize, y: usize },    // 0x8XY5
-    SetShr1 { x: usize },                // 0x8XY6
+    SetShr1 { x: usize, y: usize },      // 0x8XY6
     SetRegRevSub { x: usize, y: usize }, // 0x8XY7
-    SetShl1 { x: usize },                // 0x8XYE
+    SetShl1 { x: usize, y: usize },      // 0x8XYE
     JpRegNe { x: usize, y: usize },      // 0x9XY0
     SetI { nnn: u16 },                   // 0xANNN
     JpOffset { nnn: usize },             // 0xBNNN
@@ -60,7 +61,7 @@ impl From<u16> for OpCode {
                     0x0A00 => Eof,
                     0x00E0 => DrawClr,
                     0x00EE => Return,
-                    _ => Unknown(instr),
+                    _ => Sys { nnn: nnn },
                 }
             }
             0x1 => JpConst { nnn: nnn },
@@ -79,9 +80,9 @@ impl From<u16> for OpCode {
                     0x3 => SetRegBxor { x: x, y: y },
                     0x4 => SetRegAdd { x: x, y: y },
                     0x5 => SetRegSub { x: x, y: y },
-                    0x6 => SetShr1 { x: x },
+                    0x6 => SetShr1 { x: x, y: y },
                     0x7 => SetRegRevSub { x: x, y: y },
-                    0xE => SetShl1 { x: x },
+                    0xE => SetShl1 { x: x, y: y },
                     _ => Unknown(instr),
                 }
             }
@@ -118,10 +119,109 @@ impl From<u16> for OpCode {
     }
 }
 
+impl OpCode {
+    /// the name of the variant, without operands
+    pub fn name(&self) -> &'static str {
+        use self::OpCode::*;
+
+        match *self {
+            Eof => "Eof",
+            Sys{..} => "Sys",
+            DrawClr => "DrawClr",
+            Return => "Return",
+            JpConst{..} => "JpConst",
+            Call{..} => "Call",
+            SkpEqConst{..} => "SkpEqConst",
+            SkpNeConst{..} => "SkpNeConst",
+            SkpEqReg{..} => "SkpEqReg",
+            SetConst{..} => "SetConst",
+            AddConst{..} => "AddConst",
+            SetReg{..} => "SetReg",
+            SetRegBor{..} => "SetRegBor",
+            SetRegBand{..} => "SetRegBand",
+            SetRegBxor{..} => "SetRegBxor",
+            SetRegAdd{..} => "SetRegAdd",
+            SetRegSub{..} => "SetRegSub",
+            SetShr1{..} => "SetShr1",
+            SetRegRevSub{..} => "SetRegRevSub",
+            SetShl1{..} => "SetShl1",
+            JpRegNe{..} => "JpRegNe",
+            SetI{..} => "SetI",
+            JpOffset{..} => "JpOffset",
+            SetRand{..} => "SetRand",
+            Draw{..} => "Draw",
+            SkpKeyEq{..} => "SkpKeyEq",
+            SkpKeyNe{..} => "SkpKeyNe",
+            SetRegDelay{..} => "SetRegDelay",
+            SetKey{..} => "SetKey",
+            SetDelay{..} => "SetDelay",
+            SetSound{..} => "SetSound",
+            SetIRegAdd{..} => "SetIRegAdd",
+            SetISprite{..} => "SetISprite",
+            SetBCD{..} => "SetBCD",
+            DumpReg{..} => "DumpReg",
+            LoadReg{..} => "LoadReg",
+            Unknown(_) => "Unknown",
+        }
+    }
+
+    /// the 16 bit instruction this opcode decodes from
+    pub fn encode(&self) -> u16 {
+        use self::OpCode::*;
+
+        let xy = |op: u16, x: usize, y: usize, n: u16| {
+            (op << 12) | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | (n & 0xF)
+        };
+        let xnn = |op: u16, x: usize, nn: u8| (op << 12) | ((x as u16 & 0xF) << 8) | nn as u16;
+        let nnn = |op: u16, nnn: usize| (op << 12) | (nnn as u16 & 0xFFF);
+
+        match *self {
+            Eof => 0x0A00,
+            Sys{nnn: a} => nnn(0x0, a),
+            DrawClr => 0x00E0,
+            Return => 0x00EE,
+            JpConst{nnn: a} => nnn(0x1, a),
+            Call{nnn: a} => nnn(0x2, a),
+            SkpEqConst{x,nn} => xnn(0x3, x, nn),
+            SkpNeConst{x,nn} => xnn(0x4, x, nn),
+            SkpEqReg{x,y} => xy(0x5, x, y, 0x0),
+            SetConst{x,nn} => xnn(0x6, x, nn),
+            AddConst{x,nn} => xnn(0x7, x, nn),
+            SetReg{x,y} => xy(0x8, x, y, 0x0),
+            SetRegBor{x,y} => xy(0x8, x, y, 0x1),
+            SetRegBand{x,y} => xy(0x8, x, y, 0x2),
+            SetRegBxor{x,y} => xy(0x8, x, y, 0x3),
+            SetRegAdd{x,y} => xy(0x8, x, y, 0x4),
+            SetRegSub{x,y} => xy(0x8, x, y, 0x5),
+            SetShr1{x,y} => xy(0x8, x, y, 0x6),
+            SetRegRevSub{x,y} => xy(0x8, x, y, 0x7),
+            SetShl1{x,y} => xy(0x8, x, y, 0xE),
+            JpRegNe{x,y} => xy(0x9, x, y, 0x0),
+            SetI{nnn: a} => nnn(0xA, a as usize),
+            JpOffset{nnn: a} => nnn(0xB, a),
+            SetRand{x,nn} => xnn(0xC, x, nn),
+            Draw{x,y,n} => xy(0xD, x, y, n as u16),
+            SkpKeyEq{x} => xnn(0xE, x, 0x9E),
+            SkpKeyNe{x} => xnn(0xE, x, 0xA1),
+            SetRegDelay{x} => xnn(0xF, x, 0x07),
+            SetKey{x} => xnn(0xF, x, 0x0A),
+            SetDelay{x} => xnn(0xF, x, 0x15),
+            SetSound{x} => xnn(0xF, x, 0x18),
+            SetIRegAdd{x} => xnn(0xF, x, 0x1E),
+            SetISprite{x} => xnn(0xF, x, 0x29),
+            SetBCD{x} => xnn(0xF, x, 0x33),
+            DumpReg{x} => xnn(0xF, x, 0x55),
+            LoadReg{x} => xnn(0xF, x, 0x65),
+            Unknown(instr) => instr,
+        }
+    }
+}
+
 impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             OpCode::Eof =>               write!(f, "EOF"),
+            OpCode::Sys{nnn} =>          write!(f, "machine code 0x{:03X}", nnn),
             OpCode::DrawClr =>           write!(f, "draw clear"),
             OpCode::Return =>            write!(f, "return"),
             OpCode::JpConst{nnn} =>      write!(f, "jump 0x{:03X}", nnn),
@@ -137,9 +237,9 @@ impl fmt::Display for OpCode {
             OpCode::SetRegBxor{x,y} =>   write!(f, "set  V{:X}^=V{:X}", x, y),
             OpCode::SetRegAdd{x,y} =>    write!(f, "set  V{:X}+=V{:X}", x, y),
             OpCode::SetRegSub{x,y} =>    write!(f, "set  V{:X}-=V{:X}", x, y),
-            OpCode::SetShr1{x} =>        write!(f, "set  V{:X}>>=1", x),
+            OpCode::SetShr1{x, ..} =>     write!(f, "set  V{:X}>>=1", x),
             OpCode::SetRegRevSub{x,y} => write!(f, "set  V{:X}=V{:X} - V{:X}", x, y, x),
-            OpCode::SetShl1{x} =>        write!(f, "set  V{:X}<<=1", x),
+            OpCode::SetShl1{x, ..} =>     write!(f, "set  V{:X}<<=1", x),
             OpCode::JpRegNe{x,y} =>      write!(f, "jump V{:X}!=V{:X}", x, y),
             OpCode::SetI{nnn} =>         write!(f, "set  I=0x{:03X}", nnn),
             OpCode::JpOffset{nnn} =>     write!(f, "jump V0 + 0x{:03X}", nnn),
@@ -0,0 +1,210 @@
+use std::sync::{Arc, Mutex};
+
+use debugger::View;
+use memory_bus::{MEMORY_SIZE, ROM_START};
+use opcodes::{OpCode, OP_SIZE};
+use window::Window;
+
+// glyphs are 3x5 pixels, drawn in 4x6 cells so neighbours don't touch
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const CELL_WIDTH: usize = GLYPH_WIDTH + 1;
+const CELL_HEIGHT: usize = GLYPH_HEIGHT + 1;
+// window pixels per font pixel
+const FONT_SCALE: usize = 2;
+
+pub const COLUMNS: usize = 80;
+pub const ROWS: usize = 22;
+/// size of the panel in window pixels
+pub const PANEL_WIDTH: usize = COLUMNS * CELL_WIDTH * FONT_SCALE;
+pub const PANEL_HEIGHT: usize = ROWS * CELL_HEIGHT * FONT_SCALE + CELL_HEIGHT;
+
+// instructions shown before the pc in the disassembly
+const DISASSEMBLY_BEFORE: usize = 5;
+// rows shared by the disassembly and memory views
+const LISTING_ROWS: usize = 14;
+const MEMORY_ROW_BYTES: usize = 8;
+// width of the disassembly column; the memory view takes the rest
+const DISASSEMBLY_COLUMNS: usize = 44;
+
+// each row of a glyph is 3 bits, leftmost pixel first
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b010, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '*' => [0b101, 0b010, 0b101, 0b000, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '|' => [0b010, 0b010, 0b010, 0b010, 0b010],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '&' => [0b010, 0b101, 0b010, 0b101, 0b011],
+        '^' => [0b010, 0b101, 0b000, 0b000, 0b000],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+/// render lines of text into a panel of one byte (0 or 1) per window pixel, row-major
+pub fn render(lines: &[String]) -> Vec<u8> {
+    let mut pixels = vec![0; PANEL_WIDTH * PANEL_HEIGHT];
+    for (row, line) in lines.iter().take(ROWS).enumerate() {
+        for (column, c) in line.chars().take(COLUMNS).enumerate() {
+            let rows = glyph(c);
+            for gy in 0..GLYPH_HEIGHT {
+                for gx in 0..GLYPH_WIDTH {
+                    if rows[gy] & (1 << (GLYPH_WIDTH - 1 - gx)) == 0 {
+                        continue;
+                    }
+                    let x = (column * CELL_WIDTH + gx) * FONT_SCALE;
+                    // half a cell of padding above the first row
+                    let y = (row * CELL_HEIGHT + gy) * FONT_SCALE + CELL_HEIGHT;
+                    for dy in 0..FONT_SCALE {
+                        let start = (y + dy) * PANEL_WIDTH + x;
+                        for pixel in &mut pixels[start..start + FONT_SCALE] {
+                            *pixel = 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    pixels
+}
+
+/// the text of the panel
+pub fn lines(view: &View, keys: &[bool], paused: bool) -> Vec<String> {
+    let cpu = &view.cpu;
+    let mut lines = vec![];
+    lines.push(format!("{:<8} {:>10} INSTRUCTIONS        F5 PAUSE/RUN   F10 STEP",
+                       if paused { "PAUSED" } else { "RUNNING" }, cpu.counter));
+    lines.push(String::new());
+    for (idx, regs) in cpu.v.chunks(8).enumerate() {
+        let regs: Vec<String> = regs.iter()
+                                    .enumerate()
+                                    .map(|(x, v)| format!("V{:X} {:02X}", idx * 8 + x, v))
+                                    .collect();
+        lines.push(regs.join("  "));
+    }
+    lines.push(format!("I  {:03X}  PC {:03X}  DT {:02X}  ST {:02X}  SP {:X}",
+                       cpu.i, cpu.pc, view.delay_timer, view.sound_timer, cpu.stack.len()));
+    let stack: Vec<String> = cpu.stack.iter().rev().map(|&ret| view.symbols.format_addr(ret)).collect();
+    lines.push(format!("STACK {}", stack.join("  ")));
+    let keypad: String = keys.iter()
+                             .enumerate()
+                             .map(|(key, &down)| if down { format!("{:X}", key) } else { ".".into() })
+                             .collect();
+    lines.push(format!("KEYS  {}", keypad));
+    lines.push(String::new());
+
+    let disassembly = disassembly(view);
+    let memory = memory(view);
+    for row in 0..LISTING_ROWS {
+        let left = disassembly.get(row).map(|s| s.as_str()).unwrap_or("");
+        let left: String = left.chars().take(DISASSEMBLY_COLUMNS - 1).collect();
+        let right = memory.get(row).map(|s| s.as_str()).unwrap_or("");
+        lines.push(format!("{:<width$}{}", left, right, width = DISASSEMBLY_COLUMNS));
+    }
+    lines
+}
+
+// instructions around the pc, which is marked with `>`
+fn disassembly(view: &View) -> Vec<String> {
+    let pc = view.cpu.pc;
+    let start = pc.saturating_sub(DISASSEMBLY_BEFORE * OP_SIZE);
+    // there's nothing to see below the rom unless the pc is already there
+    let start = if pc >= ROM_START { start.max(ROM_START) } else { start };
+    let mut lines = vec![];
+    let mut addr = start;
+    while lines.len() < LISTING_ROWS && addr + 1 < MEMORY_SIZE {
+        if let Some(name) = view.symbols.name(addr) {
+            lines.push(format!("  {}:", name));
+        }
+        let instr = view.mem_bus.read_instruction(addr);
+        let marker = if addr == pc { '>' } else { ' ' };
+        lines.push(format!("{} {:03X} {:04X} {}", marker, addr, instr, OpCode::from(instr)));
+        addr += OP_SIZE;
+    }
+    lines.truncate(LISTING_ROWS);
+    lines
+}
+
+// memory from the row holding I, with I's byte marked by brackets
+fn memory(view: &View) -> Vec<String> {
+    let i = view.cpu.i as usize;
+    let start = (i / MEMORY_ROW_BYTES) * MEMORY_ROW_BYTES;
+    (0..LISTING_ROWS).map(|row| start + row * MEMORY_ROW_BYTES)
+                     .filter(|&addr| addr < MEMORY_SIZE)
+                     .map(|addr| {
+                         let bytes: String = (addr..addr + MEMORY_ROW_BYTES).map(|a| {
+                             let byte = view.mem_bus.peek_word(a);
+                             if a == i { format!("[{:02X}]", byte) } else { format!(" {:02X} ", byte) }
+                         }).collect();
+                         format!("{:03X}:{}", addr, bytes)
+                     })
+                     .collect()
+}
+
+/// A debugger panel drawn under the display in the emulator window
+pub struct Overlay {
+    window: Arc<Mutex<Window>>,
+}
+
+impl Overlay {
+    pub fn new(window: Arc<Mutex<Window>>) -> Overlay {
+        Overlay {
+            window: window,
+        }
+    }
+
+    pub fn update(&self, view: &View, keys: &[bool], paused: bool) {
+        let pixels = render(&lines(view, keys, paused));
+        self.window.lock().expect("failed to aquire lock").draw_panel(pixels);
+    }
+}
@@ -0,0 +1,26 @@
+use std::io::{self, Write};
+
+/// list `items` in the terminal and ask for one by number; None if the user quits
+pub fn choose(heading: &str, items: &[String]) -> Option<usize> {
+    println!("{}", heading);
+    for (idx, item) in items.iter().enumerate() {
+        println!("{:>4}) {}", idx + 1, item);
+    }
+    loop {
+        print!("choose 1-{}, or q to quit: ", items.len());
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            // end of input
+            Ok(0) | Err(_) => return None,
+            Ok(_) => (),
+        }
+        match line.trim() {
+            "q" | "quit" => return None,
+            choice => match choice.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= items.len() => return Some(n - 1),
+                _ => println!("no rom {}", choice),
+            },
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use memory_bus::{MEMORY_SIZE, ROM_START};
+use opcodes::OpCode;
+use symbols::Symbols;
+
+// rows shown in each table of the report
+const REPORT_ROWS: usize = 20;
+
+/// Counts where a rom spends its instructions
+pub struct Profiler {
+    // executions per address
+    pc_counts: Vec<u64>,
+    // executions per opcode variant
+    op_counts: HashMap<&'static str, u64>,
+    // entry addresses of the subroutines currently running, outermost first
+    stack: Vec<usize>,
+    // instructions executed with each call stack on top of the cpu
+    stacks: HashMap<Vec<usize>, u64>,
+    // wall-clock time spent waiting in `SetKey`
+    key_wait_ns: u64,
+    key_waits: u64,
+    total: u64,
+}
+
+fn subroutine_name(addr: usize, symbols: &Symbols) -> String {
+    match symbols.name(addr) {
+        Some(name) => name.to_owned(),
+        None => format!("label_{:03X}", addr),
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            pc_counts: vec![0; MEMORY_SIZE],
+            op_counts: HashMap::new(),
+            stack: vec![ROM_START],
+            stacks: HashMap::new(),
+            key_wait_ns: 0,
+            key_waits: 0,
+            total: 0,
+        }
+    }
+
+    /// count the instruction `opcode` at `pc`, which took `elapsed_ns` of wall-clock time
+    pub fn record(&mut self, pc: usize, opcode: OpCode, elapsed_ns: u64) {
+        self.total += 1;
+        self.pc_counts[pc] += 1;
+        *self.op_counts.entry(opcode.name()).or_insert(0) += 1;
+        match self.stacks.get_mut(&self.stack[..]) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.stack.clone(), 1);
+            }
+        }
+
+        match opcode {
+            OpCode::Call { nnn } => self.stack.push(nnn),
+            // the outermost frame is the program itself, which never returns
+            OpCode::Return if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            OpCode::SetKey { .. } => {
+                self.key_waits += 1;
+                self.key_wait_ns += elapsed_ns;
+            }
+            _ => (),
+        }
+    }
+
+    /// instructions executed in each subroutine as (inclusive, exclusive) counts, where
+    /// inclusive counts include the subroutines it calls
+    fn subroutine_counts(&self) -> BTreeMap<usize, (u64, u64)> {
+        let mut counts = BTreeMap::new();
+        for (stack, &count) in &self.stacks {
+            // recursive subroutines only count once per stack
+            let unique: BTreeSet<&usize> = stack.iter().collect();
+            for &addr in unique {
+                counts.entry(addr).or_insert((0, 0)).0 += count;
+            }
+            let top = *stack.last().expect("stacks are never empty");
+            counts.entry(top).or_insert((0, 0)).1 += count;
+        }
+        counts
+    }
+
+    /// a human readable report of the hottest addresses, opcodes and subroutines
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let mut out = String::new();
+        let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+        writeln!(out, "{} instructions executed", self.total).unwrap();
+
+        writeln!(out, "\nhottest addresses:").unwrap();
+        let mut pcs: Vec<(usize, u64)> = self.pc_counts.iter()
+                                                       .cloned()
+                                                       .enumerate()
+                                                       .filter(|&(_, count)| count > 0)
+                                                       .collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for &(pc, count) in pcs.iter().take(REPORT_ROWS) {
+            let name = symbols.describe(pc).unwrap_or(String::new());
+            writeln!(out, "    {:03X} {:>12} {:>6.2}%  {}", pc, count, percent(count), name).unwrap();
+        }
+
+        writeln!(out, "\nopcodes:").unwrap();
+        let mut ops: Vec<(&str, u64)> = self.op_counts.iter().map(|(&name, &count)| (name, count)).collect();
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for &(name, count) in &ops {
+            writeln!(out, "    {:<14} {:>12} {:>6.2}%", name, count, percent(count)).unwrap();
+        }
+
+        writeln!(out, "\nsubroutines (inclusive, self):").unwrap();
+        let mut subroutines: Vec<(usize, (u64, u64))> = self.subroutine_counts().into_iter().collect();
+        subroutines.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(&b.0)));
+        for &(addr, (inclusive, exclusive)) in subroutines.iter().take(REPORT_ROWS) {
+            writeln!(out, "    {:<14} {:>12} {:>6.2}% {:>12} {:>6.2}%",
+                     subroutine_name(addr, symbols), inclusive, percent(inclusive), exclusive, percent(exclusive)).unwrap();
+        }
+
+        writeln!(out, "\nwaiting for keys: {} waits, {:.3}s", self.key_waits, self.key_wait_ns as f64 / 1e9).unwrap();
+        out
+    }
+
+    /// write call stacks in the folded format read by flamegraph.pl and similar tools,
+    /// weighted by instructions executed
+    pub fn write_folded<P: AsRef<Path>>(&self, path: P, symbols: &Symbols) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut stacks: Vec<(String, u64)> = self.stacks.iter().map(|(stack, &count)| {
+            let names: Vec<String> = stack.iter().map(|&addr| subroutine_name(addr, symbols)).collect();
+            (names.join(";"), count)
+        }).collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        out.flush()
+    }
+}
@@ -0,0 +1,51 @@
+use serde_json::Value;
+
+/// Behaviours that differ between CHIP-8 interpreters, named as in the chip-8-database
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6 and 8XYE shift VX in place instead of shifting VY into VX
+    pub shift: bool,
+    // FX55 and FX65 leave I at I + X instead of I + X + 1
+    pub memory_increment_by_x: bool,
+    // FX55 and FX65 don't change I at all
+    pub memory_leave_i_unchanged: bool,
+    // sprites wrap around the edges of the display instead of being clipped
+    pub wrap: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump: bool,
+    // DXYN waits for the start of the next frame
+    pub vblank: bool,
+    // 8XY1, 8XY2 and 8XY3 reset VF
+    pub logic: bool,
+}
+
+impl Default for Quirks {
+    /// how this emulator has always behaved
+    fn default() -> Quirks {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+impl Quirks {
+    /// `self` with any quirks set in a chip-8-database `quirks` object replaced
+    pub fn merge_json(&self, json: &Value) -> Quirks {
+        let get = |name: &str, default: bool| json.get(name).and_then(Value::as_bool).unwrap_or(default);
+        Quirks {
+            shift: get("shift", self.shift),
+            memory_increment_by_x: get("memoryIncrementByX", self.memory_increment_by_x),
+            memory_leave_i_unchanged: get("memoryLeaveIUnchanged", self.memory_leave_i_unchanged),
+            wrap: get("wrap", self.wrap),
+            jump: get("jump", self.jump),
+            vblank: get("vblank", self.vblank),
+            logic: get("logic", self.logic),
+        }
+    }
+}
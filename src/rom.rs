@@ -0,0 +1,259 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use zip::ZipArchive;
+
+use disassembler::Analysis;
+use opcodes::OpCode;
+use memory_bus::{MEMORY_SIZE, ROM_START};
+
+/// the most rom that fits in memory above the interpreter
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - ROM_START;
+
+/// extensions roms are recognized by in directories and archives
+pub const ROM_EXTENSIONS: [&'static str; 4] = ["ch8", "c8", "rom", "sc8"];
+
+// shortest file we'll call text; a rom that happens to be all printable bytes is unlikely
+// to be this long
+const MIN_TEXT_LEN: usize = 16;
+
+/// Why a rom can't be loaded
+#[derive(Debug)]
+pub enum RomError {
+    Missing,
+    Unreadable(io::Error),
+    Empty,
+    // a rom read from a stream stops one byte past `max`, so `size` is that when it's more
+    TooLarge { size: usize, max: usize },
+    // what the file seems to be instead
+    NotChip8(&'static str),
+    Text,
+    BadArchive(String),
+    // the archive has no files that could be roms
+    NoRoms,
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Missing => write!(f, "no such file"),
+            RomError::Unreadable(ref e) => write!(f, "could not read it: {}", e),
+            RomError::Empty => write!(f, "the file is empty"),
+            RomError::TooLarge { size, max } if size == max + 1 => {
+                write!(f, "the rom is more than {} bytes, which is all that fits in memory from {:#X}", max, ROM_START)
+            }
+            RomError::TooLarge { size, max } => {
+                write!(f, "the rom is {} bytes, but only {} fit in memory from {:#X}", size, max, ROM_START)
+            }
+            RomError::NotChip8(kind) => write!(f, "this looks like {}, not a CHIP-8 rom", kind),
+            RomError::Text => write!(f, "this is text, not a CHIP-8 rom; source has to be assembled with `asm` first"),
+            RomError::BadArchive(ref e) => write!(f, "could not read the archive: {}", e),
+            RomError::NoRoms => write!(f, "the archive has no roms in it"),
+        }
+    }
+}
+
+/// read a rom and `check` it
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, RomError> {
+    match File::open(path) {
+        Ok(file) => read(file),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(RomError::Missing),
+        Err(e) => Err(RomError::Unreadable(e)),
+    }
+}
+
+/// read a rom from anywhere, e.g. stdin, and `check` it. Reading stops once the rom is too
+/// big to fit, so an endless pipe or a zip bomb can't fill up memory.
+pub fn read<R: Read>(reader: R) -> Result<Vec<u8>, RomError> {
+    let mut rom = vec![];
+    reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom).map_err(RomError::Unreadable)?;
+    check(&rom)?;
+    Ok(rom)
+}
+
+fn has_rom_extension(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ROM_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+fn open_zip<P: AsRef<Path>>(path: P) -> Result<ZipArchive<File>, RomError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(RomError::Missing),
+        Err(e) => return Err(RomError::Unreadable(e)),
+    };
+    ZipArchive::new(file).map_err(|e| RomError::BadArchive(e.to_string()))
+}
+
+/// the names of the files in a zip archive that look like roms: those with a rom extension,
+/// or every file if none have one
+pub fn zip_roms<P: AsRef<Path>>(path: P) -> Result<Vec<String>, RomError> {
+    let mut archive = open_zip(path)?;
+    let mut names = vec![];
+    for idx in 0..archive.len() {
+        let file = archive.by_index(idx).map_err(|e| RomError::BadArchive(e.to_string()))?;
+        if !file.name().ends_with('/') {
+            names.push(file.name().to_owned());
+        }
+    }
+    let roms: Vec<String> = names.iter().filter(|name| has_rom_extension(Path::new(name))).cloned().collect();
+    match (roms.is_empty(), names.is_empty()) {
+        (_, true) => Err(RomError::NoRoms),
+        (true, false) => Ok(names),
+        (false, _) => Ok(roms),
+    }
+}
+
+/// read the rom called `name` from a zip archive and `check` it
+pub fn load_zip<P: AsRef<Path>>(path: P, name: &str) -> Result<Vec<u8>, RomError> {
+    let mut archive = open_zip(path)?;
+    let file = archive.by_name(name).map_err(|e| RomError::BadArchive(e.to_string()))?;
+    read(file)
+}
+
+/// the roms, octo sources and zip archives in a directory, sorted by name
+pub fn list_dir<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let other = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext == "8o" || ext.to_lowercase() == "zip",
+            None => false,
+        };
+        if path.is_file() && (has_rom_extension(&path) || other) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// reject roms that can't fit in memory or are obviously something else
+pub fn check(rom: &[u8]) -> Result<(), RomError> {
+    if rom.is_empty() {
+        return Err(RomError::Empty);
+    }
+    if let Some(kind) = file_kind(rom) {
+        return Err(RomError::NotChip8(kind));
+    }
+    if is_text(rom) {
+        return Err(RomError::Text);
+    }
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(RomError::TooLarge { size: rom.len(), max: MAX_ROM_SIZE });
+    }
+    Ok(())
+}
+
+// what a file with a well-known signature is. Short signatures can also be valid
+// instructions, so each is matched in full.
+fn file_kind(data: &[u8]) -> Option<&'static str> {
+    let signatures: [(&[u8], &'static str); 9] = [
+        (b"PK\x03\x04", "a zip archive"),
+        (b"\x1F\x8B\x08", "a gzip file"),
+        (b"\x89PNG\r\n\x1A\n", "a png image"),
+        (b"GIF87a", "a gif image"),
+        (b"GIF89a", "a gif image"),
+        (b"%PDF-", "a pdf"),
+        (b"\x7FELF\x01", "an executable"),
+        (b"\x7FELF\x02", "an executable"),
+        (b"RIFF", "a wav file"),
+    ];
+    for &(signature, kind) in &signatures {
+        if data.starts_with(signature) {
+            return Some(kind);
+        }
+    }
+    None
+}
+
+fn is_text(data: &[u8]) -> bool {
+    let printable = data.iter().all(|&b| b == b'\t' || b == b'\n' || b == b'\r' || (b >= 0x20 && b < 0x7F));
+    printable && data.len() >= MIN_TEXT_LEN && data.contains(&b'\n')
+}
+
+/// Groups of instructions added by later CHIP-8 variants
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Extension {
+    // calls into COSMAC VIP machine code
+    MachineCode,
+    SuperChip,
+    XoChip,
+}
+
+impl Extension {
+    fn name(&self) -> &'static str {
+        match *self {
+            Extension::MachineCode => "machine code call",
+            Extension::SuperChip => "SUPER-CHIP",
+            Extension::XoChip => "XO-CHIP",
+        }
+    }
+}
+
+// the extension an instruction we can't decode comes from, if any
+fn extension(instr: u16) -> Option<Extension> {
+    match (instr >> 12, instr & 0x00FF, instr & 0x000F) {
+        (0x0, 0xFB...0xFF, _) if instr & 0x0F00 == 0 => Some(Extension::SuperChip),
+        (0x0, 0xC0...0xCF, _) if instr & 0x0F00 == 0 => Some(Extension::SuperChip),
+        (0x0, 0xD0...0xDF, _) if instr & 0x0F00 == 0 => Some(Extension::XoChip),
+        (0x0, _, _) => Some(Extension::MachineCode),
+        (0x5, _, 0x2) | (0x5, _, 0x3) => Some(Extension::XoChip),
+        (0xF, 0x30, _) | (0xF, 0x75, _) | (0xF, 0x85, _) => Some(Extension::SuperChip),
+        (0xF, 0x00, _) | (0xF, 0x01, _) | (0xF, 0x02, _) | (0xF, 0x3A, _) => Some(Extension::XoChip),
+        _ => None,
+    }
+}
+
+// the extensions a chip-8-database platform has on top of the original instructions
+fn platform_extensions(platform: &str) -> &'static [Extension] {
+    match platform {
+        "originalChip8" | "hybridVIP" => &[Extension::MachineCode],
+        "superchip1" | "superchip" => &[Extension::SuperChip],
+        "xochip" => &[Extension::SuperChip, Extension::XoChip],
+        _ => &[],
+    }
+}
+
+/// warnings about instructions reachable from the entry point that `platform` doesn't have,
+/// or that this emulator can't run
+pub fn prescan(rom: &[u8], platform: Option<&str>) -> Vec<String> {
+    let analysis = Analysis::new(rom);
+    let platform = platform.unwrap_or("chip8");
+    // 0NNN decodes as a machine code call, which is how the later variants' additions to it
+    // look to the original interpreter
+    let mut addrs: Vec<usize> = analysis.code()
+                                        .iter()
+                                        .filter_map(|(&addr, opcode)| match *opcode {
+                                            OpCode::Sys { .. } => Some(addr),
+                                            _ => None,
+                                        })
+                                        .collect();
+    addrs.extend(analysis.unknown());
+    addrs.sort();
+    let mut warnings = vec![];
+    for addr in addrs {
+        let instr = analysis.word(addr).expect("only decoded words are scanned");
+        let warning = match extension(instr) {
+            Some(Extension::MachineCode) if platform_extensions(platform).contains(&Extension::MachineCode) => {
+                format!("{:03X}: {:04X} calls machine code at {:03X}, which only runs with --sys 1802 or --vip-interpreter",
+                        addr, instr, instr & 0xFFF)
+            }
+            Some(extension) if platform_extensions(platform).contains(&extension) => {
+                format!("{:03X}: {:04X} is a {} instruction, which {} has but this emulator can't run",
+                        addr, instr, extension.name(), platform)
+            }
+            Some(extension) => {
+                format!("{:03X}: {:04X} is a {} instruction, which isn't part of {}",
+                        addr, instr, extension.name(), platform)
+            }
+            None => format!("{:03X}: {:04X} isn't an instruction on any platform", addr, instr),
+        };
+        warnings.push(warning);
+    }
+    warnings
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use serde_json::{self, Value};
+use sha1::Sha1;
+
+use capture::Palette;
+use quirks::Quirks;
+
+// platforms in the chip-8-database format. Programs aren't bundled; they come from a
+// checkout of the database given at runtime.
+const BUNDLED_PLATFORMS: &'static str = include_str!("platforms.json");
+
+/// identifies a rom regardless of its file name, as lowercase hex sha1
+pub fn rom_hash(rom: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(rom);
+    sha1.digest().to_string()
+}
+
+/// A CHIP-8 variant and how its interpreter behaves
+#[derive(Clone, Debug)]
+pub struct Platform {
+    pub id: String,
+    pub name: String,
+    // instructions per 60 Hz frame
+    pub tickrate: u32,
+    pub quirks: Quirks,
+}
+
+/// Everything the database says about how to run a rom
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub title: String,
+    pub platform: String,
+    pub quirks: Quirks,
+    pub tickrate: u32,
+    // keypad keys for game actions like `up` and `a`
+    pub keys: Vec<(String, u8)>,
+    pub palette: Option<Palette>,
+}
+
+/// Roms and platforms from the chip-8-database
+pub struct RomDb {
+    platforms: Vec<Platform>,
+    programs: Vec<Value>,
+    // sha1 of each known rom to its program's index in `programs`
+    hashes: HashMap<String, usize>,
+}
+
+impl RomDb {
+    /// just the bundled platforms, with no roms
+    pub fn bundled() -> RomDb {
+        let platforms = serde_json::from_str(BUNDLED_PLATFORMS).expect("bundled platforms are valid json");
+        RomDb {
+            platforms: parse_platforms(&platforms),
+            programs: vec![],
+            hashes: HashMap::new(),
+        }
+    }
+
+    /// read the `database` directory of a chip-8-database checkout: `sha1-hashes.json`,
+    /// `programs.json` and, if it's there, `platforms.json` in place of the bundled platforms
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<RomDb> {
+        let dir = dir.as_ref();
+        let mut db = RomDb::bundled();
+        let platforms = dir.join("platforms.json");
+        if platforms.exists() {
+            db.platforms = parse_platforms(&read_json(&platforms)?);
+        }
+        db.hashes = match read_json(&dir.join("sha1-hashes.json"))? {
+            Value::Object(hashes) => {
+                hashes.iter()
+                      .filter_map(|(hash, idx)| idx.as_u64().map(|idx| (hash.to_lowercase(), idx as usize)))
+                      .collect()
+            }
+            _ => return Err(invalid("sha1-hashes.json should be an object of hashes")),
+        };
+        db.programs = match read_json(&dir.join("programs.json"))? {
+            Value::Array(programs) => programs,
+            _ => return Err(invalid("programs.json should be an array of programs")),
+        };
+        Ok(db)
+    }
+
+    pub fn platform(&self, id: &str) -> Option<&Platform> {
+        self.platforms.iter().find(|platform| platform.id == id)
+    }
+
+    pub fn platform_ids(&self) -> Vec<&str> {
+        self.platforms.iter().map(|platform| platform.id.as_str()).collect()
+    }
+
+    /// settings for the rom with sha1 `hash`, if it's in the database and runs on a platform
+    /// we know
+    pub fn lookup(&self, hash: &str) -> Option<RomInfo> {
+        let program = match self.hashes.get(hash).and_then(|&idx| self.programs.get(idx)) {
+            Some(program) => program,
+            None => return None,
+        };
+        let title = program["title"].as_str().unwrap_or("untitled").to_owned();
+        let rom = &program["roms"][hash];
+        // platforms are listed best first
+        let platform = rom["platforms"].as_array()
+                                       .into_iter()
+                                       .flat_map(|ids| ids.iter())
+                                       .filter_map(|id| id.as_str().and_then(|id| self.platform(id)))
+                                       .next();
+        let platform = match platform {
+            Some(platform) => platform,
+            None => {
+                warn!("{} is in the rom database, but not for any platform we know", title);
+                return None;
+            }
+        };
+
+        let keys = rom["keys"].as_object()
+                              .into_iter()
+                              .flat_map(|keys| keys.iter())
+                              .filter_map(|(action, key)| match key.as_u64() {
+                                  Some(key) if key < 0x10 => Some((action.clone(), key as u8)),
+                                  _ => None,
+                              })
+                              .collect();
+        // the first pixel color is for pixels that are off
+        let palette = match rom["colors"]["pixels"].as_array() {
+            Some(pixels) if pixels.len() >= 2 => {
+                match (pixels[0].as_str(), pixels[1].as_str()) {
+                    (Some(off), Some(on)) => Palette::parse(&format!("{},{}", on, off)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        Some(RomInfo {
+            title: title,
+            platform: platform.id.clone(),
+            quirks: platform.quirks.merge_json(&rom["quirkyPlatforms"][platform.id.as_str()]),
+            tickrate: tickrate(&rom["tickrate"]).unwrap_or(platform.tickrate),
+            keys: keys,
+            palette: palette,
+        })
+    }
+}
+
+fn parse_platforms(json: &Value) -> Vec<Platform> {
+    let platforms = match json.as_array() {
+        Some(platforms) => platforms,
+        None => return vec![],
+    };
+    platforms.iter()
+             .filter_map(|platform| {
+                 let id = match platform["id"].as_str() {
+                     Some(id) => id,
+                     None => return None,
+                 };
+                 Some(Platform {
+                     id: id.to_owned(),
+                     name: platform["name"].as_str().unwrap_or(id).to_owned(),
+                     tickrate: tickrate(&platform["defaultTickrate"]).unwrap_or(15),
+                     quirks: Quirks::default().merge_json(&platform["quirks"]),
+                 })
+             })
+             .collect()
+}
+
+// a tickrate of at least one instruction a frame; anything else is left to the default
+fn tickrate(json: &Value) -> Option<u32> {
+    match json.as_u64() {
+        Some(tickrate) if tickrate > 0 && tickrate <= u32::max_value() as u64 => Some(tickrate as u32),
+        _ => None,
+    }
+}
+
+fn read_json(path: &Path) -> io::Result<Value> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    serde_json::from_str(&text).map_err(|e| invalid(&format!("{}: {}", path.display(), e)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -1,70 +1,449 @@
-use std::f64::consts::PI;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use portaudio as pa;
 
 const CHANNELS: i32 = 2;
-const NUM_MILLIS: i32 = 16;
 const SAMPLE_RATE: f64 = 44_100.0;
 const FRAMES_PER_BUFFER: u32 = 64;
-const TABLE_SIZE: usize = 200;
+// time taken to fade the tone in or out, short enough to be inaudible but long enough
+// to avoid the click of starting or stopping a wave away from zero
+const RAMP_SECONDS: f32 = 0.005;
+// the portaudio sink aims to keep about three frames of audio queued for the device
+const TARGET_QUEUED_SAMPLES: usize = 3 * 44_100 / 60;
+const MAX_QUEUED_SAMPLES: usize = 4 * TARGET_QUEUED_SAMPLES;
+// largest playback speed adjustment used to keep the queue at its target size
+const MAX_RATE_ADJUST: f64 = 0.005;
 
 type Stream = pa::Stream<pa::NonBlocking, pa::Output<f32>>;
 
-pub struct Sound {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+}
+
+impl Waveform {
+    pub fn parse(s: &str) -> Option<Waveform> {
+        match s {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            "triangle" => Some(Waveform::Triangle),
+            _ => None,
+        }
+    }
+
+    /// sample the wave at `phase`, given as a fraction of a period in [0, 1)
+    fn sample(&self, phase: f32) -> f32 {
+        match *self {
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+/// Settings for the beeper tone
+#[derive(Clone, Copy, Debug)]
+pub struct SoundConfig {
+    // tone frequency in Hz
+    pub frequency: f32,
+    pub waveform: Waveform,
+    // output volume from 0.0 (silent) to 1.0
+    pub volume: f32,
+}
+
+impl Default for SoundConfig {
+    fn default() -> SoundConfig {
+        SoundConfig {
+            frequency: 440.0,
+            waveform: Waveform::Square,
+            volume: 0.25,
+        }
+    }
+}
+
+/// Where the beeper output goes
+#[derive(Clone, Debug)]
+pub enum AudioBackend {
+    // portaudio if a device can be opened, otherwise null
+    Auto,
+    PortAudio,
+    Null,
+    Wav(PathBuf),
+}
+
+/// Generates the beeper tone one sample at a time
+struct Oscillator {
+    waveform: Waveform,
+    volume: f32,
+    phase: f32,
+    phase_step: f32,
+    amplitude: f32,
+    ramp_step: f32,
+}
+
+impl Oscillator {
+    fn new(config: &SoundConfig) -> Oscillator {
+        let volume = config.volume.max(0.0).min(1.0);
+        Oscillator {
+            waveform: config.waveform,
+            volume: volume,
+            phase: 0.0,
+            phase_step: config.frequency / SAMPLE_RATE as f32,
+            amplitude: 0.0,
+            ramp_step: volume / (RAMP_SECONDS * SAMPLE_RATE as f32),
+        }
+    }
+
+    fn next_sample(&mut self, on: bool) -> f32 {
+        let target = if on { self.volume } else { 0.0 };
+        // ramp towards the target amplitude rather than jumping to it
+        if self.amplitude < target {
+            self.amplitude = (self.amplitude + self.ramp_step).min(target);
+        } else if self.amplitude > target {
+            self.amplitude = (self.amplitude - self.ramp_step).max(target);
+        }
+        let sample = self.waveform.sample(self.phase) * self.amplitude;
+        self.phase += self.phase_step;
+        if self.phase >= 1.0 { self.phase -= 1.0; }
+        sample
+    }
+}
+
+/// An output for the beeper's samples
+pub trait AudioSink {
+    /// queue mono samples at `SAMPLE_RATE`, in emulated time order
+    fn write(&mut self, samples: &[f32]);
+
+    /// block until the sink is ready for more samples, so the emulator runs exactly as fast
+    /// as the sink consumes audio. Returns false if the sink can't pace the emulator.
+    fn wait(&mut self) -> bool {
+        false
+    }
+}
+
+/// Plays samples on the default output device. Samples are handed to the audio callback
+/// through a small queue, and the callback resamples slightly faster or slower depending
+/// on how full the queue is to absorb drift between the emulator and the device clock.
+pub struct PortAudioSink {
     pa: pa::PortAudio,
     stream: Stream,
+    queue: Arc<Mutex<VecDeque<f32>>>,
 }
 
-impl Sound {
-    pub fn new() -> Sound {
-        // setup output stream
-        let mut sine = [0.0; TABLE_SIZE];
-        for i in 0..TABLE_SIZE {
-            sine[i] = (i as f64 / TABLE_SIZE as f64 * PI * 2.0).sin() as f32;
-        }
-        let mut left_phase = 0;
-        let mut right_phase = 0;
+impl PortAudioSink {
+    pub fn new() -> Result<PortAudioSink, pa::Error> {
+        let queue = Arc::new(Mutex::new(VecDeque::with_capacity(MAX_QUEUED_SAMPLES)));
 
-        let pa = pa::PortAudio::new()
-            .expect("failed to create new portaudio");
+        let pa = pa::PortAudio::new()?;
 
-        let mut settings = pa.default_output_stream_settings(CHANNELS, SAMPLE_RATE, FRAMES_PER_BUFFER)
-            .expect("failed to create pa settings");
+        let mut settings = pa.default_output_stream_settings(CHANNELS, SAMPLE_RATE, FRAMES_PER_BUFFER)?;
         settings.flags = pa::stream_flags::CLIP_OFF;
 
+        let callback_queue = queue.clone();
+        let mut resampler = Resampler::new();
         let callback = move |pa::OutputStreamCallbackArgs { buffer, frames, .. }| {
+            let mut queue = callback_queue.lock().expect("failed to aquire lock");
             let mut idx = 0;
             for _ in 0..frames {
-                buffer[idx]   = sine[left_phase];
-                buffer[idx+1] = sine[right_phase];
-                left_phase += 1;
-                if left_phase >= TABLE_SIZE { left_phase -= TABLE_SIZE; }
-                right_phase += 3;
-                if right_phase >= TABLE_SIZE { right_phase -= TABLE_SIZE; }
+                let sample = resampler.next_sample(&mut queue);
+                buffer[idx]   = sample;
+                buffer[idx+1] = sample;
                 idx += 2;
             }
             pa::Continue
         };
 
-        let stream = pa.open_non_blocking_stream(settings, callback)
-            .expect("failed to open pa stream");
+        let mut stream = pa.open_non_blocking_stream(settings, callback)?;
+        stream.start()?;
 
-        Sound {
+        Ok(PortAudioSink {
             pa: pa,
             stream: stream,
+            queue: queue,
+        })
+    }
+}
+
+impl AudioSink for PortAudioSink {
+    fn write(&mut self, samples: &[f32]) {
+        let mut queue = self.queue.lock().expect("failed to aquire lock");
+        queue.extend(samples.iter().cloned());
+        // if the emulator is running ahead of the device, drop the oldest samples rather
+        // than let latency build up
+        while queue.len() > MAX_QUEUED_SAMPLES {
+            queue.pop_front();
         }
     }
 
-    pub fn emit(&mut self) {
-        // self.stream.start().expect("failed to start pa stream");
-        // self.pa.sleep(NUM_MILLIS);
-        // self.stream.stop().expect("failed to stop pa stream");
+    fn wait(&mut self) -> bool {
+        while self.queue.lock().expect("failed to aquire lock").len() > TARGET_QUEUED_SAMPLES {
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
     }
 }
 
-impl Drop for Sound {
+impl Drop for PortAudioSink {
     fn drop(&mut self) {
-        self.stream.close().expect("failed to close pa stream");
+        // panicking here could abort a thread that's already unwinding
+        if let Err(e) = self.stream.stop() {
+            error!("failed to stop pa stream: {}", e);
+        }
+        if let Err(e) = self.stream.close() {
+            error!("failed to close pa stream: {}", e);
+        }
+    }
+}
+
+/// Reads queued samples at a rate nudged towards keeping `TARGET_QUEUED_SAMPLES` queued,
+/// interpolating between neighbouring samples.
+struct Resampler {
+    // fractional position between the first two queued samples
+    pos: f64,
+    last: f32,
+}
+
+impl Resampler {
+    fn new() -> Resampler {
+        Resampler {
+            pos: 0.0,
+            last: 0.0,
+        }
     }
+
+    fn next_sample(&mut self, queue: &mut VecDeque<f32>) -> f32 {
+        if queue.len() < 2 {
+            // underrun: fade out whatever was last played instead of clicking to silence
+            self.last *= 0.99;
+            return self.last;
+        }
+        let fill = queue.len() as f64 / TARGET_QUEUED_SAMPLES as f64;
+        let rate = 1.0 + ((fill - 1.0) * MAX_RATE_ADJUST).max(-MAX_RATE_ADJUST).min(MAX_RATE_ADJUST);
+
+        let sample = queue[0] + (queue[1] - queue[0]) * self.pos as f32;
+        self.pos += rate;
+        while self.pos >= 1.0 && !queue.is_empty() {
+            queue.pop_front();
+            self.pos -= 1.0;
+        }
+        self.last = sample;
+        sample
+    }
+}
+
+/// Discards all sound, for machines without an audio device
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _samples: &[f32]) {}
 }
 
+/// Writes samples to a 16 bit mono wav file. Since samples are generated from emulated
+/// time, the file lines up exactly with the emulated program regardless of machine load.
+pub struct WavSink {
+    out: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<WavSink> {
+        let mut sink = WavSink {
+            out: BufWriter::new(File::create(path)?),
+            samples: 0,
+        };
+        // sizes are filled in once we know how many samples were written
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let rate = SAMPLE_RATE as u32;
+        let data_len = self.samples * 2;
+        let out = &mut self.out;
+        out.write_all(b"RIFF")?;
+        write_u32(out, 36 + data_len)?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        write_u32(out, 16)?;
+        write_u16(out, 1)?;        // pcm
+        write_u16(out, 1)?;        // channels
+        write_u32(out, rate)?;     // sample rate
+        write_u32(out, rate * 2)?; // byte rate
+        write_u16(out, 2)?;        // block align
+        write_u16(out, 16)?;       // bits per sample
+        out.write_all(b"data")?;
+        write_u32(out, data_len)
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            write_u16(&mut self.out, (sample * i16::max_value() as f32) as i16 as u16)?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, samples: &[f32]) {
+        if let Err(e) = self.write_samples(samples) {
+            error!("failed to write wav samples: {}", e);
+        }
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("failed to finish wav file: {}", e);
+        }
+    }
+}
+
+fn write_u16<W: Write>(out: &mut W, value: u16) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
+
+/// The beeper. Samples are generated from emulated time, so a beep always lasts exactly
+/// as many 60 Hz frames as the sound timer says, then handed to whichever sink was chosen
+/// at startup.
+pub struct Sound {
+    sink: Box<AudioSink>,
+    oscillator: Oscillator,
+    // samples generated since the last flush to the sink
+    pending: Vec<f32>,
+    // total samples generated, used to work out how many samples a stretch of emulated
+    // time needs without accumulating rounding errors
+    generated: u64,
+    tone: bool,
+    muted: bool,
+}
+
+impl Sound {
+    pub fn new(backend: &AudioBackend, config: &SoundConfig) -> Result<Sound, String> {
+        let sink: Box<AudioSink> = match *backend {
+            AudioBackend::Auto => match PortAudioSink::new() {
+                Ok(sink) => Box::new(sink),
+                Err(e) => {
+                    warn!("no audio device available ({}); sound is disabled", e);
+                    Box::new(NullSink)
+                }
+            },
+            AudioBackend::PortAudio => match PortAudioSink::new() {
+                Ok(sink) => Box::new(sink),
+                Err(e) => return Err(format!("failed to open audio device: {}", e)),
+            },
+            AudioBackend::Null => Box::new(NullSink),
+            AudioBackend::Wav(ref path) => match WavSink::create(path) {
+                Ok(sink) => Box::new(sink),
+                Err(e) => return Err(format!("failed to create wav file {:?}: {}", path, e)),
+            },
+        };
+        Ok(Sound {
+            sink: sink,
+            oscillator: Oscillator::new(config),
+            pending: Vec::with_capacity(SAMPLE_RATE as usize / 60),
+            generated: 0,
+            tone: false,
+            muted: false,
+        })
+    }
+
+    /// turn the tone on or off from this point in emulated time on; the emulator keeps it
+    /// on while the sound timer is non-zero
+    pub fn set_tone(&mut self, on: bool) {
+        self.tone = on;
+    }
+
+    /// generate samples up to `emulated_ns` nanoseconds after startup
+    pub fn advance_to(&mut self, emulated_ns: u64) {
+        let target = emulated_ns * SAMPLE_RATE as u64 / 1_000_000_000;
+        let on = self.tone && !self.muted;
+        while self.generated < target {
+            let sample = self.oscillator.next_sample(on);
+            self.pending.push(sample);
+            self.generated += 1;
+        }
+    }
+
+    /// returns whether sound is now muted
+    pub fn toggle_mute(&mut self) -> bool {
+        self.muted = !self.muted;
+        self.muted
+    }
+
+    /// hand the samples generated so far to the sink; called at the end of every frame
+    /// and on shutdown
+    pub fn flush(&mut self) {
+        self.sink.write(&self.pending);
+        self.pending.clear();
+    }
+
+    /// block until the sink has consumed enough audio; returns false if the sink can't be
+    /// used to pace the emulator
+    pub fn wait(&mut self) -> bool {
+        self.sink.wait()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+
+    use super::{AudioBackend, SAMPLE_RATE};
+    use chip8::{Chip8, Config};
+
+    // the 16 bit samples in a wav file written by `WavSink`
+    fn read_wav(path: &Path) -> Vec<i16> {
+        let mut data = vec![];
+        File::open(path).and_then(|mut f| f.read_to_end(&mut data)).expect("wav file can be read");
+        assert_eq!(&data[..4], b"RIFF");
+        data[44..].chunks(2).map(|pair| (pair[0] as u16 | (pair[1] as u16) << 8) as i16).collect()
+    }
+
+    #[test]
+    fn wav_beep_lasts_as_long_as_the_sound_timer() {
+        let path = env::temp_dir().join("chip8-sound-test.wav");
+        let mut config = Config::default();
+        config.headless = true;
+        config.audio = AudioBackend::Wav(path.clone());
+        // 100 instructions a frame for 30 frames
+        config.tickrate = Some(100);
+        config.cycles = Some(3000);
+        // VF := 10, ST := VF, then spin
+        Chip8::run(&[0x6F, 0x0A, 0xFF, 0x18, 0x12, 0x04], &config).expect("rom should run");
+
+        let samples = read_wav(&path);
+        fs::remove_file(&path).expect("wav file can be removed");
+        let frame = SAMPLE_RATE as usize / 60;
+        let first = samples.iter().position(|&s| s != 0).expect("the rom beeps");
+        let last = samples.iter().rposition(|&s| s != 0).expect("the rom beeps");
+        // ten frames of tone, give or take the fade in and out
+        let span = last - first + 1;
+        assert!(span > 10 * frame - frame / 2 && span < 10 * frame + frame / 2, "beep lasted {} samples", span);
+        assert!(first < frame, "beep started {} samples in", first);
+        assert!(samples.len() > last + 10 * frame, "sound ended early, at {} samples", samples.len());
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Maps program addresses back to the source lines they were compiled from
+#[derive(Clone)]
+pub struct SourceMap {
+    file: PathBuf,
+    lines: Vec<String>,
+    // 1-based line number of the statement that emitted each address
+    addrs: BTreeMap<usize, usize>,
+}
+
+impl SourceMap {
+    pub fn new<P: AsRef<Path>>(file: P, source: &str) -> SourceMap {
+        SourceMap {
+            file: file.as_ref().to_owned(),
+            lines: source.lines().map(String::from).collect(),
+            addrs: BTreeMap::new(),
+        }
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn insert(&mut self, addr: usize, line: usize) {
+        self.addrs.insert(addr, line);
+    }
+
+    /// the line number and text of the source that produced the code at `addr`
+    pub fn line_for(&self, addr: usize) -> Option<(usize, &str)> {
+        self.addrs.get(&addr).and_then(|&line| {
+            self.lines.get(line - 1).map(|text| (line, text.as_str()))
+        })
+    }
+
+    /// the first address emitted by `line`, or by the closest line after it that emitted
+    /// anything (so breakpoints on comments and labels land on the next instruction)
+    pub fn addr_for_line(&self, line: usize) -> Option<usize> {
+        self.addrs.iter()
+                  .filter(|&(_, &l)| l >= line)
+                  .min_by_key(|&(addr, &l)| (l, *addr))
+                  .map(|(addr, _)| *addr)
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+// how far past a symbol an address can be and still be shown relative to it
+const MAX_OFFSET: usize = 0x100;
+
+/// Names for addresses, from the assembler, Octo, or a hand-written symbol file
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<usize, String>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn from_labels(labels: &[(usize, String)]) -> Symbols {
+        let mut symbols = Symbols::new();
+        for &(addr, ref name) in labels {
+            symbols.insert(addr, name.clone());
+        }
+        symbols
+    }
+
+    /// read a symbol file with one `ADDR name` pair per line and the address in hex, as
+    /// written by the `asm` subcommand. Blank lines and lines starting with `#` or `;` are
+    /// skipped.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Symbols> {
+        let mut symbols = Symbols::new();
+        for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let addr = fields.next().and_then(|a| usize::from_str_radix(a.trim_left_matches("0x"), 16).ok());
+            match (addr, fields.next(), fields.next()) {
+                (Some(addr), Some(name), None) => symbols.insert(addr, name.to_owned()),
+                _ => {
+                    let message = format!("line {}: expected 'ADDR name' but found '{}'", idx + 1, line);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    /// add a symbol; the first name given to an address is the one shown for it
+    pub fn insert(&mut self, addr: usize, name: String) {
+        self.by_addr.entry(addr).or_insert(name.clone());
+        self.by_name.insert(name, addr);
+    }
+
+    pub fn merge(&mut self, other: &Symbols) {
+        for (&addr, name) in &other.by_addr {
+            self.insert(addr, name.clone());
+        }
+    }
+
+    /// the symbol at exactly `addr`
+    pub fn name(&self, addr: usize) -> Option<&str> {
+        self.by_addr.get(&addr).map(|name| name.as_str())
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// `addr` relative to the closest symbol at or before it, like `draw_player+4`
+    pub fn describe(&self, addr: usize) -> Option<String> {
+        match self.by_addr.range(..addr + 1).next_back() {
+            Some((&start, name)) if addr == start => Some(name.clone()),
+            Some((&start, name)) if addr - start < MAX_OFFSET => Some(format!("{}+{}", name, addr - start)),
+            _ => None,
+        }
+    }
+
+    /// `addr` in hex, followed by its description if it has one
+    pub fn format_addr(&self, addr: usize) -> String {
+        match self.describe(addr) {
+            Some(name) => format!("{:03X} <{}>", addr, name),
+            None => format!("{:03X}", addr),
+        }
+    }
+
+    /// an address given as a symbol name or a hex number. Symbols come first, and a hex
+    /// number has to start with a digit, `0x` or `$`, so that a name like `add` that isn't a
+    /// symbol is reported rather than taken as an address.
+    pub fn parse_addr(&self, text: &str) -> Option<usize> {
+        if let Some(addr) = self.lookup(text) {
+            return Some(addr);
+        }
+        let hex = if text.starts_with("0x") || text.starts_with("0X") {
+            &text[2..]
+        } else if text.starts_with('$') {
+            &text[1..]
+        } else if text.starts_with(|c: char| c.is_digit(10)) {
+            text
+        } else {
+            return None;
+        };
+        usize::from_str_radix(hex, 16).ok()
+    }
+}
@@ -1,18 +1,11 @@
-use time::{now, Tm};
-
-const TIMER_SPEED_NS: i64 = 1_000_000_000 / 60;
-
 pub struct Timer {
     value: u8,
-    at: Tm,
-
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
             value: 0,
-            at: now(),
         }
     }
 
@@ -24,14 +17,10 @@ impl Timer {
         self.value = value;
     }
 
-    pub fn cycle(&mut self) {
+    /// count down by one; called at the end of every 60 Hz frame of emulated time
+    pub fn tick(&mut self) {
         if self.value > 0 {
-            let current = now();
-            let diff = (current - self.at).num_nanoseconds().unwrap();
-            if diff > TIMER_SPEED_NS {
-                self.value -= 1;
-                self.at = current;
-            }
+            self.value -= 1;
         }
     }
 }
@@ -0,0 +1,87 @@
+use opcodes::OpCode;
+use opcodes::OpCode::*;
+
+// the COSMAC VIP's 1802 runs at 1.7609 MHz, with 8 clocks to a machine cycle
+pub const VIP_CYCLE_NS: u64 = 8 * 1_000_000_000 / 1_760_900;
+
+// machine cycles the interpreter loses every frame: the 1861 takes 8 cycles of DMA for each
+// of its 128 lines, and the interrupt routine that sets up the DMA and counts down the timers
+// takes the rest
+const DMA_CYCLES: u64 = 8 * 128;
+const INTERRUPT_ROUTINE_CYCLES: u64 = 46;
+pub const VIP_INTERRUPT_NS: u64 = (DMA_CYCLES + INTERRUPT_ROUTINE_CYCLES) * VIP_CYCLE_NS;
+
+// fetching and decoding an instruction, before jumping to the code for it
+const FETCH_CYCLES: u64 = 40;
+// taking a skip costs the extra branch past the next instruction
+const SKIP_CYCLES: u64 = 4;
+
+/// How long each instruction takes in emulated time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timing {
+    // every instruction takes the same time, set by the tickrate
+    Fixed,
+    // instructions take as long as they did in the COSMAC VIP's interpreter, DXYN waits for
+    // the vertical blank, and each frame's interrupt takes time away from the interpreter
+    Vip,
+}
+
+impl Timing {
+    pub fn parse(name: &str) -> Option<Timing> {
+        match name {
+            "fixed" => Some(Timing::Fixed),
+            "vip" => Some(Timing::Vip),
+            _ => None,
+        }
+    }
+}
+
+/// machine cycles the VIP's interpreter takes to run `opcode`, not counting the wait for the
+/// vertical blank before a draw. `v` holds the registers as they were before the instruction,
+/// and `skipped` is whether it skipped the next one.
+pub fn vip_cycles(opcode: OpCode, v: &[u8], skipped: bool) -> u64 {
+    let cycles = match opcode {
+        // a loop storing zero over each of the 256 bytes of the display buffer
+        DrawClr => 24 + 6 * 256,
+        Return => 10,
+        JpConst { .. } => 12,
+        Call { .. } => 26,
+        SkpEqConst { .. } | SkpNeConst { .. } => 10 + skip(skipped),
+        SkpEqReg { .. } | JpRegNe { .. } => 14 + skip(skipped),
+        SetConst { .. } => 6,
+        AddConst { .. } => 10,
+        // 8XYN runs its operation from a small routine built in memory
+        SetReg { .. } | SetRegBor { .. } | SetRegBand { .. } | SetRegBxor { .. } | SetRegAdd { .. } |
+        SetRegSub { .. } | SetShr1 { .. } | SetRegRevSub { .. } | SetShl1 { .. } => 44,
+        SetI { .. } => 12,
+        JpOffset { .. } => 22,
+        SetRand { .. } => 36,
+        Draw { x, n, .. } => {
+            // sprites not on a byte boundary are shifted across two bytes of every row
+            let row = if v[x] % 8 == 0 { 16 } else { 28 };
+            26 + row * n as u64
+        }
+        SkpKeyEq { .. } | SkpKeyNe { .. } => 14 + skip(skipped),
+        SetRegDelay { .. } | SetDelay { .. } | SetSound { .. } => 10,
+        // the time spent waiting for the key isn't counted here
+        SetKey { .. } => 18,
+        SetIRegAdd { .. } => 16,
+        SetISprite { .. } => 20,
+        SetBCD { x } => {
+            // each digit is found by subtracting 100s, then 10s, then 1s until it would go
+            // below zero
+            let value = v[x];
+            let subtractions = (value / 100 + value / 10 % 10 + value % 10) as u64;
+            84 + 16 * subtractions
+        }
+        DumpReg { x } | LoadReg { x } => 14 + 14 * (x as u64 + 1),
+        // getting into the routine and back; the routine's own time isn't known
+        Sys { .. } => 14,
+        Eof | Unknown(_) => 0,
+    };
+    FETCH_CYCLES + cycles
+}
+
+fn skip(skipped: bool) -> u64 {
+    if skipped { SKIP_CYCLES } else { 0 }
+}
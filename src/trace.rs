@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde_json::{self, Value};
+
+use cpu::{CpuState, GP_REG_COUNT};
+
+/// Everything that happened during one instruction. Records aren't compared whole, since the
+/// mnemonic and symbol are only there to be read; `trace_diff::differences` says what counts.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    // the instruction's position in the run, counting from 1
+    pub counter: u64,
+    pub pc: usize,
+    // the pc relative to the nearest symbol, when symbols are loaded
+    pub symbol: Option<String>,
+    pub instr: u16,
+    pub mnemonic: String,
+    pub before: CpuState,
+    pub after: CpuState,
+    // memory writes made by the instruction as (address, value)
+    pub writes: Vec<(usize, u8)>,
+    // timer values after the instruction
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    // hash of the display after the instruction
+    pub display: u64,
+}
+
+fn state_to_json(state: &CpuState) -> Value {
+    json!({
+        "pc": state.pc,
+        "i": state.i,
+        "v": state.v.to_vec(),
+        "stack": state.stack,
+    })
+}
+
+fn state_from_json(value: &Value, counter: u64) -> Option<CpuState> {
+    let v = value.get("v")?.as_array()?;
+    if v.len() != GP_REG_COUNT {
+        return None;
+    }
+    let mut regs = [0; GP_REG_COUNT];
+    for (reg, value) in regs.iter_mut().zip(v) {
+        *reg = value.as_u64()? as u8;
+    }
+    let mut stack = vec![];
+    for addr in value.get("stack")?.as_array()? {
+        stack.push(addr.as_u64()? as usize);
+    }
+    Some(CpuState {
+        counter: counter,
+        pc: value.get("pc")?.as_u64()? as usize,
+        i: value.get("i")?.as_u64()? as u16,
+        v: regs,
+        stack: stack,
+    })
+}
+
+impl TraceRecord {
+    pub fn to_json(&self) -> Value {
+        let writes: Vec<Value> = self.writes.iter().map(|&(addr, value)| json!([addr, value])).collect();
+        let mut record = json!({
+            "n": self.counter,
+            "pc": self.pc,
+            "op": self.instr,
+            "asm": self.mnemonic,
+            "before": state_to_json(&self.before),
+            "after": state_to_json(&self.after),
+            "writes": writes,
+            "dt": self.delay_timer,
+            "st": self.sound_timer,
+            // as a string, since json readers often can't hold a full 64-bit integer
+            "display": format!("{:016x}", self.display),
+        });
+        if let Some(ref symbol) = self.symbol {
+            record["sym"] = json!(symbol);
+        }
+        record
+    }
+
+    pub fn from_json(value: &Value) -> Option<TraceRecord> {
+        let counter = value.get("n")?.as_u64()?;
+        let mut writes = vec![];
+        for write in value.get("writes")?.as_array()? {
+            let write = write.as_array()?;
+            writes.push((write.get(0)?.as_u64()? as usize, write.get(1)?.as_u64()? as u8));
+        }
+        Some(TraceRecord {
+            counter: counter,
+            pc: value.get("pc")?.as_u64()? as usize,
+            symbol: value.get("sym").and_then(|s| s.as_str()).map(String::from),
+            instr: value.get("op")?.as_u64()? as u16,
+            mnemonic: value.get("asm")?.as_str()?.to_owned(),
+            // counters start at 1, so a record numbered 0 isn't one of ours
+            before: state_from_json(value.get("before")?, counter.checked_sub(1)?)?,
+            after: state_from_json(value.get("after")?, counter)?,
+            writes: writes,
+            delay_timer: value.get("dt")?.as_u64()? as u8,
+            sound_timer: value.get("st")?.as_u64()? as u8,
+            display: u64::from_str_radix(value.get("display")?.as_str()?, 16).ok()?,
+        })
+    }
+}
+
+/// Which instructions end up in a trace
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    // only instructions at addresses in this inclusive range
+    pub addrs: Option<(usize, usize)>,
+    // only instructions with counters in this inclusive range
+    pub window: Option<(u64, u64)>,
+}
+
+impl TraceFilter {
+    /// parse an inclusive `FIRST-LAST` range; either end may be left out
+    pub fn parse_range(range: &str, radix: u32) -> Result<(u64, u64), String> {
+        let mut parts = range.splitn(2, '-');
+        let parse = |part: Option<&str>, default: u64| -> Result<u64, String> {
+            match part.map(|p| p.trim().trim_left_matches("0x")) {
+                Some("") | None => Ok(default),
+                Some(p) => u64::from_str_radix(p, radix).map_err(|e| format!("invalid bound '{}': {}", p, e)),
+            }
+        };
+        let first = parse(parts.next(), 0)?;
+        let last = parse(parts.next(), u64::max_value())?;
+        if first > last {
+            return Err(format!("range {} is backwards", range));
+        }
+        Ok((first, last))
+    }
+
+    pub fn includes(&self, counter: u64, pc: usize) -> bool {
+        let in_addrs = self.addrs.map(|(first, last)| pc >= first && pc <= last).unwrap_or(true);
+        let in_window = self.window.map(|(first, last)| counter >= first && counter <= last).unwrap_or(true);
+        in_addrs && in_window
+    }
+
+    /// true once no later instruction can pass the filter
+    pub fn finished(&self, counter: u64) -> bool {
+        self.window.map(|(_, last)| counter >= last).unwrap_or(false)
+    }
+}
+
+/// Writes trace records to a file, one json object per line
+pub struct Tracer {
+    out: BufWriter<File>,
+    pub filter: TraceFilter,
+}
+
+impl Tracer {
+    pub fn create<P: AsRef<Path>>(path: P, filter: TraceFilter) -> io::Result<Tracer> {
+        Ok(Tracer {
+            out: BufWriter::new(File::create(path)?),
+            filter: filter,
+        })
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, &record.to_json())?;
+        writeln!(self.out)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Read every record of a trace file
+pub fn read_trace<P: AsRef<Path>>(path: P) -> io::Result<Vec<TraceRecord>> {
+    let file = File::open(path)?;
+    let mut records = vec![];
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).ok().as_ref().and_then(TraceRecord::from_json);
+        match record {
+            Some(record) => records.push(record),
+            None => {
+                let message = format!("line {} is not a trace record", idx + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceRecord;
+    use cpu::CpuState;
+
+    fn record() -> TraceRecord {
+        let state = |counter: u64, pc: usize| CpuState { counter: counter, pc: pc, i: 0x300, v: [7; 16], stack: vec![0x202] };
+        TraceRecord {
+            counter: 5,
+            pc: 0x204,
+            symbol: Some("draw+4".to_owned()),
+            instr: 0xF055,
+            mnemonic: "LD [I], V0".to_owned(),
+            before: state(4, 0x204),
+            after: state(5, 0x206),
+            writes: vec![(0x300, 7)],
+            delay_timer: 3,
+            sound_timer: 0,
+            display: 0xFFFF_FFFF_FFFF_FFFF,
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = record().to_json();
+        let parsed = TraceRecord::from_json(&json).expect("a written record can be read back");
+        assert_eq!(parsed.to_json(), json);
+        assert_eq!(parsed.before.counter, 4);
+    }
+
+    #[test]
+    fn bad_records() {
+        let mut json = record().to_json();
+        json["n"] = json!(0);
+        assert!(TraceRecord::from_json(&json).is_none(), "records are numbered from 1");
+        let mut json = record().to_json();
+        json["display"] = json!(0);
+        assert!(TraceRecord::from_json(&json).is_none(), "the display hash is a hex string");
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use cpu::CpuState;
+use trace::TraceRecord;
+
+/// Compares two traces instruction by instruction, lined up by instruction counter
+pub struct TraceDiff<'a> {
+    left: BTreeMap<u64, &'a TraceRecord>,
+    right: BTreeMap<u64, &'a TraceRecord>,
+}
+
+fn state_differences(name: &str, left: &CpuState, right: &CpuState, out: &mut Vec<String>) {
+    if left.pc != right.pc {
+        out.push(format!("{} pc: {:03X} vs {:03X}", name, left.pc, right.pc));
+    }
+    if left.i != right.i {
+        out.push(format!("{} i: {:03X} vs {:03X}", name, left.i, right.i));
+    }
+    for (reg, (l, r)) in left.v.iter().zip(right.v.iter()).enumerate() {
+        if l != r {
+            out.push(format!("{} v{:X}: {:02X} vs {:02X}", name, reg, l, r));
+        }
+    }
+    if left.stack != right.stack {
+        out.push(format!("{} stack: {} vs {}", name, format_stack(&left.stack), format_stack(&right.stack)));
+    }
+}
+
+fn format_stack(stack: &[usize]) -> String {
+    let addrs: Vec<String> = stack.iter().map(|addr| format!("{:03X}", addr)).collect();
+    format!("[{}]", addrs.join(" "))
+}
+
+fn format_writes(writes: &[(usize, u8)]) -> String {
+    let writes: Vec<String> = writes.iter().map(|&(addr, value)| format!("{:03X}={:02X}", addr, value)).collect();
+    format!("[{}]", writes.join(" "))
+}
+
+/// every way two records of the same instruction differ, as readable lines
+pub fn differences(left: &TraceRecord, right: &TraceRecord) -> Vec<String> {
+    let mut out = vec![];
+    if left.pc != right.pc || left.instr != right.instr {
+        out.push(format!("instruction: {:03X} {:04X} {} vs {:03X} {:04X} {}",
+                         left.pc, left.instr, left.mnemonic, right.pc, right.instr, right.mnemonic));
+    }
+    state_differences("before", &left.before, &right.before, &mut out);
+    state_differences("after", &left.after, &right.after, &mut out);
+    if left.writes != right.writes {
+        out.push(format!("memory writes: {} vs {}", format_writes(&left.writes), format_writes(&right.writes)));
+    }
+    if left.delay_timer != right.delay_timer {
+        out.push(format!("delay timer: {:02X} vs {:02X}", left.delay_timer, right.delay_timer));
+    }
+    if left.sound_timer != right.sound_timer {
+        out.push(format!("sound timer: {:02X} vs {:02X}", left.sound_timer, right.sound_timer));
+    }
+    if left.display != right.display {
+        out.push(format!("display: {:016x} vs {:016x}", left.display, right.display));
+    }
+    out
+}
+
+fn summary_line(record: Option<&&TraceRecord>) -> String {
+    match record {
+        Some(r) => format!("{:03X} {:04X} {:<20}", r.pc, r.instr, r.mnemonic),
+        None => format!("{:<29}", "-"),
+    }
+}
+
+impl<'a> TraceDiff<'a> {
+    pub fn new(left: &'a [TraceRecord], right: &'a [TraceRecord]) -> TraceDiff<'a> {
+        TraceDiff {
+            left: left.iter().map(|r| (r.counter, r)).collect(),
+            right: right.iter().map(|r| (r.counter, r)).collect(),
+        }
+    }
+
+    fn counters(&self) -> Vec<u64> {
+        let mut counters: Vec<u64> = self.left.keys().chain(self.right.keys()).cloned().collect();
+        counters.sort();
+        counters.dedup();
+        counters
+    }
+
+    fn differs(&self, counter: u64) -> bool {
+        match (self.left.get(&counter), self.right.get(&counter)) {
+            // only what `differences` can explain counts, so mnemonics and symbols don't
+            (Some(l), Some(r)) => !differences(l, r).is_empty(),
+            _ => true,
+        }
+    }
+
+    /// counter of the first instruction that differs, or that only one trace has
+    pub fn first_divergence(&self) -> Option<u64> {
+        self.counters().into_iter().find(|&counter| self.differs(counter))
+    }
+
+    /// runs of consecutive differing instructions as inclusive (first, last) counters
+    pub fn divergent_regions(&self) -> Vec<(u64, u64)> {
+        let mut regions: Vec<(u64, u64)> = vec![];
+        for counter in self.counters() {
+            if !self.differs(counter) {
+                continue;
+            }
+            match regions.last_mut() {
+                Some(&mut (_, ref mut last)) if *last + 1 == counter => {
+                    *last = counter;
+                    continue;
+                }
+                _ => (),
+            }
+            regions.push((counter, counter));
+        }
+        regions
+    }
+
+    /// describe the first divergence with `context` instructions either side
+    pub fn report(&self, context: u64) -> Option<String> {
+        let first = self.first_divergence()?;
+        let mut out = String::new();
+        writeln!(out, "first divergence at instruction {}:", first).unwrap();
+        match (self.left.get(&first), self.right.get(&first)) {
+            (Some(l), Some(r)) => {
+                for line in differences(l, r) {
+                    writeln!(out, "    {}", line).unwrap();
+                }
+            }
+            (Some(_), None) => writeln!(out, "    only in the left trace").unwrap(),
+            _ => writeln!(out, "    only in the right trace").unwrap(),
+        }
+
+        writeln!(out, "").unwrap();
+        writeln!(out, "    {:>10}  {:<29}  {}", "#", "left", "right").unwrap();
+        let from = first.saturating_sub(context);
+        let to = first.saturating_add(context).saturating_add(1);
+        // only the counters the traces have, since the context can be huge
+        let mut counters: Vec<u64> = self.left.range(from..to).chain(self.right.range(from..to))
+                                              .map(|(&counter, _)| counter)
+                                              .collect();
+        counters.sort();
+        counters.dedup();
+        for counter in counters {
+            let (l, r) = (self.left.get(&counter), self.right.get(&counter));
+            let marker = if counter == first { ">" } else if self.differs(counter) { "*" } else { " " };
+            let line = format!("  {} {:>10}  {}  {}", marker, counter, summary_line(l), summary_line(r));
+            writeln!(out, "{}", line.trim_right()).unwrap();
+        }
+        Some(out)
+    }
+
+    /// list every divergent region
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        let regions = self.divergent_regions();
+        let total: u64 = regions.iter().map(|&(first, last)| last - first + 1).sum();
+        writeln!(out, "{} divergent instructions in {} regions:", total, regions.len()).unwrap();
+        for (first, last) in regions {
+            let pc = |counter: u64| {
+                self.left.get(&counter).or(self.right.get(&counter)).map(|r| r.pc).unwrap_or(0)
+            };
+            writeln!(out, "    {:>10}-{:<10} ({} instructions, from {:03X} to {:03X})",
+                     first, last, last - first + 1, pc(first), pc(last)).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceDiff;
+    use cpu::CpuState;
+    use trace::TraceRecord;
+
+    fn state(counter: u64, pc: usize, v0: u8) -> CpuState {
+        let mut v = [0; 16];
+        v[0] = v0;
+        CpuState { counter: counter, pc: pc, i: 0, v: v, stack: vec![] }
+    }
+
+    // `ADD V0, 1` records, the nth leaving `v0s[n]` in V0
+    fn trace(v0s: &[u8]) -> Vec<TraceRecord> {
+        v0s.iter().enumerate().map(|(idx, &v0)| {
+            let counter = idx as u64 + 1;
+            let pc = 0x200 + 2 * idx;
+            TraceRecord {
+                counter: counter,
+                pc: pc,
+                symbol: None,
+                instr: 0x7001,
+                mnemonic: "ADD V0, 0x01".to_owned(),
+                before: state(counter - 1, pc, v0.wrapping_sub(1)),
+                after: state(counter, pc + 2, v0),
+                writes: vec![],
+                delay_timer: 0,
+                sound_timer: 0,
+                display: 0,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn identical_traces() {
+        let (left, mut right) = (trace(&[1, 2, 3]), trace(&[1, 2, 3]));
+        // only what the emulator did counts, not how it was labelled
+        right[1].symbol = Some("main+2".to_owned());
+        let diff = TraceDiff::new(&left, &right);
+        assert_eq!(diff.first_divergence(), None);
+        assert!(diff.report(5).is_none());
+        assert!(diff.divergent_regions().is_empty());
+    }
+
+    #[test]
+    fn first_divergence() {
+        let (left, right) = (trace(&[1, 2, 3, 4, 5]), trace(&[1, 2, 9, 4, 5, 6]));
+        let diff = TraceDiff::new(&left, &right);
+        assert_eq!(diff.first_divergence(), Some(3));
+        assert_eq!(diff.divergent_regions(), vec![(3, 3), (6, 6)]);
+
+        let report = diff.report(1).expect("the traces differ");
+        assert!(report.starts_with("first divergence at instruction 3:\n"), "{}", report);
+        assert!(report.contains("after v0: 03 vs 09"), "{}", report);
+        let rows: Vec<&str> = report.lines().filter(|line| line.contains("ADD V0")).collect();
+        assert_eq!(rows.len(), 3, "{}", report);
+        assert!(rows[1].starts_with("  >          3"), "{}", report);
+    }
+
+    #[test]
+    fn huge_context() {
+        let (left, right) = (trace(&[1, 2]), trace(&[1, 3]));
+        let report = TraceDiff::new(&left, &right).report(u64::max_value()).expect("the traces differ");
+        assert!(report.contains("  >          2"), "{}", report);
+    }
+}
@@ -0,0 +1,248 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use time;
+
+use capture;
+use cdp1802::{Bus, Cdp1802};
+use chip8::Config;
+use display::{Display, HEIGHT, WIDTH};
+use keyboard::{Hotkey, Keyboard};
+use memory_bus::ROM_START;
+use rom::RomError;
+use sound::Sound;
+use timing::VIP_CYCLE_NS;
+use window::Window;
+
+const RAM_SIZE: usize = 4 * 1024;
+// the interpreter keeps its stack, variables and display buffer at the top of memory
+pub const INTERPRETER_RAM_START: usize = 0xEA0;
+// where the interpreter keeps V0 to VF
+const V_REGISTERS: usize = 0xEF0;
+
+// the 1861 draws 262 lines a frame, 14 machine cycles each
+const LINE_CYCLES: u32 = 14;
+const FRAME_LINES: u32 = 262;
+const FRAME_CYCLES: u32 = LINE_CYCLES * FRAME_LINES;
+// lines 80 to 207 are shown, each starting with 8 cycles of DMA
+const FIRST_LINE: u32 = 80;
+const DISPLAY_LINES: u32 = 128;
+// the interrupt comes just over 2 lines before the first is shown. The interpreter's interrupt
+// routine counts on this to have its loop, which puts R0 back to show each row 4 times, in step
+// with the DMA.
+const INTERRUPT_CYCLE: u32 = FIRST_LINE * LINE_CYCLES - 31;
+// EF1 is raised for the 4 lines before the display starts and the 4 before it ends
+const EF1_LINES: u32 = 4;
+
+/// Everything on the VIP's bus besides the 1802: RAM, the 1861 and the hex keypad
+struct Hardware {
+    ram: Vec<u8>,
+    // machine cycles into the current frame
+    cycle: u32,
+    display_on: bool,
+    // the key the keypad is asked about with OUT 2
+    key: u8,
+    keys: [bool; 16],
+    q: bool,
+}
+
+impl Bus for Hardware {
+    fn read(&mut self, addr: u16) -> u8 {
+        // RAM repeats through the lower 32K; the monitor ROM above isn't there
+        if addr < 0x8000 { self.ram[addr as usize % RAM_SIZE] } else { 0 }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr < 0x8000 {
+            self.ram[addr as usize % RAM_SIZE] = value;
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        // INP 1 turns the 1861 on
+        if port == 1 {
+            self.display_on = true;
+        }
+        0
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.key = value & 0xF,
+            _ => (),
+        }
+    }
+
+    fn flag(&mut self, flag: usize) -> bool {
+        match flag {
+            0 => {
+                let line = self.cycle / LINE_CYCLES;
+                let end = FIRST_LINE + DISPLAY_LINES;
+                self.display_on && (line >= FIRST_LINE - EF1_LINES && line < FIRST_LINE ||
+                                    line >= end - EF1_LINES && line < end)
+            }
+            // EF3 is low while the key picked with OUT 2 is held
+            2 => self.keys[self.key as usize],
+            _ => false,
+        }
+    }
+
+    fn set_q(&mut self, on: bool) {
+        self.q = on;
+    }
+}
+
+/// A COSMAC VIP running CHIP-8 the way it first did: an 1802 running the original
+/// interpreter out of RAM, with the rom loaded after it
+pub struct Vip {
+    cpu: Cdp1802,
+    hw: Hardware,
+    // one byte per pixel, as `Display` has it
+    grid: Vec<u8>,
+}
+
+impl Vip {
+    /// `interpreter` goes at 0 and `rom` at 0x200, which has to end before the interpreter's
+    /// own memory
+    pub fn new(interpreter: &[u8], rom: &[u8]) -> Result<Vip, RomError> {
+        let max = INTERPRETER_RAM_START - ROM_START;
+        if rom.len() > max {
+            return Err(RomError::TooLarge { size: rom.len(), max: max });
+        }
+        let mut ram = vec![0; RAM_SIZE];
+        ram[..interpreter.len()].copy_from_slice(interpreter);
+        ram[ROM_START..ROM_START + rom.len()].copy_from_slice(rom);
+        let mut cpu = Cdp1802::new();
+        // the monitor leaves the top page of RAM in R1 after sizing memory, which the
+        // interpreter puts its stack and display buffer under
+        cpu.set_register(1, ((RAM_SIZE - 1) & 0xFF00) as u16);
+        Ok(Vip {
+            cpu: cpu,
+            hw: Hardware {
+                ram: ram,
+                cycle: 0,
+                display_on: false,
+                key: 0,
+                keys: [false; 16],
+                q: false,
+            },
+            grid: vec![0; WIDTH * HEIGHT],
+        })
+    }
+
+    /// the interpreter's V0 to VF
+    pub fn chip8_registers(&self) -> &[u8] {
+        &self.hw.ram[V_REGISTERS..V_REGISTERS + 16]
+    }
+
+    /// run a frame's worth of machine cycles with `keys` held, calling `beep` with the emulated
+    /// time into the frame whenever Q changes
+    fn run_frame<F>(&mut self, keys: [bool; 16], mut beep: F)
+        where F: FnMut(u64, bool)
+    {
+        self.hw.keys = keys;
+        self.hw.cycle = 0;
+        let mut interrupted = false;
+        let mut line = FIRST_LINE;
+        while self.hw.cycle < FRAME_CYCLES {
+            let q = self.hw.q;
+            if self.hw.display_on && !interrupted && self.hw.cycle >= INTERRUPT_CYCLE {
+                interrupted = true;
+                self.hw.cycle += self.cpu.interrupt();
+            } else if self.hw.display_on && line < FIRST_LINE + DISPLAY_LINES && self.hw.cycle >= line * LINE_CYCLES {
+                // the interpreter shows each row of its 64x32 buffer on 4 lines
+                let row = ((line - FIRST_LINE) / 4) as usize;
+                for byte in 0..WIDTH / 8 {
+                    let value = self.cpu.dma_out(&mut self.hw);
+                    for bit in 0..8 {
+                        self.grid[row * WIDTH + byte * 8 + bit] = (value >> (7 - bit)) & 1;
+                    }
+                }
+                self.hw.cycle += (WIDTH / 8) as u32;
+                line += 1;
+            } else {
+                self.hw.cycle += self.cpu.step(&mut self.hw);
+            }
+            if self.hw.q != q {
+                beep(self.hw.cycle as u64 * VIP_CYCLE_NS, self.hw.q);
+            }
+        }
+    }
+}
+
+/// run `rom` under the VIP's own `interpreter` until the window is closed, or for
+/// `config.frames` frames
+pub fn run(interpreter: &[u8], rom: &[u8], config: &Config) -> Result<(), String> {
+    let mut vip = Vip::new(interpreter, rom).map_err(|e| format!("could not load the rom: {}", e))?;
+    let window = if config.headless {
+        None
+    } else {
+        let title = match config.title {
+            Some(ref title) => format!("COSMAC VIP - {}", title),
+            None => "COSMAC VIP".to_owned(),
+        };
+        Some(Arc::new(Mutex::new(Window::new(64, 32, false, &title, config.palette))))
+    };
+    let mut display = Display::new(window.clone());
+    let mut keyboard = Keyboard::new(window.clone());
+    let mut sound = Sound::new(&config.audio, &config.sound)?;
+
+    let frame_ns = FRAME_CYCLES as u64 * VIP_CYCLE_NS;
+    let start_time = time::get_time();
+    let mut frames = 0;
+    while config.frames.map(|limit| frames < limit).unwrap_or(true) {
+        let frame_start_ns = frames * frame_ns;
+        let keys = keyboard.pressed_keys();
+        {
+            let sound = &mut sound;
+            vip.run_frame(keys, |ns, on| {
+                sound.advance_to(frame_start_ns + ns);
+                sound.set_tone(on);
+            });
+        }
+        frames += 1;
+        sound.advance_to(frames * frame_ns);
+        sound.flush();
+        if display.grid() != &vip.grid[..] {
+            display.set_grid(&vip.grid);
+        }
+
+        for hotkey in keyboard.take_hotkeys() {
+            match hotkey {
+                Hotkey::Screenshot => {
+                    let path = format!("chip8-{:06}.png", frames);
+                    match capture::save_png(&path, display.grid(), config.scale, &config.palette) {
+                        Ok(()) => info!("saved screenshot to {}", path),
+                        Err(e) => error!("failed to save screenshot to {}: {}", path, e),
+                    }
+                }
+                Hotkey::ToggleMute => {
+                    let muted = sound.toggle_mute();
+                    info!("sound {}", if muted { "muted" } else { "unmuted" });
+                }
+                hotkey => debug!("{:?} isn't available on the VIP", hotkey),
+            }
+        }
+
+        // keep to the VIP's frame rate in wall-clock time
+        let elapsed = (time::get_time() - start_time).num_nanoseconds().unwrap_or(i64::max_value());
+        let due = (frames * frame_ns) as i64;
+        if elapsed < due {
+            let nanos = (due - elapsed) as u64;
+            thread::sleep(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32));
+        }
+    }
+
+    if let Some(ref path) = config.screenshot {
+        match capture::save_png(path, display.grid(), config.scale, &config.palette) {
+            Ok(()) => info!("saved screenshot to {:?}", path),
+            Err(e) => error!("failed to save screenshot to {:?}: {}", path, e),
+        }
+    }
+    sound.flush();
+    let v: Vec<String> = vip.chip8_registers().iter().map(|v| format!("{:02X}", v)).collect();
+    info!("Shutdown after {} frames -- V0-VF {}", frames, v.join(" "));
+    Ok(())
+}
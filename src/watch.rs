@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use octo;
+use rom;
+use source_map::SourceMap;
+use symbols::Symbols;
+
+/// What a reloaded rom starts from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reload {
+    // power on afresh
+    Reset,
+    // carry on with the registers, memory and display as they were, around the new code
+    Keep,
+    // the state last saved with F6, or afresh if nothing has been saved
+    Saved,
+}
+
+impl Reload {
+    pub fn parse(name: &str) -> Option<Reload> {
+        match name {
+            "reset" => Some(Reload::Reset),
+            "keep" => Some(Reload::Keep),
+            "saved" => Some(Reload::Saved),
+            _ => None,
+        }
+    }
+}
+
+/// Notices when a file is written to, by polling its modification time
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Watcher {
+    pub fn new<P: AsRef<Path>>(path: P) -> Watcher {
+        let path = path.as_ref().to_owned();
+        Watcher {
+            modified: modified(&path),
+            path: path,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// true if the file has been written since the last call
+    pub fn changed(&mut self) -> bool {
+        let modified = modified(&self.path);
+        // a file that's gone missing is probably halfway through being rewritten
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// the rom in the file at `path`, compiling it first if it's octo source
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(Vec<u8>, Option<SourceMap>, Symbols), String> {
+    let path = path.as_ref();
+    if path.extension().map(|ext| ext == "8o").unwrap_or(false) {
+        let compiled = octo::compile_file(path).map_err(|e| e.to_string())?;
+        let symbols = Symbols::from_labels(&compiled.assembly.labels);
+        return Ok((compiled.assembly.rom, Some(compiled.source_map), symbols));
+    }
+    match rom::load(path) {
+        Ok(rom) => {
+            debug!("Read {} bytes from bin file {:?}", rom.len(), path);
+            Ok((rom, None, Symbols::new()))
+        }
+        Err(e) => Err(format!("could not load {}: {}", path.display(), e)),
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use glium::backend::glutin_backend::GlutinFacade;
-use glium::glutin::{ElementState, Event};
+use glium::glutin::{ElementState, Event, VirtualKeyCode};
 use glium::{
     DisplayBuild,
     VertexBuffer,
@@ -14,7 +14,10 @@ use glium::{
     uniforms,
 };
 
-use keyboard::Keyboard;
+use capture::Palette;
+use display::{HEIGHT, WIDTH};
+use keyboard::{Hotkey, Keyboard};
+use overlay::{PANEL_HEIGHT, PANEL_WIDTH};
 
 const FRAG_SHADER: &'static str = r#"
 #version 400
@@ -77,13 +80,22 @@ pub struct Window {
     index_buffer: IndexBuffer<u16>,
     program: Program,
     key_states: HashMap<u8, ElementState>,
+    // keys that play keypad keys on top of the usual layout, e.g. arrows for a game's controls
+    bindings: HashMap<VirtualKeyCode, u8>,
+    hotkeys: Vec<Hotkey>,
+    palette: Palette,
+    // the last display drawn, kept so the panel can be redrawn on its own
+    grid: Vec<u8>,
+    // the debugger overlay under the display, one byte per window pixel, if it's shown
+    panel: Option<Vec<u8>>,
 }
 
 impl Window {
-    pub fn new(grid_width: usize, grid_height: usize) -> Window {
+    pub fn new(grid_width: usize, grid_height: usize, overlay: bool, title: &str, palette: Palette) -> Window {
+        let panel_height = if overlay { PANEL_HEIGHT } else { 0 };
         let display = glutin::WindowBuilder::new()
-            .with_dimensions(640, 320)
-            .with_title("CHIP-8")
+            .with_dimensions(640, (320 + panel_height) as u32)
+            .with_title(title)
             .build_glium()
             .expect("failed to build glutin window");
         let vertex_buffer = VertexBuffer::new(&display, &SLAB).expect("VB");
@@ -100,15 +112,30 @@ impl Window {
             index_buffer: index_buffer,
             program: program,
             key_states: key_states,
+            bindings: HashMap::new(),
+            hotkeys: vec![],
+            palette: palette,
+            grid: vec![0; WIDTH * HEIGHT],
+            panel: if overlay { Some(vec![0; PANEL_WIDTH * PANEL_HEIGHT]) } else { None },
         }
     }
 
+    pub fn bind_key(&mut self, key: VirtualKeyCode, hex_code: u8) {
+        self.bindings.insert(key, hex_code);
+    }
+
+    fn hex_code(&self, key: VirtualKeyCode) -> Option<u8> {
+        self.bindings.get(&key).cloned().or_else(|| Keyboard::remap_key(key))
+    }
+
     pub fn poll_events(&mut self) {
         for ev in self.display.poll_events() {
             match ev {
                 Event::KeyboardInput(state, _, Some(key)) => {
-                    if let Some(hex_code) = Keyboard::remap_key(key) {
+                    if let Some(hex_code) = self.hex_code(key) {
                         self.key_states.insert(hex_code, state);
+                    } else if let ElementState::Pressed = state {
+                        self.hotkeys.extend(Keyboard::remap_hotkey(key));
                     }
                 }
                 _ => (),
@@ -116,6 +143,11 @@ impl Window {
         }
     }
 
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        self.poll_events();
+        self.hotkeys.drain(..).collect()
+    }
+
     pub fn get_key_states(&mut self) -> &HashMap<u8, ElementState> {
         self.poll_events();
         &self.key_states
@@ -125,7 +157,7 @@ impl Window {
         for ev in self.display.wait_events() {
             match ev {
                 Event::KeyboardInput(state, _, Some(key)) => {
-                    if let Some(hex_code) = Keyboard::remap_key(key) {
+                    if let Some(hex_code) = self.hex_code(key) {
                         debug!("{:?} -> {:X}", key, hex_code);
                         self.key_states.insert(hex_code, state);
                         if let ElementState::Pressed = state {
@@ -133,6 +165,9 @@ impl Window {
                         }
                     } else {
                         debug!("{:?} -> None", key);
+                        if let ElementState::Pressed = state {
+                            self.hotkeys.extend(Keyboard::remap_hotkey(key));
+                        }
                     }
                 }
                 _ => (),
@@ -142,20 +177,38 @@ impl Window {
     }
 
     pub fn draw(&mut self, grid: &[u8]) {
-        let mut image_buffer = Vec::with_capacity(320);
-        for y in 0..320 {
-            let y = 319 - y;
+        self.grid.copy_from_slice(grid);
+        self.render();
+    }
+
+    /// replace the overlay panel, which must be `PANEL_WIDTH` by `PANEL_HEIGHT`
+    pub fn draw_panel(&mut self, panel: Vec<u8>) {
+        if self.panel.is_some() {
+            self.panel = Some(panel);
+            self.render();
+        }
+    }
+
+    fn render(&mut self) {
+        let panel_height = if self.panel.is_some() { PANEL_HEIGHT } else { 0 };
+        let height = 320 + panel_height;
+        let (on, off) = (rgba(self.palette.on), rgba(self.palette.off));
+        let mut image_buffer = Vec::with_capacity(height);
+        for y in 0..height {
+            let y = height - 1 - y;
             let mut row = Vec::with_capacity(640);
             for x in 0..640 {
-                let grid_idx = (y / 10) * 64 + (x / 10);
-                if grid_idx == 2048 {
-                    error!("uh oh index");
-                }
-                
-                let pixel = if grid[grid_idx] == 1 {
-                    (1.0, 0.16, 0.16, 1.0)
+                let pixel = if y >= 320 {
+                    let panel = self.panel.as_ref().expect("only taller with a panel");
+                    if panel[(y - 320) * PANEL_WIDTH + x] == 1 {
+                        (0.85, 0.85, 0.85, 1.0)
+                    } else {
+                        (0.02, 0.02, 0.04, 1.0)
+                    }
+                } else if self.grid[(y / 10) * 64 + (x / 10)] == 1 {
+                    on
                 } else {
-                    (0.07, 0.07, 0.07, 1.0)
+                    off
                 };
                 // let pixel = (y as f32 / 319.0, x as f32 / 639.0, 0.0, 1.0);
                 row.push(pixel);
@@ -175,3 +228,7 @@ impl Window {
         self.poll_events();
     }
 }
+
+fn rgba(color: [u8; 3]) -> (f32, f32, f32, f32) {
+    (color[0] as f32 / 255.0, color[1] as f32 / 255.0, color[2] as f32 / 255.0, 1.0)
+}